@@ -2,9 +2,12 @@ use std::str;
 
 use lambda_http::{Request, RequestExt};
 
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 
-use crate::{decoder::decode, Metric};
+use crate::{
+    decoder::{decode, DecodeError},
+    Metric,
+};
 
 type E = Box<dyn std::error::Error + Send + Sync + 'static>;
 
@@ -15,7 +18,11 @@ pub struct Metrics {
     pub metrics: Vec<Metric>,
 }
 
-#[instrument(level = "info", skip(req), fields(token, bytes, lines, metrics))]
+#[instrument(
+    level = "info",
+    skip(req),
+    fields(token, bytes, lines, metrics, rejected)
+)]
 pub fn extract_metrics(req: Request) -> Result<Metrics, E> {
     let _context = req.lambda_context();
     let (parts, body) = req.into_parts();
@@ -27,17 +34,30 @@ pub fn extract_metrics(req: Request) -> Result<Metrics, E> {
     let mut stream = body.split(|c| *c == b'\n');
 
     let mut lines: u16 = 0;
+    let mut rejected: u16 = 0;
     let mut metrics: Vec<Metric> = Vec::with_capacity(5);
 
     while let Some(line) = stream.next() {
         lines += 1;
-        if let Ok(Some(metric)) = decode(str::from_utf8(line)?.to_string()) {
-            metrics.push(metric);
+        let line = str::from_utf8(line)?;
+        match decode(line.to_string()) {
+            Ok(Some(metric)) => metrics.push(metric),
+            Ok(None) => {}
+            Err(
+                e @ DecodeError::MissingFieldKey(..)
+                | e @ DecodeError::MissingTagKey(..)
+                | e @ DecodeError::TooFewFields(..),
+            ) => {
+                rejected += 1;
+                warn!("rejected metric: {}\n{}", e, line);
+            }
+            Err(_) => {}
         }
     }
 
     tracing::Span::current().record("lines", &lines);
     tracing::Span::current().record("metrics", &metrics.len());
+    tracing::Span::current().record("rejected", &rejected);
 
     info!("extracted metrics");
 
@@ -10,6 +10,16 @@ pub struct Decoder<'a> {
     pub name: &'a str,
     pub tag_names: &'a [&'a str],
     pub field_names: &'a [&'a str],
+    /// Keys to leave out, even when they match `tag_names`
+    pub exclude_tag_names: &'a [&'a str],
+    /// Keys to leave out, even when they match `field_names`
+    pub exclude_field_names: &'a [&'a str],
+    /// Reject the line if any of these tags are missing
+    pub required_tags: &'a [&'a str],
+    /// Reject the line if any of these fields are missing
+    pub required_fields: &'a [&'a str],
+    /// Reject the line if it has fewer fields than this. A metric always needs at least one.
+    pub min_fields: usize,
 }
 
 const HEROKU: &str = "heroku";
@@ -29,6 +39,11 @@ const DYNO_LOAD_DECODER: Decoder<'static> = Decoder {
         "sample#load_avg_5m",
         "sample#load_avg_15m",
     ],
+    exclude_tag_names: &[],
+    exclude_field_names: &[],
+    required_tags: &["source"],
+    required_fields: &[],
+    min_fields: 0,
 };
 
 const DYNO_MEMORY_DECODER: Decoder<'static> = Decoder {
@@ -44,6 +59,11 @@ const DYNO_MEMORY_DECODER: Decoder<'static> = Decoder {
         "sample#memory_pgpgout",
         "sample#memory_quota",
     ],
+    exclude_tag_names: &[],
+    exclude_field_names: &[],
+    required_tags: &["source"],
+    required_fields: &[],
+    min_fields: 0,
 };
 
 const ROUTER_DECODER: Decoder<'static> = Decoder {
@@ -51,29 +71,23 @@ const ROUTER_DECODER: Decoder<'static> = Decoder {
     name: "heroku_router",
     tag_names: &["method", "host", "dyno", "status", "protocol"],
     field_names: &["connect", "service", "bytes"],
+    exclude_tag_names: &[],
+    exclude_field_names: &[],
+    required_tags: &["status"],
+    required_fields: &["service"],
+    min_fields: 0,
 };
 
 const POSTGRES_DECODER: Decoder<'static> = Decoder {
     matcher: &{ |ld: &LogData| ld.procid == HEROKU_POSTGRES },
     name: "heroku_postgres",
     tag_names: &["addon", "source"],
-    field_names: &[
-        "sample#db_size",
-        "sample#tables",
-        "sample#active-connections",
-        "sample#waiting-connections",
-        "sample#index-cache-hit-rate",
-        "sample#table-cache-hit-rate",
-        "sample#load-avg-1m",
-        "sample#load-avg-5m",
-        "sample#load-avg-15m",
-        "sample#read-iops",
-        "sample#write-iops",
-        "sample#memory-total",
-        "sample#memory-free",
-        "sample#memory-cached",
-        "sample#memory-postgres",
-    ],
+    field_names: &["sample#*"],
+    exclude_tag_names: &[],
+    exclude_field_names: &[],
+    required_tags: &["addon"],
+    required_fields: &[],
+    min_fields: 0,
 };
 
 const REDIS_DECODER: Decoder<'static> = Decoder {
@@ -94,6 +108,11 @@ const REDIS_DECODER: Decoder<'static> = Decoder {
         "sample#hit-rate",
         "sample#evicted-keys",
     ],
+    exclude_tag_names: &[],
+    exclude_field_names: &[],
+    required_tags: &["addon"],
+    required_fields: &[],
+    min_fields: 0,
 };
 
 const DECODERS: &'static [Decoder] = &[
@@ -117,6 +136,9 @@ pub enum DecodeError {
 
     #[error("Field Key `{0}` was not found in {1:?}")]
     MissingFieldKey(String, KVPairs),
+
+    #[error("Metric `{0}` has {1} fields, but needs at least {2}")]
+    TooFewFields(String, usize, usize),
 }
 
 #[instrument(level = "info")]
@@ -139,11 +161,38 @@ fn parse(line: String) -> Result<Option<LogData>, DecodeError> {
 fn extract(ld: &LogData) -> Result<Option<Metric>, DecodeError> {
     if let Some(decoder) = find_decoder(ld) {
         let pairs = parser::parse_msg(&ld.msg)?;
+        let tags = extract_keys(decoder.tag_names, decoder.exclude_tag_names, &pairs)?;
+        let fields = extract_keys(decoder.field_names, decoder.exclude_field_names, &pairs)?;
+
+        if let Some(key) = decoder
+            .required_tags
+            .iter()
+            .find(|k| !tags.contains_key(**k))
+        {
+            return Err(DecodeError::MissingTagKey(key.to_string(), pairs));
+        }
+        if let Some(key) = decoder
+            .required_fields
+            .iter()
+            .find(|k| !fields.contains_key(**k))
+        {
+            return Err(DecodeError::MissingFieldKey(key.to_string(), pairs));
+        }
+        // InfluxDB rejects the whole batch if a point has no fields
+        let min_fields = decoder.min_fields.max(1);
+        if fields.len() < min_fields {
+            return Err(DecodeError::TooFewFields(
+                decoder.name.to_string(),
+                fields.len(),
+                min_fields,
+            ));
+        }
+
         Ok(Some(Metric::new(
             parse_timestamp(ld)?,
             decoder.name.to_string(),
-            extract_keys(decoder.tag_names, &pairs)?,
-            extract_keys(decoder.field_names, &pairs)?,
+            tags,
+            fields,
         )))
     } else {
         Ok(None)
@@ -165,14 +214,144 @@ fn parse_timestamp(ld: &LogData) -> Result<DateTime<Utc>, DecodeError> {
         .with_timezone(&Utc))
 }
 
-fn extract_keys(keys: &[&str], pairs: &KVPairs) -> Result<KVPairs, DecodeError> {
+fn extract_keys(keys: &[&str], excludes: &[&str], pairs: &KVPairs) -> Result<KVPairs, DecodeError> {
     let mut out = KVPairs::new();
-    for key in keys {
-        let key_s = String::from(*key);
-
-        if let Some(val) = pairs.get(&key_s) {
-            out.insert(key_s, val.clone());
+    if keys.iter().any(|key| is_glob(key)) {
+        for (key, val) in pairs {
+            if includes(keys, excludes, key) {
+                out.insert(key.clone(), val.clone());
+            }
+        }
+    } else {
+        for key in keys {
+            if let Some((key, val)) = pairs.get_key_value(*key) {
+                if !excludes.iter().any(|pattern| glob_match(pattern, key)) {
+                    out.insert(key.clone(), val.clone());
+                }
+            }
         }
     }
     Ok(out)
 }
+
+fn includes(patterns: &[&str], excludes: &[&str], key: &str) -> bool {
+    patterns.iter().any(|pattern| glob_match(pattern, key))
+        && !excludes.iter().any(|pattern| glob_match(pattern, key))
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Shell-style matching, where `*` matches any run of characters and `?` any single one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the most recent `*`, if the rest doesn't match
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, extract_keys, glob_match, DecodeError};
+    use crate::parser::KVPairs;
+
+    #[test]
+    fn test_it_decodes_a_router_line() {
+        let line = r#"<158>1 2019-11-25T18:28:00.089034+00:00 host heroku router - at=info method=GET dyno=web.1 connect=0ms service=25ms status=200"#;
+
+        let metric = decode(line.to_string())
+            .expect("Should decode the line")
+            .expect("Should have a metric");
+        assert_eq!(metric.name, "heroku_router");
+    }
+
+    #[test]
+    fn test_it_rejects_lines_missing_required_keys() {
+        let no_status = r#"<158>1 2019-11-25T18:28:00.089034+00:00 host heroku router - at=info dyno=web.1 service=25ms"#;
+        assert!(matches!(
+            decode(no_status.to_string()),
+            Err(DecodeError::MissingTagKey(key, _)) if key == "status"
+        ));
+
+        let no_service = r#"<158>1 2019-11-25T18:28:00.089034+00:00 host heroku router - at=info dyno=web.1 connect=0ms status=200"#;
+        assert!(matches!(
+            decode(no_service.to_string()),
+            Err(DecodeError::MissingFieldKey(key, _)) if key == "service"
+        ));
+
+        let no_source =
+            "<45>1 2019-11-25T18:28:15.490955+00:00 host heroku web.1 - sample#load_avg_1m=0.01";
+        assert!(matches!(
+            decode(no_source.to_string()),
+            Err(DecodeError::MissingTagKey(key, _)) if key == "source"
+        ));
+    }
+
+    #[test]
+    fn test_it_never_decodes_a_metric_without_fields() {
+        let line = "<45>1 2019-11-25T18:28:15.490955+00:00 host heroku-postgres heroku-postgres - addon=postgres-1 source=DATABASE";
+        assert!(matches!(
+            decode(line.to_string()),
+            Err(DecodeError::TooFewFields(name, 0, 1)) if name == "heroku_postgres"
+        ));
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("sample#*", "sample#wal-percentage-used"));
+        assert!(glob_match("sample#load-avg-?m", "sample#load-avg-5m"));
+        assert!(glob_match("*-iops", "sample#read-iops"));
+        assert!(glob_match("sample#*-*", "sample#memory-free"));
+        assert!(!glob_match("sample#*", "source"));
+        assert!(!glob_match("sample#load-avg-?m", "sample#load-avg-15m"));
+        assert!(!glob_match("*-iops", "sample#read-iops-max"));
+    }
+
+    #[test]
+    fn test_it_leaves_out_excluded_keys() {
+        let pairs: KVPairs = [
+            ("source", "DATABASE"),
+            ("sample#read-iops", "0"),
+            ("sample#write-iops", "1"),
+            ("sample#current_transaction", "2"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+
+        let fields = extract_keys(&["sample#*"], &["*-iops"], &pairs).unwrap();
+        assert_eq!(
+            fields.keys().collect::<Vec<_>>(),
+            ["sample#current_transaction"]
+        );
+
+        let fields = extract_keys(&["sample#read-iops", "source"], &["sample#*"], &pairs).unwrap();
+        assert_eq!(fields.keys().collect::<Vec<_>>(), ["source"]);
+    }
+}
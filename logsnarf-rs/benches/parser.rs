@@ -0,0 +1,235 @@
+//! Parsing a drain's worth of lines, most of which match no decoder.
+//!
+//! `owned` is the parser as it was before `LogData` borrowed from the line, kept here verbatim so
+//! the difference between the two is what the rewrite saves. Its `parse_msg` gives up with an error
+//! at a bare word that ends a message, so on those lines it does a little less than the new one.
+
+use std::borrow::Cow;
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use logsnarf::parser;
+
+const LINES: &[&str] = &[
+    r#"302 <158>1 2019-11-25T18:28:00.089034+00:00 host heroku router - at=info method=GET path="/" host=myapp.example request_id=0e5a6c7e-6a49-4e49-9a6b-2c1b1f4ad0c3 fwd="52.90.232.237" dyno=web.1 connect=0ms service=25ms status=200 bytes=1541 protocol=https"#,
+    r#"120 <190>1 2019-11-25T18:28:00.101522+00:00 host app web.1 - [3f1c] Started GET "/" for 52.90.232.237 at 2019-11-25 18:28:00 +0000"#,
+    r#"98 <190>1 2019-11-25T18:28:00.129411+00:00 host app web.1 - [3f1c] Completed 200 OK in 21ms (Views: 12.1ms | ActiveRecord: 4.2ms)"#,
+    r#"302 <158>1 2019-11-25T18:28:01.022341+00:00 host heroku router - at=info method=POST path="/api/events" host=myapp.example request_id=5d3c0dd1-0b8a-4e37-8f5c-d5b1f9f2b8f4 fwd="52.90.232.237" dyno=web.2 connect=1ms service=41ms status=201 bytes=312 protocol=https"#,
+    r#"96 <190>1 2019-11-25T18:28:01.331098+00:00 host app worker.1 - Performing ReportJob (Job ID: 7f3d) from Sidekiq(default)"#,
+    r#"120 <190>1 2019-11-25T18:28:01.480012+00:00 host app web.2 - [8a2e] Started POST "/api/events" for 52.90.232.237 at 2019-11-25 18:28:01 +0000"#,
+    r#"98 <190>1 2019-11-25T18:28:01.512344+00:00 host app web.2 - [8a2e] Completed 201 Created in 30ms (ActiveRecord: 9.8ms)"#,
+    r#"302 <158>1 2019-11-25T18:28:02.411235+00:00 host heroku router - at=info method=GET path="/assets/app.js" host=myapp.example request_id=9b1e6f0e-2f4b-4c7b-8e43-1c5d3f6a2b10 fwd="52.90.232.237" dyno=web.1 connect=0ms service=3ms status=304 bytes=0 protocol=https"#,
+    r#"96 <190>1 2019-11-25T18:28:02.731001+00:00 host app worker.1 - Performed ReportJob (Job ID: 7f3d) from Sidekiq(default) in 1391.4ms"#,
+    "176 <45>1 2019-11-25T18:28:15.490955+00:00 host heroku web.1 - source=web.1 dyno=heroku.145151706.6cc5ac40-1b7c-4b2f-8d8a-4f5a7f1e2c6d sample#memory_total=324.41MB sample#memory_rss=317.93MB sample#memory_cache=6.48MB",
+];
+
+fn sample() -> Vec<String> {
+    LINES
+        .iter()
+        .cycle()
+        .take(10_000)
+        .map(|line| line.to_string())
+        .collect()
+}
+
+// The parser before `LogData` borrowed from the line
+#[allow(dead_code, clippy::needless_borrow)]
+mod owned {
+    use std::collections::BTreeMap;
+    use std::str;
+
+    #[derive(Debug)]
+    pub enum ParseError {
+        UnexpectedEndOfInput,
+        BaseUnicodeError(str::Utf8Error),
+    }
+
+    type ParseResult<T> = Result<T, ParseError>;
+
+    pub type KVPairs = BTreeMap<String, String>;
+
+    #[derive(Debug)]
+    pub struct LogData {
+        pub timestamp_str: String,
+        pub hostname: String,
+        pub appname: String,
+        pub procid: String,
+        pub msgid: Option<String>,
+        pub msg: String,
+    }
+
+    pub fn parse_line(m: &str) -> ParseResult<Option<LogData>> {
+        if m.is_empty() {
+            return Ok(None);
+        }
+
+        let mut rest = m;
+
+        rest = skip_to_after('>', rest)?;
+        rest = skip_to_after(' ', rest)?;
+        let (timestamp_str, rest) = parse_term(rest)?;
+        let (hostname, rest) = parse_term(rest)?;
+        let (appname, rest) = parse_term(rest)?;
+        let (procid, rest) = parse_term(rest)?;
+        let (msgid, rest) = parse_term(rest)?;
+        let msg = String::from(rest);
+
+        if let (Some(timestamp_str), Some(hostname), Some(appname), Some(procid)) =
+            (timestamp_str, hostname, appname, procid)
+        {
+            Ok(Some(LogData {
+                timestamp_str,
+                hostname,
+                appname,
+                procid,
+                msgid,
+                msg,
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn parse_msg(msg: &str) -> ParseResult<KVPairs> {
+        let mut rest = msg;
+        let mut pairs = KVPairs::new();
+
+        loop {
+            let (key, rest2) = parse_key(rest)?;
+            rest = rest2;
+            if key.is_none() {
+                continue;
+            };
+            let (val, rest) = parse_value(rest)?;
+            pairs.insert(key.unwrap(), val);
+            if rest.is_empty() {
+                break;
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    fn skip_to_after(c: char, m: &str) -> ParseResult<&str> {
+        for (idx, chr) in m.char_indices() {
+            if chr == c {
+                return Ok(&m[(idx + 1)..]);
+            }
+        }
+        Err(ParseError::UnexpectedEndOfInput)
+    }
+
+    fn parse_term(m: &str) -> ParseResult<(Option<String>, &str)> {
+        // Blank field
+        if m.starts_with('-') && (m.len() <= 1 || m.as_bytes()[1] == 0x20) {
+            return Ok((None, &m[2..]));
+        }
+
+        // Read until we get a Space or some unprintable ascii
+        let byte_ary = m.as_bytes();
+        for (idx, chr) in byte_ary.iter().enumerate() {
+            if *chr < 33 || *chr > 126 {
+                let utf8_ary =
+                    str::from_utf8(&byte_ary[..idx]).map_err(ParseError::BaseUnicodeError)?;
+                return Ok((Some(String::from(utf8_ary)), &m[(idx + 1)..]));
+            }
+        }
+        Err(ParseError::UnexpectedEndOfInput)
+    }
+
+    fn parse_key(input: &str) -> ParseResult<(Option<String>, &str)> {
+        for (idx, chr) in input.char_indices() {
+            if chr == '=' {
+                let key = String::from(&input[..idx]);
+                return Ok((Some(key), &input[(idx + 1)..]));
+            } else if chr == ' ' {
+                return Ok((None, &input[(idx + 1)..]));
+            }
+        }
+        Err(ParseError::UnexpectedEndOfInput)
+    }
+
+    fn parse_value(input: &str) -> ParseResult<(String, &str)> {
+        let mut quoted = false;
+        let mut chars = input.char_indices().peekable();
+        while let Some((idx, chr)) = chars.next() {
+            if quoted {
+                if chr == '"' {
+                    return Ok((String::from(&input[1..idx]), &input[(idx + 1)..]));
+                }
+            } else {
+                if chr == '"' {
+                    quoted = true;
+                    continue;
+                }
+                if chr == ' ' {
+                    return Ok((String::from(&input[..idx]), &input[(idx + 1)..]));
+                }
+                if chars.peek().is_none() {
+                    return Ok((String::from(input), &""));
+                }
+            }
+        }
+        Err(ParseError::UnexpectedEndOfInput)
+    }
+}
+
+fn bench_parse_line(c: &mut Criterion) {
+    let lines = sample();
+    let mut group = c.benchmark_group("parse_line");
+    group.throughput(Throughput::Elements(lines.len() as u64));
+
+    group.bench_function("borrowed", |b| {
+        b.iter(|| {
+            for line in &lines {
+                black_box(parser::parse_line(line).unwrap());
+            }
+        })
+    });
+    group.bench_function("owned", |b| {
+        b.iter(|| {
+            for line in &lines {
+                black_box(owned::parse_line(line).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_parse_msg(c: &mut Criterion) {
+    let lines = sample();
+    let msgs: Vec<Cow<str>> = lines
+        .iter()
+        .filter_map(|line| parser::parse_line(line).unwrap())
+        .map(|ld| ld.msg)
+        .collect();
+    let mut group = c.benchmark_group("parse_msg");
+    group.throughput(Throughput::Elements(msgs.len() as u64));
+
+    group.bench_function("borrowed", |b| {
+        b.iter(|| {
+            for msg in &msgs {
+                black_box(parser::parse_msg(msg));
+            }
+        })
+    });
+    group.bench_function("extract", |b| {
+        b.iter(|| {
+            for msg in &msgs {
+                black_box(parser::extract_msg(msg, &["source", "sample#memory_total"]));
+            }
+        })
+    });
+    group.bench_function("owned", |b| {
+        b.iter(|| {
+            for msg in &msgs {
+                let _ = black_box(owned::parse_msg(msg));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse_line, bench_parse_msg);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+#![no_main]
+
+//! A whole drain request, through every decoder and builtin in the example config, and out as
+//! line protocol.
+
+use std::io;
+use std::sync::OnceLock;
+
+use async_trait::async_trait;
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime::{Builder, Runtime};
+
+use logsnarf::{
+    app::App,
+    metric::Metric,
+    metric_writer::{influxdb_v1::WriteDataPoint, rollup::Rollup, MetricWriter, WriterError},
+    settings::Settings,
+};
+
+const CONFIG: &str = include_str!("../../logsnarf.toml");
+
+struct LineProtocol;
+
+#[async_trait]
+impl MetricWriter for LineProtocol {
+    fn write(&mut self, metric: Metric) {
+        metric.write_data_point_to(io::sink()).unwrap();
+    }
+
+    async fn flush(&mut self) -> Result<(), WriterError> {
+        Ok(())
+    }
+}
+
+fn setup() -> &'static (Runtime, App, Settings) {
+    static SETUP: OnceLock<(Runtime, App, Settings)> = OnceLock::new();
+    SETUP.get_or_init(|| {
+        let load = || Settings::from_toml(CONFIG).unwrap();
+        let runtime = Builder::new_current_thread().build().unwrap();
+        (runtime, App::new(load()).unwrap(), load())
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (runtime, app, settings) = setup();
+
+    runtime.block_on(async {
+        let mut writer = Rollup::new(&settings.rollups, LineProtocol).unwrap();
+        app.extract_into("fuzz", data, &mut writer).await.unwrap();
+        writer.close().await.unwrap();
+    });
+});
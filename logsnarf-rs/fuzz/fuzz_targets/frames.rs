@@ -0,0 +1,43 @@
+#![no_main]
+
+//! Lines arrive split across reads at arbitrary points, so the first byte picks the read size.
+//! Decoding never fails: a line that's too long is `None`, and the next one is read as usual.
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+use logsnarf::{
+    app::Lines,
+    parser::{self, Mode, Options},
+};
+
+fn parse(options: &Options, line: Option<String>) {
+    if let Some(line) = line {
+        assert!(!line.contains('\n'));
+        let _ = parser::parse_line_with(options, &line);
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk, data)) = data.split_first() else {
+        return;
+    };
+
+    let options = Options {
+        mode: Mode::Auto,
+        ..Default::default()
+    };
+    let mut codec = Lines::new();
+    let mut buf = BytesMut::new();
+
+    for read in data.chunks(chunk.max(1) as usize) {
+        buf.extend_from_slice(read);
+        while let Some(line) = codec.decode(&mut buf).unwrap() {
+            parse(&options, line);
+        }
+    }
+    while let Some(line) = codec.decode_eof(&mut buf).unwrap() {
+        parse(&options, line);
+    }
+});
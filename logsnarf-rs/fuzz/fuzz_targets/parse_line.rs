@@ -0,0 +1,30 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use logsnarf::parser::{self, Mode, Options, Timezone};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(line) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    for mode in [
+        Mode::Logplex,
+        Mode::Rfc5424,
+        Mode::Rfc3164,
+        Mode::Auto,
+        Mode::Json,
+        Mode::Logfmt,
+    ] {
+        let options = Options {
+            mode,
+            timezone: Timezone::Local,
+            ..Default::default()
+        };
+        if let Ok(Some(ld)) = parser::parse_line_with(&options, line) {
+            let _ = ld.severity_name();
+            let _ = ld.facility_name();
+        }
+    }
+});
@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use logsnarf::parser;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(msg) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let pairs = parser::parse_msg(msg);
+    let words = parser::parse_words(msg);
+    assert!(pairs.len() + words.len() <= msg.len());
+
+    let extracted = parser::extract_msg(msg, &["code", "desc", "source"]);
+    assert!(extracted.len() <= 3);
+    for (key, val) in &extracted {
+        assert_eq!(pairs.get(key), Some(val));
+    }
+});
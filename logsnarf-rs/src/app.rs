@@ -1,105 +1,520 @@
+use std::collections::BTreeMap;
 use std::sync::{
     atomic::{self, AtomicUsize},
-    Arc,
+    Arc, Mutex,
 };
 
+use bytes::{Buf, BytesMut};
+use chrono::Utc;
 use tokio::io::AsyncRead;
-use tokio_stream::StreamExt;
-use tokio_util::codec::{FramedRead, LinesCodec};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::codec::{self, FramedRead};
 use tracing::{debug, instrument};
 
 use crate::{
-    decoder::{self, Decoder},
-    error::Result,
+    decoder::{Builtin, Decoder},
+    error::{LineError, LineErrorKind, Result},
+    error_sink::ErrorSink,
     metric::Metric,
-    metric_writer::{self, MetricWriter},
-    parser::{self, LogData},
+    metric_writer::{self, rollup::Rollup, MetricWriter},
+    parser,
+    pipeline::{Stages, Trace},
     record_stream::RecordStream,
     settings::Settings,
+    timestamp::SkewStats,
 };
 
+/// Longer lines are skipped, so one bad line can't buffer the whole input
+pub const MAX_LINE_LENGTH: usize = 16 * 1024;
+
+/// Splits input into lines of at most `MAX_LINE_LENGTH`, with `None` in place of each longer
+/// one. Bytes that aren't UTF-8 are replaced rather than failing the read, since `FramedRead`
+/// stops at the first error and one bad line would end the whole input.
+#[derive(Debug, Default)]
+pub struct Lines {
+    // Where to carry on looking for a newline, since the last call
+    next_index: usize,
+    // Skipping the rest of a line that was too long
+    discarding: bool,
+}
+
+impl Lines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn line(bytes: &[u8]) -> String {
+        let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+}
+
+impl codec::Decoder for Lines {
+    type Item = Option<String>;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> std::io::Result<Option<Self::Item>> {
+        loop {
+            let read_to = buf.len().min(MAX_LINE_LENGTH + 1);
+            let newline = buf[self.next_index..read_to]
+                .iter()
+                .position(|b| *b == b'\n')
+                .map(|offset| self.next_index + offset);
+
+            match (self.discarding, newline) {
+                (true, Some(end)) => {
+                    buf.advance(end + 1);
+                    self.discarding = false;
+                    self.next_index = 0;
+                }
+                (true, None) => {
+                    buf.advance(read_to);
+                    self.next_index = 0;
+                    if buf.is_empty() {
+                        return Ok(None);
+                    }
+                }
+                (false, Some(end)) => {
+                    self.next_index = 0;
+                    let line = buf.split_to(end + 1);
+                    return Ok(Some(Some(Self::line(&line[..end]))));
+                }
+                (false, None) if buf.len() > MAX_LINE_LENGTH => {
+                    self.discarding = true;
+                    return Ok(Some(None));
+                }
+                (false, None) => {
+                    self.next_index = read_to;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> std::io::Result<Option<Self::Item>> {
+        if let Some(line) = self.decode(buf)? {
+            return Ok(Some(line));
+        }
+        self.next_index = 0;
+        if buf.is_empty() || self.discarding {
+            buf.clear();
+            return Ok(None);
+        }
+        let line = buf.split();
+        Ok(Some(Some(Self::line(&line))))
+    }
+}
+
+/// Tallies for a single call to `extract`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Counts {
+    pub lines: u64,
+    /// Lines that at least one decoder or builtin matched
+    pub matched: u64,
+    pub unmatched: u64,
+    /// Lines that couldn't be parsed at all
+    pub unparsed: u64,
+    pub metrics: u64,
+    pub rejected: u64,
+    pub skew: SkewStats,
+    /// By decoder or builtin name
+    pub decoders: BTreeMap<String, DecoderCounts>,
+    /// Lines that gave an error, by `LineErrorKind::name`, eg `parse.invalid`
+    pub errors: BTreeMap<String, u64>,
+}
+
+/// Tallies for one decoder or builtin, over the lines it matched
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DecoderCounts {
+    pub lines: u64,
+    pub metrics: u64,
+    pub rejected: u64,
+    pub errors: u64,
+}
+
+impl Counts {
+    /// Adds up the counts from another call, eg to summarize several files
+    pub fn merge(&mut self, other: &Counts) {
+        self.lines += other.lines;
+        self.matched += other.matched;
+        self.unmatched += other.unmatched;
+        self.unparsed += other.unparsed;
+        self.metrics += other.metrics;
+        self.rejected += other.rejected;
+        self.skew.merge(&other.skew);
+        for (name, count) in &other.decoders {
+            let total = self.decoders.entry(name.clone()).or_default();
+            total.lines += count.lines;
+            total.metrics += count.metrics;
+            total.rejected += count.rejected;
+            total.errors += count.errors;
+        }
+        for (kind, count) in &other.errors {
+            *self.errors.entry(kind.clone()).or_default() += count;
+        }
+    }
+
+    pub(crate) fn count_error(&mut self, kind: &LineErrorKind) {
+        *self.errors.entry(kind.name()).or_default() += 1;
+    }
+}
+
+// The counts for a stream from `extract_stream`. Its skew goes into the app's stats when it's
+// dropped, so a caller that stops reading part way still has the lines it read counted.
+struct StreamCounts<'a> {
+    app: &'a App,
+    source: &'a str,
+    counts: Counts,
+}
+
+impl Drop for StreamCounts<'_> {
+    fn drop(&mut self) {
+        self.app.merge_skew(self.source, &self.counts.skew);
+    }
+}
+
 pub struct App {
     settings: Settings,
-    decoders: Vec<Decoder>,
+    stages: Stages,
+    errors: ErrorSink,
+    // Since startup, by source
+    skew: Mutex<BTreeMap<String, SkewStats>>,
 }
 
 impl App {
-    pub fn new(settings: Settings) -> Self {
-        let decoders = decoder::build_decoders(&settings.metrics);
+    /// Fails if a decoder in the settings can't be built
+    pub fn new(settings: Settings) -> Result<Self> {
+        let stages = Stages::new(&settings.metrics, &settings.builtins)?;
+        let errors = ErrorSink::new(settings.errors.clone());
+
+        Ok(Self {
+            settings,
+            stages,
+            errors,
+            skew: Mutex::default(),
+        })
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
 
-        Self { settings, decoders }
+    /// The configured decoders, in the order they're tried
+    pub fn decoders(&self) -> &[Decoder] {
+        &self.stages.decoders
     }
 
-    #[instrument(skip(self, data), fields(bytes, lines, metrics))]
-    pub async fn extract(&self, data: impl AsyncRead + std::marker::Unpin) -> Result<()> {
-        // let mut metrics: Vec<Metric> = Vec::new();
-        let mut writer = metric_writer::build(&self.settings.tsdb);
+    pub fn builtins(&self) -> &[Builtin] {
+        &self.stages.builtins
+    }
+
+    /// Runs one line through the same stages as `extract`, without writing or reporting anything,
+    /// and says what each of them made of it
+    pub fn trace_line<'a>(&self, source: &str, line: &'a str, mode: parser::Mode) -> Trace<'a> {
+        let options = parser::Options {
+            mode,
+            ..self.settings.parser.clone()
+        };
+        let policy = self.settings.timestamps.for_source(source);
+        self.stages.trace_line(line, &options, policy, Utc::now())
+    }
+
+    /// How far off each source's clock has been since the last call, to spot the ones that need
+    /// fixing. Taking them resets them, so a long-running server doesn't keep a source forever.
+    pub fn take_skew_stats(&self) -> BTreeMap<String, SkewStats> {
+        std::mem::take(&mut *self.skew.lock().unwrap())
+    }
+
+    /// Extracts the metrics from one `source`, such as a drain or a file, and writes them
+    pub async fn extract(
+        &self,
+        source: &str,
+        data: impl AsyncRead + std::marker::Unpin,
+    ) -> Result<Counts> {
+        self.extract_as(source, self.settings.parser.mode, data)
+            .await
+    }
+
+    /// Like `extract`, but reads lines in `mode` instead of the configured one
+    #[instrument(skip(self, data))]
+    pub async fn extract_as(
+        &self,
+        source: &str,
+        mode: parser::Mode,
+        data: impl AsyncRead + std::marker::Unpin,
+    ) -> Result<Counts> {
+        let mut writer = Rollup::new(
+            &self.settings.rollups,
+            metric_writer::build(&self.settings.tsdb),
+        )?;
+
+        let counts = self
+            .extract_into_as(source, mode, data, &mut writer)
+            .await?;
+        writer.close().await?;
+
+        Ok(counts)
+    }
+
+    /// Every metric in `data` as it's extracted, for callers with their own sink. Lines that give
+    /// no metric, or not all of them, come out as errors; lines that simply don't match any
+    /// decoder don't. The timestamp skew of the lines read is counted even if the stream is
+    /// dropped before the end.
+    pub fn extract_stream<'a>(
+        &'a self,
+        source: &'a str,
+        data: impl AsyncRead + std::marker::Unpin + 'a,
+    ) -> impl Stream<Item = std::result::Result<Metric, LineError>> + 'a {
+        self.extract_stream_as(source, self.settings.parser.mode, data)
+    }
+
+    /// Like `extract_stream`, but reads lines in `mode` instead of the configured one
+    pub fn extract_stream_as<'a>(
+        &'a self,
+        source: &'a str,
+        mode: parser::Mode,
+        data: impl AsyncRead + std::marker::Unpin + 'a,
+    ) -> impl Stream<Item = std::result::Result<Metric, LineError>> + 'a {
+        let options = parser::Options {
+            mode,
+            ..self.settings.parser.clone()
+        };
+        let policy = self.settings.timestamps.for_source(source);
+        let lines = FramedRead::new(data, Lines::new());
+
+        async_stream::stream! {
+            let mut stream_counts = StreamCounts {
+                app: self,
+                source,
+                counts: Counts::default(),
+            };
+            let mut number = 0;
+            for await line in lines {
+                number += 1;
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    Ok(None) => {
+                        let kind = LineErrorKind::TooLong(MAX_LINE_LENGTH);
+                        yield Err(LineError { number, line: String::new(), kind });
+                        continue;
+                    }
+                    Err(e) => {
+                        let kind = LineErrorKind::Read(e);
+                        yield Err(LineError { number, line: String::new(), kind });
+                        break;
+                    }
+                };
+
+                let results = self.stages.results_from_line(
+                    &line,
+                    &options,
+                    policy,
+                    Utc::now(),
+                    &mut stream_counts.counts,
+                );
+                for result in results {
+                    yield result.map_err(|kind| LineError {
+                        number,
+                        line: line.clone(),
+                        kind,
+                    });
+                }
+            }
+        }
+    }
+
+    fn merge_skew(&self, source: &str, skew: &SkewStats) {
+        self.skew
+            .lock()
+            .unwrap()
+            .entry(source.to_string())
+            .or_default()
+            .merge(skew);
+    }
 
-        let mut line_cnt: u64 = 0;
-        let mut metric_cnt: u64 = 0;
+    /// Writes every metric in `data` to `writer`, leaving it to the caller to flush or close it.
+    /// Lines that give errors go to the `[errors]` sink, and are counted by kind. Reading waits
+    /// whenever `writer` isn't ready for more, and stops if it fails.
+    pub async fn extract_into(
+        &self,
+        source: &str,
+        data: impl AsyncRead + std::marker::Unpin,
+        writer: &mut (impl MetricWriter + Send),
+    ) -> Result<Counts> {
+        self.extract_into_as(source, self.settings.parser.mode, data, writer)
+            .await
+    }
+
+    /// Like `extract_into`, but reads lines in `mode` instead of the configured one
+    #[instrument(
+        skip(self, data, writer),
+        fields(bytes, lines, metrics, rejected, skew_min, skew_max)
+    )]
+    pub async fn extract_into_as(
+        &self,
+        source: &str,
+        mode: parser::Mode,
+        data: impl AsyncRead + std::marker::Unpin,
+        writer: &mut (impl MetricWriter + Send),
+    ) -> Result<Counts> {
+        let options = parser::Options {
+            mode,
+            ..self.settings.parser.clone()
+        };
+        let policy = self.settings.timestamps.for_source(source);
+        let mut counts = Counts::default();
         let bytes = Arc::new(AtomicUsize::new(0));
 
         let data = RecordStream::new(data, bytes.clone());
-        let mut stream = FramedRead::new(data, LinesCodec::new_with_max_length(16 * 1024));
-
-        while let Some(Ok(line)) = stream.next().await {
-            line_cnt += 1;
-            match self.metric_from_line(line.as_ref()) {
-                Ok(Some(metric)) => {
-                    metric_cnt += 1;
-                    writer.write(metric);
+        let mut stream = FramedRead::new(data, Lines::new());
+
+        let mut error = None;
+        while let Some(line) = stream.next().await {
+            let line = match line {
+                Ok(Some(line)) => line,
+                // Eg a body that fails to decompress, which the caller should hear about
+                Err(e) => {
+                    error = Some(e.into());
+                    break;
                 }
-                Ok(None) => {}
-                Err(_e) => {
-                    // tracing::error!("Problem parsing line: {}\n{}", e, line);
-                    // sentry::capture_error(&e);
+                Ok(None) => {
+                    counts.lines += 1;
+                    let kind = LineErrorKind::TooLong(MAX_LINE_LENGTH);
+                    counts.count_error(&kind);
+                    self.report(source, counts.lines, String::new(), kind);
+                    continue;
                 }
+            };
+
+            let results =
+                self.stages
+                    .results_from_line(&line, &options, policy, Utc::now(), &mut counts);
+            for result in results {
+                match result {
+                    Ok(metric) => writer.write(metric),
+                    Err(kind) => self.report(source, counts.lines, line.clone(), kind),
+                }
+            }
+            if let Err(e) = writer.ready().await {
+                error = Some(e.into());
+                break;
             }
         }
 
         tracing::Span::current().record("bytes", bytes.load(atomic::Ordering::Relaxed));
-        tracing::Span::current().record("lines", line_cnt);
-        tracing::Span::current().record("metrics", metric_cnt);
+        tracing::Span::current().record("lines", counts.lines);
+        tracing::Span::current().record("metrics", counts.metrics);
+        tracing::Span::current().record("rejected", counts.rejected);
+        if let (Some(min), Some(max)) = (counts.skew.min, counts.skew.max) {
+            tracing::Span::current().record("skew_min", min);
+            tracing::Span::current().record("skew_max", max);
+        }
 
         debug!(
-            "Consumed {:?} bytes, in {} lines, extracted {} metrics",
-            bytes, line_cnt, metric_cnt
+            "Consumed {:?} bytes, in {} lines, extracted {} metrics, rejected {}",
+            bytes, counts.lines, counts.metrics, counts.rejected
         );
+        self.merge_skew(source, &counts.skew);
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(counts),
+        }
+    }
+
+    fn report(&self, source: &str, number: u64, line: String, kind: LineErrorKind) {
+        self.errors
+            .report(source, &LineError { number, line, kind });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
 
-        writer.flush().await?;
+    use super::{App, MAX_LINE_LENGTH};
+    use crate::{error::LineErrorKind, metric::Metric, settings::Settings};
 
-        Ok(())
+    fn app() -> App {
+        let settings = Settings::from_toml(include_str!("../logsnarf.toml")).unwrap();
+        App::new(settings).unwrap()
     }
 
-    #[instrument(skip(self))]
-    fn metric_from_line(&self, line: &str) -> Result<Option<Metric>> {
-        Ok(Self::parse_line(line)?.and_then(|ld| {
-            Self::find_decoder(&self.decoders, &ld).and_then(|decoder| {
-                Self::decode_metric(&decoder, &ld)
-                    .ok()?
-                    .and_then(|metric| Some(metric))
-            })
-        }))
+    #[tokio::test]
+    async fn test_it_streams_metrics_and_line_errors() {
+        let data = "\
+<158>1 2019-11-25T18:28:00Z host heroku router - dyno=web.1 connect=0ms service=25ms status=200
+not syslog
+<45>1 2019-11-25T18:28:00Z host app web.1 - nothing to see here
+<158>1 2019-11-25T18:28:01Z host heroku router - dyno=web.2 status=200
+";
+        let app = app();
+        let results: Vec<_> = app.extract_stream("test", data.as_bytes()).collect().await;
+        assert_eq!(results.len(), 3);
+
+        assert_eq!(results[0].as_ref().unwrap().name, "heroku_router");
+
+        let e = results[1].as_ref().unwrap_err();
+        assert_eq!(e.number, 2);
+        assert_eq!(e.line, "not syslog");
+        assert!(matches!(e.kind, LineErrorKind::Parse(_)));
+
+        // A router line without any of the fields
+        let e = results[2].as_ref().unwrap_err();
+        assert_eq!(e.number, 4);
+        assert!(matches!(&e.kind, LineErrorKind::Decode(d) if d.is_rejection()));
     }
 
-    #[instrument]
-    fn parse_line(line: &str) -> Result<Option<LogData>> {
-        Ok(parser::parse_line(line).map_err(|e| {
-            tracing::warn!("Problem parsing line: {}", e);
-            e
-        })?)
+    #[tokio::test]
+    async fn test_a_stream_stopped_early_still_counts_skew() {
+        let data = "\
+<158>1 2019-11-25T18:28:00Z host heroku router - dyno=web.1 connect=0ms service=25ms status=200
+<158>1 2019-11-25T18:28:01Z host heroku router - dyno=web.2 connect=0ms service=25ms status=200
+";
+        let app = app();
+        let stream = app.extract_stream("test", data.as_bytes());
+        let first: Vec<_> = stream.take(1).collect().await;
+        assert_eq!(first.len(), 1);
+
+        let skew = app.take_skew_stats();
+        assert!(!skew["test"].is_empty());
     }
 
-    #[instrument]
-    fn find_decoder<'a>(decoders: &'a Vec<Decoder>, ld: &'a LogData) -> Option<&'a Decoder> {
-        decoders.iter().find(|decoder| decoder.matches(&ld))
+    #[tokio::test]
+    async fn test_it_counts_errors_by_kind_and_reads_past_long_lines() {
+        let data = format!(
+            "not syslog\n{}\n<158>1 2019-11-25T18:28:00Z host heroku router - dyno=web.1 status=200\n<158>1 2019-11-25T18:28:00Z host heroku router - dyno=web.1 service=25ms status=200\n",
+            "x".repeat(MAX_LINE_LENGTH + 1)
+        );
+        let mut metrics: Vec<Metric> = Vec::new();
+        let counts = app()
+            .extract_into("test", data.as_bytes(), &mut metrics)
+            .await
+            .unwrap();
+
+        assert_eq!(counts.lines, 4);
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(counts.errors["parse.unexpected_eof"], 1);
+        assert_eq!(counts.errors["too_long"], 1);
+        assert_eq!(counts.errors["decode.too_few_fields"], 1);
     }
 
-    #[instrument]
-    fn decode_metric(decoder: &Decoder, ld: &LogData) -> Result<Option<Metric>> {
-        Ok(decoder.decode(&ld).map_err(|e| {
-            tracing::warn!("Problem decoding log message: {}", e);
-            e
-        })?)
+    #[tokio::test]
+    async fn test_it_reads_past_lines_that_are_not_utf8() {
+        let mut data =
+            b"<158>1 2019-11-25T18:28:00Z host heroku router - path=/caf\xe9 service=5ms\r\n"
+                .to_vec();
+        data.extend_from_slice(b"\xff\xfe\n");
+        data.extend_from_slice(b"<158>1 2019-11-25T18:28:01Z host heroku router - service=7ms");
+
+        let mut metrics: Vec<Metric> = Vec::new();
+        let counts = app()
+            .extract_into("test", &data[..], &mut metrics)
+            .await
+            .unwrap();
+
+        assert_eq!(counts.lines, 3);
+        assert_eq!(counts.unparsed, 1);
+        assert_eq!(metrics.len(), 2);
     }
 }
@@ -1,5 +1,7 @@
 use clap::{crate_name, Parser, Subcommand};
 
+use logsnarf::{metric_writer::print::Format, parser::Mode, replay};
+
 #[derive(Parser)]
 #[command(name = crate_name!(), author, version, about, long_about = None)]
 pub struct Cli {
@@ -9,12 +11,131 @@ pub struct Cli {
 
 #[derive(Subcommand)]
 pub enum Commands {
-    /// Extract metrics from a single log file
+    /// Extract metrics from log files, which may be gzipped (.gz) or zstd compressed (.zst)
     Parse {
-        /// File to parse
+        /// Files or globs to parse, or `-` for stdin
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// How many files to parse at once
+        #[arg(long, short, default_value_t = 1)]
+        jobs: usize,
+
+        /// Format of the lines, instead of `parser.mode` from the config: logplex, rfc5424,
+        /// rfc3164, auto, json or logfmt
+        #[arg(long)]
+        mode: Option<Mode>,
+
+        /// Print the metrics instead of writing them, followed by a summary of what matched
+        #[arg(long)]
+        dry_run: bool,
+
+        /// How to print metrics for --dry-run: line-protocol, json or table
+        #[arg(long, default_value = "line-protocol", requires = "dry_run")]
+        format: Format,
+    },
+
+    /// Backfill from archived log files, oldest first, at a limited rate
+    Replay {
+        /// Files or globs to replay, which may be compressed like for `parse`
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Format of the lines, instead of `parser.mode` from the config
+        #[arg(long)]
+        mode: Option<Mode>,
+
+        /// Write at most this many points a second
+        #[arg(long)]
+        rate: Option<u64>,
+
+        /// Move every timestamp so the oldest line happened now, eg for a load test
+        #[arg(long)]
+        shift_to_now: bool,
+
+        /// Skip points older than this, eg `30d` for the database's retention period
+        #[arg(long, value_parser = replay::parse_duration)]
+        max_age: Option<chrono::Duration>,
+    },
+
+    /// Follow a log file as it grows, like `tail -F`, resuming where it left off after a restart
+    Tail {
+        /// File to follow
         file: String,
+
+        /// Where to remember how far into the file we got, instead of the XDG state directory
+        #[arg(long)]
+        checkpoint: Option<String>,
+
+        /// Format of the lines, instead of `parser.mode` from the config
+        #[arg(long)]
+        mode: Option<Mode>,
+
+        /// Without a checkpoint, read what's already in the file instead of only new lines
+        #[arg(long)]
+        from_start: bool,
+    },
+
+    /// Suggest `[[metrics]]` config for the kinds of lines in a sample log file
+    Discover {
+        /// File to scan, which may be compressed like for `parse`, or `-` for stdin
+        file: String,
+
+        /// Format of the lines, instead of `parser.mode` from the config
+        #[arg(long)]
+        mode: Option<Mode>,
+
+        /// Stop after this many lines
+        #[arg(long, default_value_t = 100_000)]
+        limit: usize,
+    },
+
+    /// Show how one line is parsed and decoded, and why decoders that don't match it don't
+    Explain {
+        /// The raw line, quoted
+        #[arg(required_unless_present = "file", conflicts_with = "file")]
+        line: Option<String>,
+
+        /// Read the line from this file instead, which may be compressed like for `parse`
+        #[arg(long)]
+        file: Option<String>,
+
+        /// Which line of --file, counting from 1
+        #[arg(long, short = 'n', default_value_t = 1, requires = "file")]
+        line_number: usize,
+
+        /// Format of the line, instead of `parser.mode` from the config
+        #[arg(long)]
+        mode: Option<Mode>,
+    },
+
+    /// Send synthetic Heroku drain traffic to a running server, and report how it responded
+    Loadgen {
+        /// Where to POST frames, instead of /logs on the port from the config
+        #[arg(long)]
+        url: Option<String>,
+
+        /// Lines per second, across every tenant
+        #[arg(long, default_value_t = 1000)]
+        rate: u64,
+
+        /// How many apps, each with its own drain token, to spread the lines over
+        #[arg(long, default_value_t = 10)]
+        tenants: usize,
+
+        /// Lines in each frame
+        #[arg(long, default_value_t = 100)]
+        batch: usize,
+
+        /// How many seconds to send for
+        #[arg(long, default_value_t = 60)]
+        duration: u64,
+
+        /// Most frames to have in flight at once
+        #[arg(long, default_value_t = 64)]
+        concurrency: usize,
     },
 
-    /// Run a server that continuously parses metrics from input
+    /// Run a server that continuously parses metrics from Heroku HTTPS drains
     Server,
 }
@@ -0,0 +1,83 @@
+use std::io::{self, Write};
+
+use futures::StreamExt;
+use tokio::fs::File;
+use tokio::io::BufReader;
+use tokio_util::codec::FramedRead;
+use tracing::instrument;
+
+use logsnarf::{
+    app::Lines,
+    compression::{Encoding, Reader},
+    discover::Discovery,
+    error::Result,
+    parser::{self, Mode},
+    settings::Settings,
+};
+
+/// The path that means stdin, like for `parse`
+const STDIN: &str = "-";
+
+pub struct Discover {
+    settings: Settings,
+}
+
+impl Discover {
+    pub fn new(settings: Settings) -> Self {
+        Self { settings }
+    }
+
+    /// Reads up to `limit` lines of `path` and prints a suggested `[[metrics]]` block for each
+    /// kind of line with numbers in it
+    #[instrument(name = "Discover::discover", skip(self))]
+    pub async fn discover(&self, path: String, mode: Option<Mode>, limit: usize) -> Result<()> {
+        let options = parser::Options {
+            mode: mode.unwrap_or(self.settings.parser.mode),
+            ..self.settings.parser.clone()
+        };
+        let mut lines = if path == STDIN {
+            open(Encoding::Identity.decode(BufReader::new(tokio::io::stdin()), None))
+        } else {
+            let file = File::open(&path).await?;
+            open(Encoding::from_path(&path).decode(BufReader::new(file), None))
+        };
+
+        let mut discovery = Discovery::new();
+        let (mut scanned, mut unparsed) = (0, 0);
+        while scanned < limit {
+            let line = match lines.next().await.transpose()? {
+                Some(line) => line,
+                None => break,
+            };
+            scanned += 1;
+            // Too long to be a line worth suggesting a metric for
+            let Some(line) = line else {
+                unparsed += 1;
+                continue;
+            };
+            match parser::parse_line_with(&options, &line) {
+                Ok(Some(ld)) => discovery.add(&ld),
+                Ok(None) => {}
+                Err(_) => unparsed += 1,
+            }
+        }
+
+        let suggestions = discovery.suggestions();
+        let mut out = io::stdout().lock();
+        for suggestion in &suggestions {
+            writeln!(out, "{}", suggestion)?;
+        }
+        eprintln!(
+            "Scanned {} lines, {} didn't parse, {} suggested metrics",
+            scanned,
+            unparsed,
+            suggestions.len()
+        );
+        Ok(())
+    }
+}
+
+// Stdin or a file, possibly compressed
+fn open(reader: Reader) -> FramedRead<Reader, Lines> {
+    FramedRead::new(reader, Lines::new())
+}
@@ -0,0 +1,335 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use futures::StreamExt;
+use tokio::fs::File;
+use tokio::io::BufReader;
+use tokio_util::codec::FramedRead;
+
+use logsnarf::{
+    app::{App, Lines, MAX_LINE_LENGTH},
+    compression::Encoding,
+    decoder::Decoder,
+    error::{LineErrorKind, Result},
+    metric::Metric,
+    metric_writer::influxdb_v1::WriteDataPoint,
+    parser::{KVPairs, LogData, Mode},
+    pipeline::Trace,
+    settings::{Matcher, Settings},
+};
+
+pub struct Explain {
+    app: App,
+}
+
+impl Explain {
+    pub fn new(settings: Settings) -> Result<Self> {
+        let app = App::new(settings)?;
+        Ok(Self { app })
+    }
+
+    /// Prints what happens to one line, either given directly or read from line `line_number`
+    /// (counting from 1) of `file`: how it parses, which decoders match it and why the others
+    /// don't, and the metrics that come out
+    pub async fn explain(
+        &self,
+        line: Option<String>,
+        file: Option<String>,
+        line_number: usize,
+        mode: Option<Mode>,
+    ) -> Result<()> {
+        let (source, line) = match (line, file) {
+            (Some(line), _) => (None, line),
+            (None, Some(file)) => {
+                let line = read_line(&file, line_number).await?;
+                (Some(file), line)
+            }
+            (None, None) => return Err("a line or a file is needed".to_string().into()),
+        };
+        let mode = mode.unwrap_or(self.app.settings().parser.mode);
+
+        let mut out = io::stdout().lock();
+        self.write_explanation(&mut out, source.as_deref(), &line, mode)?;
+        Ok(())
+    }
+
+    // Everything comes from the same stages `parse` and the server run, so what's explained is
+    // what would happen
+    fn write_explanation(
+        &self,
+        out: &mut impl Write,
+        source: Option<&str>,
+        line: &str,
+        mode: Mode,
+    ) -> io::Result<()> {
+        let trace = self.app.trace_line(source.unwrap_or(""), line, mode);
+        writeln!(out, "Line:\n  {}\n", line)?;
+
+        writeln!(out, "Parsed as {:?}:", mode)?;
+        let ld = match &trace.log_data {
+            Some(ld) => ld,
+            None => {
+                return match trace.results.first() {
+                    Some(Err(e)) => writeln!(out, "  failed: {}", e),
+                    _ => writeln!(out, "  nothing, the line is empty"),
+                };
+            }
+        };
+        write_log_data(out, ld)?;
+
+        writeln!(out, "\nTimestamp:")?;
+        let rejected = trace.results.iter().find_map(|result| match result {
+            Err(e @ LineErrorKind::Timestamp(_)) => Some(e),
+            _ => None,
+        });
+        let matched = trace.decoder.is_some() || trace.builtins.iter().any(|(_, m)| *m);
+        match (trace.timestamp, rejected) {
+            (Some(timestamp), _) => writeln!(out, "  {}", timestamp.to_rfc3339())?,
+            (None, Some(e)) => writeln!(out, "  dropped: {}", e)?,
+            (None, None) if matched => writeln!(out, "  none, so the line is skipped")?,
+            (None, None) => writeln!(out, "  not needed, nothing matched")?,
+        }
+
+        // Only the first configured decoder that matches is used
+        writeln!(out, "\nDecoders:")?;
+        for (name, mismatch) in &trace.decoders {
+            match (mismatch, &trace.decoder) {
+                (Some((attr, condition)), _) => writeln!(
+                    out,
+                    "  {}: no match, {} {} failed, was {}",
+                    name,
+                    attr,
+                    condition_text(condition),
+                    attribute(ld, attr).map_or("missing".into(), |v| format!("{:?}", v)),
+                )?,
+                (None, Some(chosen)) if chosen == name => {
+                    writeln!(out, "  {}: matches", name)?;
+                    if let Some(decoder) = self.app.decoders().iter().find(|d| d.name() == name) {
+                        write_pairs(out, decoder, &trace.pairs)?;
+                    }
+                    write_results(out, &trace, name)?;
+                }
+                (None, chosen) => writeln!(
+                    out,
+                    "  {}: matches, but {} matched first",
+                    name,
+                    chosen.as_deref().unwrap_or("another")
+                )?,
+            }
+        }
+        if trace.decoders.is_empty() {
+            writeln!(out, "  none configured")?;
+        }
+
+        writeln!(out, "\nBuiltins:")?;
+        for (name, matched) in &trace.builtins {
+            if !matched {
+                writeln!(out, "  {}: no match", name)?;
+                continue;
+            }
+            writeln!(out, "  {}: matches", name)?;
+            write_results(out, &trace, name)?;
+        }
+        if trace.builtins.is_empty() {
+            writeln!(out, "  none enabled")?;
+        }
+        Ok(())
+    }
+}
+
+async fn read_line(path: &str, line_number: usize) -> Result<String> {
+    let file = File::open(path).await?;
+    let data = Encoding::from_path(path).decode(BufReader::new(file), None);
+    let mut lines = FramedRead::new(data, Lines::new());
+
+    let mut count = 0;
+    while let Some(line) = lines.next().await.transpose()? {
+        count += 1;
+        if count == line_number {
+            return line.ok_or_else(|| {
+                format!(
+                    "line {} is longer than {} bytes",
+                    line_number, MAX_LINE_LENGTH
+                )
+                .into()
+            });
+        }
+    }
+    Err(format!(
+        "{} has {} lines, there's no line {}",
+        path, count, line_number
+    )
+    .into())
+}
+
+fn write_log_data(out: &mut impl Write, ld: &LogData) -> io::Result<()> {
+    let rows = [
+        ("facility", ld.facility_name().map(String::from)),
+        ("severity", ld.severity_name().map(String::from)),
+        ("version", ld.version.map(|v| v.to_string())),
+        (
+            "timestamp",
+            ld.timestamp_str.as_ref().map(|t| t.to_string()),
+        ),
+        ("hostname", Some(ld.hostname.to_string())),
+        ("appname", Some(ld.appname.to_string())),
+        ("procid", Some(ld.procid.to_string())),
+        ("msgid", ld.msgid.map(String::from)),
+        ("msg", Some(ld.msg.to_string())),
+    ];
+    for (name, value) in rows {
+        match value {
+            Some(value) => writeln!(out, "  {:<10} {:?}", name, value)?,
+            None => writeln!(out, "  {:<10} -", name)?,
+        }
+    }
+    for (id, params) in &ld.structured_data {
+        for (name, value) in params {
+            writeln!(out, "  sd.{}.{} {:?}", id, name, value)?;
+        }
+    }
+    Ok(())
+}
+
+// The pairs the decoder read from the message, and the keys it wanted but didn't find
+fn write_pairs(
+    out: &mut impl Write,
+    decoder: &Decoder,
+    pairs: &BTreeMap<String, String>,
+) -> io::Result<()> {
+    writeln!(out, "    pairs:")?;
+    for (key, value) in pairs {
+        writeln!(out, "      {} = {:?}", key, value)?;
+    }
+
+    let dec = decoder.metric_decoder();
+    let missing: Vec<&str> = dec
+        .tag_names
+        .iter()
+        .chain(&dec.field_names)
+        .filter(|key| !key.contains(['*', '?']) && !pairs.contains_key(key.as_str()))
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        writeln!(out, "    missing keys: {}", missing.join(", "))?;
+    }
+    let pairs: KVPairs = pairs
+        .iter()
+        .map(|(key, value)| (key.as_str(), Cow::Borrowed(value.as_str())))
+        .collect();
+    let unused = decoder.unmatched_keys(&pairs);
+    if !unused.is_empty() {
+        writeln!(out, "    unused keys: {}", unused.join(", "))?;
+    }
+    Ok(())
+}
+
+// What came out of one decoder or builtin, after the same checks as for any other line
+fn write_results(out: &mut impl Write, trace: &Trace, name: &str) -> io::Result<()> {
+    let range = match trace.decoded.iter().find(|(decoded, _)| decoded == name) {
+        Some((_, range)) => range.clone(),
+        None => return Ok(()),
+    };
+    if range.is_empty() {
+        return writeln!(out, "    no metric");
+    }
+    for result in &trace.results[range] {
+        match result {
+            Ok(metric) => write_metric(out, metric)?,
+            Err(LineErrorKind::Decode(e)) if e.is_rejection() => {
+                writeln!(out, "    rejected: {}", e)?
+            }
+            Err(e) => writeln!(out, "    failed: {}", e)?,
+        }
+    }
+    Ok(())
+}
+
+fn write_metric(out: &mut impl Write, metric: &Metric) -> io::Result<()> {
+    write!(out, "    metric: ")?;
+    metric.write_data_point_to(&mut *out)
+}
+
+fn condition_text(condition: &Matcher) -> String {
+    match condition {
+        Matcher::String(s) => format!("= {:?}", s),
+        Matcher::Condition(conditions) => {
+            let rules: Vec<String> = conditions
+                .iter()
+                .map(|(rule, value)| format!("{} {:?}", rule, value))
+                .collect();
+            rules.join(" or ")
+        }
+    }
+}
+
+// The value a matcher looked at, the same way `Decoder::mismatch` reads it
+fn attribute<'a>(ld: &'a LogData, attr: &str) -> Option<Cow<'a, str>> {
+    match attr {
+        "hostname" => Some(ld.hostname.as_ref().into()),
+        "appname" => Some(ld.appname.as_ref().into()),
+        "procid" => Some(ld.procid.as_ref().into()),
+        "msgid" => ld.msgid.map(Into::into),
+        "msg" => Some(ld.msg.as_ref().into()),
+        "severity" => ld.severity_name().map(Into::into),
+        "facility" => ld.facility_name().map(Into::into),
+        _ => {
+            let (id, name) = attr.strip_prefix("sd.")?.rsplit_once('.')?;
+            ld.sd_param(id, name).map(Into::into)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use logsnarf::{parser::Mode, settings::Settings};
+
+    use super::Explain;
+
+    const LINE: &str = r#"<158>1 2019-11-25T18:31:12Z host heroku router - at=error code=H12 desc="Request timeout" method=GET host=myapp.example dyno=web.2 connect=1ms service=30000ms status=503 protocol=https"#;
+
+    fn explain(line: &str) -> String {
+        let settings = Settings::from_toml(include_str!("../../../logsnarf.toml")).unwrap();
+        let mut out = Vec::new();
+        Explain::new(settings)
+            .unwrap()
+            .write_explanation(&mut out, None, line, Mode::Auto)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_it_explains_decoders_and_builtins() {
+        let out = explain(LINE);
+
+        assert!(
+            out.contains("  timestamp  \"2019-11-25T18:31:12Z\"\n"),
+            "{}",
+            out
+        );
+        assert!(out.contains("\nTimestamp:\n  2019-11-25T18:31:12+00:00\n"));
+        assert!(out.contains(
+            "  heroku_dyno_load: no match, msg contains \"sample#load_avg_1m\" failed, was"
+        ));
+        assert!(out.contains("  heroku_router: matches\n    pairs:\n      connect = \"1ms\"\n"));
+        assert!(out.contains("      dyno = \"web.2\"\n"));
+        // Only the keys the decoder asked for
+        assert!(!out.contains("      code = "));
+        assert!(out.contains("    missing keys: bytes\n"));
+        assert!(out.contains("    metric: heroku_router,dyno=web.2,"));
+        assert!(out.contains("  heroku_errors: matches\n    metric: heroku_errors,"));
+        assert!(out.contains("  l2met: no match\n"));
+    }
+
+    #[test]
+    fn test_it_explains_lines_that_dont_parse() {
+        let out = explain("<45>1 2019-11-25T18:28:00Z h\u{e9}st heroku web.1 - msg");
+
+        assert!(
+            out.ends_with("Parsed as Auto:\n  failed: invalid header\n"),
+            "{}",
+            out
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::MissedTickBehavior;
+use tracing::{info, instrument};
+
+use logsnarf::{
+    error::Result,
+    loadgen::{Latencies, Tenant},
+    logplex::{DRAIN_TOKEN, FRAME_ID, MSG_COUNT},
+    settings::Settings,
+};
+
+use crate::server::DEFAULT_HTTP_PORT;
+
+/// What happened to one frame
+enum Outcome {
+    Status(u16, Duration),
+    Failed,
+}
+
+pub struct Loadgen {
+    url: String,
+}
+
+impl Loadgen {
+    /// Sends to the server `settings` would start, unless there's a `url`
+    pub fn new(settings: Settings, url: Option<String>) -> Self {
+        let port = settings.daemon.http_port.unwrap_or(DEFAULT_HTTP_PORT);
+        let url = url.unwrap_or_else(|| format!("http://localhost:{}/logs", port));
+        Self { url }
+    }
+
+    /// POSTs `rate` lines a second, in frames of `batch`, spread over `tenants` drains, for
+    /// `duration`. At most `concurrency` frames are in flight, so a server that can't keep up
+    /// shows as a lower rate as well as slower responses.
+    #[instrument(name = "Loadgen::run", skip(self))]
+    pub async fn run(
+        &self,
+        rate: u64,
+        tenants: usize,
+        batch: usize,
+        duration: Duration,
+        concurrency: usize,
+    ) -> Result<()> {
+        let client = reqwest::Client::new();
+        let batch = batch.max(1);
+        let concurrency = concurrency.max(1);
+        let mut tenants: Vec<Tenant> = (0..tenants.max(1))
+            .map(|id| Tenant::new(id, rand::random()))
+            .collect();
+
+        let frames_per_second = (rate as f64 / batch as f64).max(0.001);
+        let mut ticks = tokio::time::interval(Duration::from_secs_f64(1.0 / frames_per_second));
+        // After waiting for a permit, the next frame is a whole period later, rather than every
+        // frame that was due meanwhile going out at once
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let in_flight = Arc::new(Semaphore::new(concurrency));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        info!(
+            "Sending {} lines/s to {} from {} tenants",
+            rate,
+            self.url,
+            tenants.len()
+        );
+
+        let started = Instant::now();
+        let mut frames = 0;
+        while started.elapsed() < duration {
+            ticks.tick().await;
+            let permit = in_flight
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            let next = frames % tenants.len();
+            let (frame, body) = tenants[next].frame(batch, Utc::now());
+            frames += 1;
+
+            let mut request = client
+                .post(&self.url)
+                .header("content-type", "application/logplex-1")
+                .body(body);
+            for (name, value) in [
+                (MSG_COUNT, frame.msg_count.map(|n| n.to_string())),
+                (FRAME_ID, frame.frame_id),
+                (DRAIN_TOKEN, frame.drain_token),
+            ] {
+                if let Some(value) = value {
+                    request = request.header(name, value);
+                }
+            }
+
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let sent = Instant::now();
+                let outcome = match request.send().await {
+                    Ok(response) => Outcome::Status(response.status().as_u16(), sent.elapsed()),
+                    Err(_) => Outcome::Failed,
+                };
+                let _ = tx.send(outcome);
+                drop(permit);
+            });
+        }
+        drop(tx);
+        let elapsed = started.elapsed();
+
+        let mut statuses: BTreeMap<u16, u64> = BTreeMap::new();
+        let mut failed = 0;
+        let mut latencies = Latencies::default();
+        while let Some(outcome) = rx.recv().await {
+            match outcome {
+                Outcome::Status(status, latency) => {
+                    *statuses.entry(status).or_default() += 1;
+                    latencies.record(latency);
+                }
+                Outcome::Failed => failed += 1,
+            }
+        }
+
+        report(frames * batch, elapsed, &statuses, failed, &mut latencies);
+        Ok(())
+    }
+}
+
+fn report(
+    lines: usize,
+    elapsed: Duration,
+    statuses: &BTreeMap<u16, u64>,
+    failed: u64,
+    latencies: &mut Latencies,
+) {
+    let non_2xx: u64 = statuses
+        .iter()
+        .filter(|(status, _)| !(200..300).contains(*status))
+        .map(|(_, count)| count)
+        .sum();
+
+    println!(
+        "Sent {} lines in {:.1}s, {:.0} lines/s",
+        lines,
+        elapsed.as_secs_f64(),
+        lines as f64 / elapsed.as_secs_f64()
+    );
+    for (status, count) in statuses {
+        println!("  {}  {:>8}", status, count);
+    }
+    println!("non-2xx {:>8}", non_2xx);
+    println!("failed  {:>8}", failed);
+
+    if latencies.is_empty() {
+        return;
+    }
+    let percentiles: Vec<String> = [50.0, 90.0, 99.0, 100.0]
+        .iter()
+        .map(|p| {
+            let latency = latencies.percentile(*p).unwrap_or_default();
+            let name = match *p {
+                p if p >= 100.0 => "max".to_string(),
+                p => format!("p{}", p),
+            };
+            format!("{} {:.1}ms", name, latency.as_secs_f64() * 1000.0)
+        })
+        .collect();
+    println!("latency {}", percentiles.join("  "));
+}
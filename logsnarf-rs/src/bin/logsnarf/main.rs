@@ -1,9 +1,17 @@
+use std::time::Duration;
+
 use clap::Parser;
 
 use logsnarf::{settings::Settings, util};
 
 mod cli;
+mod discover;
+mod explain;
+mod loadgen;
 mod parser;
+mod replay;
+mod server;
+mod tail;
 
 use cli::{Cli, Commands};
 
@@ -15,8 +23,72 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Parse { file } => parser::Parser::new(settings).parse(file).await?,
-        Commands::Server => (),
+        Commands::Parse {
+            files,
+            jobs,
+            mode,
+            dry_run,
+            format,
+        } => {
+            parser::Parser::new(settings)?
+                .parse(files, mode, jobs, dry_run.then_some(format))
+                .await?
+        }
+        Commands::Replay {
+            files,
+            mode,
+            rate,
+            shift_to_now,
+            max_age,
+        } => {
+            replay::Replay::new(settings)?
+                .replay(files, mode, rate, shift_to_now, max_age)
+                .await?
+        }
+        Commands::Tail {
+            file,
+            checkpoint,
+            mode,
+            from_start,
+        } => {
+            tail::Tail::new(settings)?
+                .follow(file, checkpoint, mode, from_start)
+                .await?
+        }
+        Commands::Discover { file, mode, limit } => {
+            discover::Discover::new(settings)
+                .discover(file, mode, limit)
+                .await?
+        }
+        Commands::Explain {
+            line,
+            file,
+            line_number,
+            mode,
+        } => {
+            explain::Explain::new(settings)?
+                .explain(line, file, line_number, mode)
+                .await?
+        }
+        Commands::Loadgen {
+            url,
+            rate,
+            tenants,
+            batch,
+            duration,
+            concurrency,
+        } => {
+            loadgen::Loadgen::new(settings, url)
+                .run(
+                    rate,
+                    tenants,
+                    batch,
+                    Duration::from_secs(duration),
+                    concurrency,
+                )
+                .await?
+        }
+        Commands::Server => server::Server::new(settings)?.run().await?,
     };
 
     util::teardown()?;
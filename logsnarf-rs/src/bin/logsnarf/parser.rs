@@ -1,25 +1,194 @@
+use std::io::{self, Write};
+
+use futures::{stream, StreamExt};
 use tokio::fs::File;
 use tokio::io::BufReader;
+use tracing::{error, instrument};
 
-use tracing::instrument;
+use logsnarf::{
+    app::{App, Counts},
+    compression::Encoding,
+    error::{Error, Result},
+    metric_writer::{
+        self,
+        channel::{self, Sender},
+        print::{Format, Print},
+        rollup::Rollup,
+    },
+    parser::Mode,
+    settings::Settings,
+};
 
-use logsnarf::{app::App, error::Result, settings::Settings};
+/// The path that means stdin, eg `heroku logs --tail | logsnarf parse -`
+const STDIN: &str = "-";
 
 pub struct Parser {
     app: App,
 }
 
 impl Parser {
-    pub fn new(settings: Settings) -> Self {
-        let app = App::new(settings);
-        Self { app }
+    pub fn new(settings: Settings) -> Result<Self> {
+        let app = App::new(settings)?;
+        Ok(Self { app })
     }
 
+    /// Extracts from every path, `jobs` at a time, into one shared writer. With `dry_run`, the
+    /// metrics are printed in that format instead of written, and a summary goes to stderr.
     #[instrument(name = "Parser::parse", skip(self))]
-    pub async fn parse(&self, filename: String) -> Result<()> {
-        let file = File::open(&filename).await?;
-        let data = BufReader::new(file);
+    pub async fn parse(
+        &self,
+        paths: Vec<String>,
+        mode: Option<Mode>,
+        jobs: usize,
+        dry_run: Option<Format>,
+    ) -> Result<()> {
+        let paths = expand(&paths)?;
+        let mode = mode.unwrap_or(self.app.settings().parser.mode);
+        let settings = self.app.settings();
+        let flush_every = settings.daemon.flush_every();
+
+        let (sender, receiver) = channel::channel();
+        let writing = async {
+            match dry_run {
+                Some(format) => {
+                    let mut writer =
+                        Rollup::new(&settings.rollups, Print::new(format, io::stdout()))?;
+                    receiver.write_to(&mut writer, flush_every).await?;
+                }
+                None => {
+                    let mut writer =
+                        Rollup::new(&settings.rollups, metric_writer::build(&settings.tsdb))?;
+                    receiver.write_to(&mut writer, flush_every).await?;
+                }
+            }
+            Ok::<_, Error>(())
+        };
+        let extracting = async move {
+            let results = stream::iter(paths)
+                .map(|path| {
+                    let sender = sender.clone();
+                    async move {
+                        let result = self.extract(&path, mode, sender).await;
+                        (path, result)
+                    }
+                })
+                .buffer_unordered(jobs.max(1))
+                .collect::<Vec<_>>()
+                .await;
+            Ok(results)
+        };
+        // If the writer fails there's nowhere for the metrics to go, so the extractions stop too
+        let ((), results) = tokio::try_join!(writing, extracting)?;
+
+        let mut total = Counts::default();
+        let mut failed = 0;
+        for (path, result) in &results {
+            match result {
+                Ok(counts) => total.merge(counts),
+                Err(e) => {
+                    failed += 1;
+                    error!("Problem parsing {}: {}", path, e);
+                }
+            }
+        }
+
+        if dry_run.is_some() {
+            write_summary(&total, io::stderr())?;
+        }
+        if failed > 0 {
+            return Err(format!("{} of {} files failed", failed, results.len()).into());
+        }
+        Ok(())
+    }
+
+    async fn extract(&self, path: &str, mode: Mode, mut writer: Sender) -> Result<Counts> {
+        if path == STDIN {
+            let data = BufReader::new(tokio::io::stdin());
+            return self
+                .app
+                .extract_into_as("stdin", mode, data, &mut writer)
+                .await;
+        }
+
+        let file = File::open(path).await?;
+        let data = Encoding::from_path(path).decode(BufReader::new(file), None);
+        self.app
+            .extract_into_as(path, mode, data, &mut writer)
+            .await
+    }
+}
 
-        self.app.extract(data).await
+// Shells expand globs already, but not quoted ones, or on every platform
+pub fn expand(paths: &[String]) -> Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    for path in paths {
+        if path == STDIN || !path.contains(['*', '?', '[']) {
+            expanded.push(path.clone());
+            continue;
+        }
+
+        let mut matches = glob::glob(path)
+            .map_err(|e| format!("bad glob {}: {}", path, e))?
+            .map(|entry| entry.map(|entry| entry.display().to_string()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        if matches.is_empty() {
+            return Err(format!("no files match {}", path).into());
+        }
+        matches.sort();
+        expanded.append(&mut matches);
+    }
+    Ok(expanded)
+}
+
+fn write_summary(counts: &Counts, mut w: impl Write) -> io::Result<()> {
+    writeln!(w)?;
+    for (name, count) in [
+        ("lines", counts.lines),
+        ("matched", counts.matched),
+        ("unmatched", counts.unmatched),
+        ("unparsed", counts.unparsed),
+        ("metrics", counts.metrics),
+        ("rejected", counts.rejected),
+    ] {
+        writeln!(w, "{:<10} {:>10}", name, count)?;
+    }
+
+    if !counts.errors.is_empty() {
+        let width = counts.errors.keys().map(String::len).max().unwrap_or(0);
+        writeln!(w)?;
+        writeln!(w, "{:<width$} {:>10}", "error", "lines", width = width)?;
+        for (kind, count) in &counts.errors {
+            writeln!(w, "{:<width$} {:>10}", kind, count, width = width)?;
+        }
+    }
+
+    if counts.decoders.is_empty() {
+        return Ok(());
+    }
+    let width = counts.decoders.keys().map(String::len).max().unwrap_or(0);
+    writeln!(w)?;
+    writeln!(
+        w,
+        "{:<width$} {:>10} {:>10} {:>10} {:>10}",
+        "decoder",
+        "lines",
+        "metrics",
+        "rejected",
+        "errors",
+        width = width
+    )?;
+    for (name, count) in &counts.decoders {
+        writeln!(
+            w,
+            "{:<width$} {:>10} {:>10} {:>10} {:>10}",
+            name,
+            count.lines,
+            count.metrics,
+            count.rejected,
+            count.errors,
+            width = width
+        )?;
     }
+    Ok(())
 }
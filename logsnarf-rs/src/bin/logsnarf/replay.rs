@@ -0,0 +1,269 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use tokio::fs::File;
+use tokio::io::BufReader;
+use tokio_util::codec::FramedRead;
+use tracing::{info, instrument};
+
+use logsnarf::{
+    app::{App, Counts, Lines},
+    compression::{Encoding, Reader},
+    error::Result,
+    metric::Metric,
+    metric_writer::{self, rollup::Rollup, MetricWriter},
+    parser::{self, Mode},
+    record_stream::RecordStream,
+    replay::{self, Merge, Progress, RateLimit, Retime},
+    settings::Settings,
+    timestamp::{self, Skew},
+};
+
+// Lines handed to the app at a time, and between writes
+const BATCH_LINES: usize = 1000;
+// How far into a file to look for a timestamp to order it by
+const MAX_PROBE_LINES: usize = 1000;
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct Replay {
+    app: App,
+}
+
+/// How the replay is going, across every file
+struct Run<W> {
+    writer: W,
+    retime: Retime,
+    limit: Option<RateLimit>,
+    progress: Progress,
+    read: Arc<AtomicUsize>,
+    counts: Counts,
+    too_long: u64,
+    written: u64,
+    expired: u64,
+    flush_every: Duration,
+    flushed: Instant,
+    reported: Instant,
+}
+
+impl Replay {
+    /// Archived lines are all old, so the skew limits meant for live drains are turned off.
+    /// `max_age` takes their place.
+    pub fn new(mut settings: Settings) -> Result<Self> {
+        settings.timestamps.default.skew = Skew::Accept;
+        for policy in settings.timestamps.sources.values_mut() {
+            policy.skew = Skew::Accept;
+        }
+        let app = App::new(settings)?;
+        Ok(Self { app })
+    }
+
+    /// Writes the metrics from every file, merged so the oldest line goes first, at no more than
+    /// `rate` points a second. With `shift_to_now`, timestamps are moved so the oldest line happened now.
+    #[instrument(name = "Replay::replay", skip(self))]
+    pub async fn replay(
+        &self,
+        paths: Vec<String>,
+        mode: Option<Mode>,
+        rate: Option<u64>,
+        shift_to_now: bool,
+        max_age: Option<chrono::Duration>,
+    ) -> Result<()> {
+        let settings = self.app.settings();
+        let options = parser::Options {
+            mode: mode.unwrap_or(settings.parser.mode),
+            ..settings.parser.clone()
+        };
+
+        let mut files = Vec::new();
+        let mut total = 0;
+        for path in crate::parser::expand(&paths)? {
+            let first = first_timestamp(&path, &options).await?;
+            total += tokio::fs::metadata(&path).await?.len();
+            files.push((first, path));
+        }
+        // Files without any timestamps go last, where they can't hold back a rollup window
+        files.sort_by_key(|(first, path)| (first.is_none(), *first, path.clone()));
+
+        let now = Utc::now();
+        let retime = Retime {
+            shift: files
+                .first()
+                .and_then(|(first, _)| *first)
+                .filter(|_| shift_to_now)
+                .map(|oldest| now - oldest),
+            cutoff: max_age.map(|max_age| now - max_age),
+        };
+        info!(
+            "Replaying {} files, {} bytes, {:?}",
+            files.len(),
+            total,
+            retime
+        );
+
+        let mut run = Run {
+            writer: Rollup::new(&settings.rollups, metric_writer::build(&settings.tsdb))?,
+            retime,
+            limit: rate.map(RateLimit::new),
+            progress: Progress::new(total),
+            read: Arc::new(AtomicUsize::new(0)),
+            counts: Counts::default(),
+            too_long: 0,
+            written: 0,
+            expired: 0,
+            flush_every: settings.daemon.flush_every(),
+            flushed: Instant::now(),
+            reported: Instant::now(),
+        };
+        // Files are only opened once the replay gets to their first line, so only the ones that
+        // overlap are read at the same time
+        let mut pending = files.iter().enumerate().peekable();
+        let mut merge = Merge::new(|line: &str| line_timestamp(line, &options));
+        let mut batch = String::new();
+        let mut batched = 0;
+        let mut batch_source: Option<usize> = None;
+        loop {
+            while let Some((source, (_, path))) =
+                pending.next_if(|(_, (first, _))| match (first, merge.oldest()) {
+                    (_, None) => true,
+                    (Some(first), Some(oldest)) => *first <= oldest,
+                    (None, Some(_)) => false,
+                })
+            {
+                merge.push(source, open(path, &run.read).await?).await?;
+            }
+
+            // Lines go to the app in batches from one file at a time, since the file decides
+            // the source
+            let next = merge.next().await?;
+            if let Some(source) = batch_source {
+                if batched == BATCH_LINES || next.as_ref().is_none_or(|(next, _)| *next != source) {
+                    let path = &files[source].1;
+                    self.replay_batch(path, options.mode, &batch, &mut run)
+                        .await?;
+                    batch.clear();
+                    batched = 0;
+                    batch_source = None;
+                }
+            }
+
+            match next {
+                Some((source, Some(line))) => {
+                    batch.push_str(&line);
+                    batch.push('\n');
+                    batched += 1;
+                    batch_source = Some(source);
+                }
+                Some((_, None)) => run.too_long += 1,
+                None => break,
+            }
+        }
+        run.writer.close().await?;
+        run.report();
+        eprintln!(
+            "Replayed {} lines into {} points, {} older than the cutoff, {} rejected, {} too long",
+            run.counts.lines, run.written, run.expired, run.counts.rejected, run.too_long
+        );
+        Ok(())
+    }
+
+    async fn replay_batch<W: MetricWriter + Send>(
+        &self,
+        path: &str,
+        mode: Mode,
+        batch: &str,
+        run: &mut Run<W>,
+    ) -> Result<()> {
+        let mut metrics: Vec<Metric> = Vec::new();
+        let counts = self
+            .app
+            .extract_into_as(path, mode, batch.as_bytes(), &mut metrics)
+            .await?;
+        run.counts.merge(&counts);
+        run.write(metrics).await
+    }
+}
+
+impl<W: MetricWriter + Send> Run<W> {
+    async fn write(&mut self, metrics: Vec<Metric>) -> Result<()> {
+        let metrics: Vec<Metric> = metrics
+            .into_iter()
+            .filter_map(|metric| {
+                let kept = self.retime.apply(metric);
+                if kept.is_none() {
+                    self.expired += 1;
+                }
+                kept
+            })
+            .collect();
+
+        if let Some(limit) = &mut self.limit {
+            limit.wait(metrics.len() as u64).await;
+        }
+        self.written += metrics.len() as u64;
+        for metric in metrics {
+            self.writer.write(metric);
+        }
+
+        if self.flushed.elapsed() >= self.flush_every {
+            self.writer.flush().await?;
+            self.flushed = Instant::now();
+        }
+        if self.reported.elapsed() >= REPORT_INTERVAL {
+            self.report();
+            self.reported = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn report(&self) {
+        let done = self.read.load(Ordering::Relaxed) as u64;
+        let eta = match self.progress.eta(done) {
+            Some(eta) => replay::format_duration(eta),
+            None => "unknown".into(),
+        };
+        eprintln!(
+            "{:5.1}%  {} lines  {} points  eta {}",
+            self.progress.percent(done),
+            self.counts.lines,
+            self.written,
+            eta
+        );
+    }
+}
+
+async fn open(path: &str, read: &Arc<AtomicUsize>) -> Result<FramedRead<Reader, Lines>> {
+    let file = RecordStream::new(File::open(path).await?, read.clone());
+    let data = Encoding::from_path(path).decode(BufReader::new(file), None);
+    Ok(FramedRead::new(data, Lines::new()))
+}
+
+// The first timestamp in the file that can be parsed, if there's one near the start
+async fn first_timestamp(path: &str, options: &parser::Options) -> Result<Option<DateTime<Utc>>> {
+    let file = File::open(path).await?;
+    let data = Encoding::from_path(path).decode(BufReader::new(file), None);
+    let mut lines = FramedRead::new(data, Lines::new());
+
+    for _ in 0..MAX_PROBE_LINES {
+        let line = match lines.next().await {
+            Some(line) => line?,
+            None => break,
+        };
+        let timestamp = line
+            .as_deref()
+            .and_then(|line| line_timestamp(line, options));
+        if timestamp.is_some() {
+            return Ok(timestamp);
+        }
+    }
+    Ok(None)
+}
+
+fn line_timestamp(line: &str, options: &parser::Options) -> Option<DateTime<Utc>> {
+    match parser::parse_line_with(options, line) {
+        Ok(Some(ld)) => ld.timestamp_str.as_deref().and_then(timestamp::parse),
+        _ => None,
+    }
+}
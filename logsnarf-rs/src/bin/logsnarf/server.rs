@@ -0,0 +1,479 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{BodyStream, Path, State},
+    http::{header::CONTENT_ENCODING, HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use chrono::Utc;
+use tokio::io::AsyncRead;
+use tokio::sync::oneshot;
+use tokio_stream::StreamExt;
+use tokio_util::io::StreamReader;
+use tracing::{info, instrument, warn};
+
+use logsnarf::{
+    app::App,
+    compression::{self, Encoding},
+    error::{Error, Result},
+    error_sink,
+    logplex::{Dedupe, Frame, LossStats},
+    metric::Metric,
+    metric_writer::{
+        self,
+        channel::{self, Receiver, Sender},
+        rollup::Rollup,
+        MetricWriter,
+    },
+    parser::Mode,
+    settings::Settings,
+};
+
+pub const DEFAULT_HTTP_PORT: u16 = 42080;
+const DEFAULT_DEDUPE_WINDOW: u64 = 300;
+const DEFAULT_MAX_BODY_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Drains that don't send a `Logplex-Drain-Token`
+const UNKNOWN_DRAIN: &str = "unknown";
+
+pub struct Server {
+    app: App,
+    port: u16,
+    flush_interval: Duration,
+    dedupe_window: Duration,
+    max_body_size: u64,
+    drain_token_tag: Option<String>,
+}
+
+/// Everything the request handlers share
+struct Ingest {
+    app: App,
+    // Cloned by each request. The writer task owns the real writer, so flushing to the TSDB never
+    // holds up a request unless the channel fills.
+    writer: Sender,
+    dedupe: Mutex<Dedupe>,
+    // Since the last flush, by drain token
+    loss: Mutex<BTreeMap<String, LossStats>>,
+    // Lines that gave errors since the last flush, by drain token and kind
+    errors: Mutex<BTreeMap<String, BTreeMap<String, u64>>>,
+    max_body_size: u64,
+    drain_token_tag: Option<String>,
+}
+
+impl Server {
+    pub fn new(settings: Settings) -> Result<Self> {
+        let daemon = &settings.daemon;
+        let port = daemon.http_port.unwrap_or(DEFAULT_HTTP_PORT);
+        let flush_interval = daemon.flush_every();
+        let dedupe_window =
+            Duration::from_secs(daemon.dedupe_window.unwrap_or(DEFAULT_DEDUPE_WINDOW));
+        let max_body_size = daemon.max_body_size.unwrap_or(DEFAULT_MAX_BODY_SIZE);
+        let drain_token_tag = daemon.drain_token_tag.clone();
+
+        Ok(Self {
+            app: App::new(settings)?,
+            port,
+            flush_interval,
+            dedupe_window,
+            max_body_size,
+            drain_token_tag,
+        })
+    }
+
+    /// Accepts drain POSTs on `/logs` until ctrl-c, then writes whatever is left
+    pub async fn run(self) -> Result<()> {
+        let writer = Rollup::new(
+            &self.app.settings().rollups,
+            metric_writer::build(&self.app.settings().tsdb),
+        )?;
+        let (sender, receiver) = channel::channel();
+        let ingest = Arc::new(Ingest {
+            app: self.app,
+            writer: sender,
+            dedupe: Mutex::new(Dedupe::new(self.dedupe_window)),
+            loss: Mutex::default(),
+            errors: Mutex::default(),
+            max_body_size: self.max_body_size,
+            drain_token_tag: self.drain_token_tag,
+        });
+
+        let (shutdown, stopping) = oneshot::channel();
+        let writing = tokio::spawn(write_periodically(
+            ingest.clone(),
+            receiver,
+            writer,
+            self.flush_interval,
+            stopping,
+        ));
+
+        let router = Router::new()
+            .route("/logs", post(receive))
+            .route("/logs/:mode", post(receive))
+            .with_state(ingest);
+        let addr = SocketAddr::from(([0, 0, 0, 0], self.port));
+        info!("Listening on {}", addr);
+
+        axum::Server::bind(&addr)
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(async {
+                tokio::signal::ctrl_c().await.ok();
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let _ = shutdown.send(());
+        writing.await.map_err(|e| e.to_string())??;
+
+        Ok(())
+    }
+}
+
+impl Ingest {
+    /// Writes, and resets, the loss stats, error counts and timestamp skew for every drain that
+    /// sent anything since the last call
+    fn write_stats(&self, writer: &mut impl MetricWriter) {
+        let loss = std::mem::take(&mut *self.loss.lock().unwrap());
+        let errors = std::mem::take(&mut *self.errors.lock().unwrap());
+        let skew = self.app.take_skew_stats();
+        let now = Utc::now();
+        for (drain, stats) in loss.iter().filter(|(_, stats)| !stats.is_empty()) {
+            writer.write(stats.to_metric(drain, now));
+        }
+        for (drain, errors) in &errors {
+            if let Some(metric) = error_sink::to_metric(drain, errors, now) {
+                writer.write(metric);
+            }
+        }
+        for (drain, stats) in skew.iter().filter(|(_, stats)| !stats.is_empty()) {
+            writer.write(stats.to_metric(drain, now));
+        }
+    }
+
+    /// Extracts one frame. Its id is claimed up front, so a retry that arrives while it's still
+    /// being extracted is a duplicate, but only remembered once its metrics are on their way to
+    /// the writer, so when anything goes wrong before that, Heroku's retry is extracted again.
+    async fn receive(
+        &self,
+        frame: &Frame,
+        mode: Mode,
+        encoding: Encoding,
+        body: impl AsyncRead + Unpin,
+    ) -> StatusCode {
+        let drain = frame.drain_token.as_deref().unwrap_or(UNKNOWN_DRAIN);
+
+        if let Some(frame_id) = &frame.frame_id {
+            if !self.dedupe.lock().unwrap().claim(frame_id, Instant::now()) {
+                self.loss
+                    .lock()
+                    .unwrap()
+                    .entry(drain.to_string())
+                    .or_default()
+                    .duplicate_frames += 1;
+                return StatusCode::OK;
+            }
+        }
+        let claim = Claim {
+            dedupe: &self.dedupe,
+            frame_id: frame.frame_id.as_deref(),
+        };
+
+        let mut metrics: Vec<Metric> = Vec::new();
+        let counts = match self
+            .app
+            .extract_into_as(drain, mode, body, &mut metrics)
+            .await
+        {
+            Ok(counts) => counts,
+            Err(Error::Io(e)) if compression::is_too_large(&e) => {
+                warn!("Problem reading frame: {}", e);
+                return StatusCode::PAYLOAD_TOO_LARGE;
+            }
+            Err(e) if encoding != Encoding::Identity => {
+                warn!("Problem reading frame: {}", e);
+                return StatusCode::BAD_REQUEST;
+            }
+            Err(e) => {
+                warn!("Problem reading frame: {}", e);
+                return StatusCode::OK;
+            }
+        };
+
+        let mut writer = self.writer.clone();
+        for mut metric in metrics {
+            if let Some(tag) = &self.drain_token_tag {
+                metric.tags.insert(tag.clone(), drain.to_string());
+            }
+            writer.write(metric);
+        }
+        if let Err(e) = writer.ready().await {
+            warn!("Problem writing metrics: {}", e);
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+        claim.record();
+
+        let matched = self
+            .loss
+            .lock()
+            .unwrap()
+            .entry(drain.to_string())
+            .or_default()
+            .record(frame, counts.lines);
+        if !counts.errors.is_empty() {
+            let mut errors = self.errors.lock().unwrap();
+            let totals = errors.entry(drain.to_string()).or_default();
+            for (kind, count) in &counts.errors {
+                *totals.entry(kind.clone()).or_default() += count;
+            }
+        }
+        if !matched {
+            warn!(
+                "Frame had {} lines, but Logplex-Msg-Count was {:?}",
+                counts.lines, frame.msg_count
+            );
+        }
+
+        StatusCode::OK
+    }
+}
+
+/// A frame id claimed from the dedupe. Unless it's recorded, dropping it releases the id again,
+/// whether the frame failed or the request was cancelled.
+struct Claim<'a> {
+    dedupe: &'a Mutex<Dedupe>,
+    frame_id: Option<&'a str>,
+}
+
+impl Claim<'_> {
+    fn record(mut self) {
+        if let Some(frame_id) = self.frame_id.take() {
+            self.dedupe.lock().unwrap().record(frame_id, Instant::now());
+        }
+    }
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        if let Some(frame_id) = self.frame_id {
+            self.dedupe.lock().unwrap().release(frame_id);
+        }
+    }
+}
+
+/// Owns the writer: writes what the requests send, and the stats and a flush every `every`. A
+/// flush that fails is only logged, and tried again next time. Once `stopping` fires, whatever was
+/// already sent is written and the writer is closed.
+async fn write_periodically<W: MetricWriter + Send>(
+    ingest: Arc<Ingest>,
+    mut receiver: Receiver,
+    mut writer: W,
+    every: Duration,
+    mut stopping: oneshot::Receiver<()>,
+) -> Result<()> {
+    let mut interval = tokio::time::interval(every);
+    interval.tick().await;
+    let mut stopped = false;
+
+    loop {
+        tokio::select! {
+            metric = receiver.recv() => match metric {
+                Some(metric) => writer.write(metric),
+                None => break,
+            },
+            _ = interval.tick() => {
+                ingest.write_stats(&mut writer);
+                if let Err(e) = writer.flush().await {
+                    warn!("Problem flushing metrics: {}", e);
+                }
+            }
+            _ = &mut stopping, if !stopped => {
+                stopped = true;
+                receiver.close();
+            }
+        }
+    }
+
+    ingest.write_stats(&mut writer);
+    writer.close().await?;
+    Ok(())
+}
+
+/// Heroku retries anything but a 2xx, so problems with the lines themselves are only logged.
+/// Bodies that can't be decompressed, which Heroku never sends, are refused.
+#[instrument(skip_all, fields(drain, frame_id, msg_count))]
+async fn receive(
+    State(ingest): State<Arc<Ingest>>,
+    mode: Option<Path<String>>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> StatusCode {
+    // `/logs/json` for JSON lines, `/logs/logfmt` and so on, or `/logs` for the configured mode
+    let mode = match mode {
+        Some(Path(mode)) => match mode.parse() {
+            Ok(mode) => mode,
+            Err(_) => return StatusCode::NOT_FOUND,
+        },
+        None => ingest.app.settings().parser.mode,
+    };
+
+    let encoding = match headers.get(CONTENT_ENCODING) {
+        Some(value) => match Encoding::from_content_encoding(value.to_str().unwrap_or_default()) {
+            Ok(encoding) => encoding,
+            Err(e) => {
+                warn!("{}", e);
+                return StatusCode::UNSUPPORTED_MEDIA_TYPE;
+            }
+        },
+        None => Encoding::Identity,
+    };
+
+    let frame = Frame::from_headers(&headers);
+    let drain = frame.drain_token.as_deref().unwrap_or(UNKNOWN_DRAIN);
+    tracing::Span::current().record("drain", drain);
+    if let Some(frame_id) = &frame.frame_id {
+        tracing::Span::current().record("frame_id", frame_id.as_str());
+    }
+    if let Some(msg_count) = frame.msg_count {
+        tracing::Span::current().record("msg_count", msg_count);
+    }
+
+    let body = encoding.decode(
+        StreamReader::new(body.map(|chunk| chunk.map_err(std::io::Error::other))),
+        Some(ingest.max_body_size),
+    );
+    ingest.receive(&frame, mode, encoding, body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use axum::http::StatusCode;
+    use tokio::io::{AsyncWriteExt, BufReader};
+
+    use logsnarf::{
+        app::App,
+        compression::Encoding,
+        logplex::{Dedupe, Frame},
+        metric::Metric,
+        metric_writer::channel::{self, Receiver},
+        parser::Mode,
+        settings::Settings,
+    };
+
+    use super::Ingest;
+
+    const LINE: &str =
+        "<158>1 2019-11-25T18:28:00Z host heroku router - dyno=web.1 service=25ms status=200\n";
+
+    fn ingest() -> (Ingest, Receiver) {
+        let settings = Settings::from_toml(include_str!("../../../logsnarf.toml")).unwrap();
+        let (writer, receiver) = channel::channel();
+        let ingest = Ingest {
+            app: App::new(settings).unwrap(),
+            writer,
+            dedupe: Mutex::new(Dedupe::new(Duration::from_secs(300))),
+            loss: Mutex::default(),
+            errors: Mutex::default(),
+            max_body_size: 1024,
+            drain_token_tag: None,
+        };
+        (ingest, receiver)
+    }
+
+    fn frame(id: &str) -> Frame {
+        Frame {
+            msg_count: Some(1),
+            frame_id: Some(id.into()),
+            drain_token: Some("d.1".into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_it_extracts_a_retry_of_a_frame_that_failed() {
+        let (ingest, mut receiver) = ingest();
+        let frame = frame("1");
+
+        let garbage =
+            Encoding::Gzip.decode(BufReader::new(Cursor::new(b"not gzip".to_vec())), None);
+        let status = ingest
+            .receive(&frame, Mode::Logplex, Encoding::Gzip, garbage)
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let too_large =
+            Encoding::Identity.decode(BufReader::new(Cursor::new(LINE.repeat(100))), Some(1024));
+        let status = ingest
+            .receive(&frame, Mode::Logplex, Encoding::Identity, too_large)
+            .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let status = ingest
+            .receive(&frame, Mode::Logplex, Encoding::Identity, LINE.as_bytes())
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(receiver.recv().await.unwrap().name, "heroku_router");
+
+        // Now it's been written, another retry is a duplicate
+        let status = ingest
+            .receive(&frame, Mode::Logplex, Encoding::Identity, LINE.as_bytes())
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        let loss = ingest.loss.lock().unwrap()["d.1"];
+        assert_eq!((loss.frames, loss.duplicate_frames), (1, 1));
+
+        let mut stats: Vec<Metric> = Vec::new();
+        ingest.write_stats(&mut stats);
+        let names: Vec<&str> = stats.iter().map(|metric| metric.name.as_str()).collect();
+        assert_eq!(names, ["logsnarf_drain", "logsnarf_timestamp_skew"]);
+        assert!(ingest.app.take_skew_stats().is_empty());
+        drop(ingest);
+        assert!(receiver.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_it_counts_overlapping_deliveries_of_a_frame_once() {
+        let (ingest, mut receiver) = ingest();
+        let frame = frame("1");
+
+        // The first delivery is still reading its body when the retry arrives
+        let (mut client, body) = tokio::io::duplex(1024);
+        client.write_all(LINE.as_bytes()).await.unwrap();
+        let first = ingest.receive(&frame, Mode::Logplex, Encoding::Identity, body);
+        tokio::pin!(first);
+        assert!(futures::poll!(&mut first).is_pending());
+
+        let status = ingest
+            .receive(&frame, Mode::Logplex, Encoding::Identity, LINE.as_bytes())
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        drop(client);
+        assert_eq!(first.await, StatusCode::OK);
+        assert_eq!(receiver.recv().await.unwrap().name, "heroku_router");
+        let loss = ingest.loss.lock().unwrap()["d.1"];
+        assert_eq!((loss.frames, loss.duplicate_frames), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_it_refuses_frames_it_cannot_write() {
+        let (ingest, receiver) = ingest();
+        drop(receiver);
+        let frame = frame("1");
+
+        let status = ingest
+            .receive(&frame, Mode::Logplex, Encoding::Identity, LINE.as_bytes())
+            .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!ingest
+            .dedupe
+            .lock()
+            .unwrap()
+            .is_duplicate("1", std::time::Instant::now()));
+    }
+}
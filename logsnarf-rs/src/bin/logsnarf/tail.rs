@@ -0,0 +1,93 @@
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use tracing::{info, instrument, warn};
+
+use logsnarf::{
+    app::App,
+    error::Result,
+    follow::{Checkpoint, Follower},
+    metric_writer::{self, rollup::Rollup, MetricWriter},
+    parser::Mode,
+    settings::Settings,
+};
+
+pub struct Tail {
+    app: App,
+}
+
+impl Tail {
+    pub fn new(settings: Settings) -> Result<Self> {
+        let app = App::new(settings)?;
+        Ok(Self { app })
+    }
+
+    /// Follows `path` until ctrl-c. The checkpoint is only saved after a flush, so lines are never
+    /// skipped on restart, though points still being rolled up when the process dies are lost.
+    #[instrument(name = "Tail::follow", skip(self))]
+    pub async fn follow(
+        &self,
+        path: String,
+        checkpoint: Option<String>,
+        mode: Option<Mode>,
+        from_start: bool,
+    ) -> Result<()> {
+        let settings = self.app.settings();
+        let mode = mode.unwrap_or(settings.parser.mode);
+        let flush_every = settings.daemon.flush_every();
+        let checkpoint_path = match checkpoint {
+            Some(checkpoint) => PathBuf::from(checkpoint),
+            None => default_checkpoint(&path)?,
+        };
+
+        let saved = Checkpoint::load(&checkpoint_path).await?;
+        let mut follower = Follower::open(&path, saved, from_start).await?;
+        info!("Following {} from {:?}", path, follower.checkpoint());
+
+        let mut writer = Rollup::new(&settings.rollups, metric_writer::build(&settings.tsdb))?;
+        let mut flushed = Instant::now();
+        let shutdown = tokio::signal::ctrl_c();
+        tokio::pin!(shutdown);
+
+        loop {
+            let chunk = tokio::select! {
+                _ = &mut shutdown => break,
+                chunk = follower.next_chunk() => chunk?,
+            };
+            // A chunk that can't be extracted is logged and skipped, so one bad line doesn't
+            // stop the tail or hold the checkpoint back forever
+            if !chunk.is_empty() {
+                if let Err(e) = self
+                    .app
+                    .extract_into_as(&path, mode, &chunk[..], &mut writer)
+                    .await
+                {
+                    warn!("Skipping {} bytes of {}: {}", chunk.len(), path, e);
+                }
+            }
+
+            if flushed.elapsed() >= flush_every {
+                writer.flush().await?;
+                follower.checkpoint().save(&checkpoint_path).await?;
+                flushed = Instant::now();
+            }
+        }
+
+        writer.close().await?;
+        follower.checkpoint().save(&checkpoint_path).await?;
+        Ok(())
+    }
+}
+
+// One per followed file, under eg ~/.local/state/logsnarf/
+fn default_checkpoint(path: &str) -> Result<PathBuf> {
+    let absolute = std::path::absolute(Path::new(path))?;
+    let name: String = absolute
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    let xdg_dirs = xdg::BaseDirectories::with_prefix("logsnarf").map_err(|e| e.to_string())?;
+    Ok(xdg_dirs.place_state_file(format!("tail{}.checkpoint", name))?)
+}
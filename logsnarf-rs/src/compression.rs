@@ -0,0 +1,205 @@
+//! Streaming decompression for request bodies and files, so shippers like Vector and Fluent Bit can
+//! send compressed batches, and rotated logs can be parsed without unpacking them first.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_compression::tokio::bufread::{GzipDecoder, ZlibDecoder, ZstdDecoder};
+use pin_project_lite::pin_project;
+use thiserror::Error;
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+
+#[derive(Debug, Error)]
+pub enum CompressionError {
+    #[error("unsupported Content-Encoding `{0}`")]
+    Unsupported(String),
+    #[error("decompressed data is larger than {0} bytes")]
+    TooLarge(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    /// The zlib format, which is what HTTP means by deflate
+    Deflate,
+    Zstd,
+}
+
+pub type Reader = Pin<Box<dyn AsyncRead + Send>>;
+
+impl Encoding {
+    pub fn from_content_encoding(header: &str) -> Result<Self, CompressionError> {
+        match header.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Ok(Self::Identity),
+            "gzip" | "x-gzip" => Ok(Self::Gzip),
+            "deflate" => Ok(Self::Deflate),
+            "zstd" => Ok(Self::Zstd),
+            other => Err(CompressionError::Unsupported(other.into())),
+        }
+    }
+
+    /// By extension, so `app.log.gz` is gzipped and `app.log` is read as-is
+    pub fn from_path(path: &str) -> Self {
+        match path.rsplit_once('.').map(|(_, ext)| ext) {
+            Some("gz") => Self::Gzip,
+            Some("zst") => Self::Zstd,
+            _ => Self::Identity,
+        }
+    }
+
+    /// Wraps `reader` in a decoder, which fails with `CompressionError::TooLarge` once more than
+    /// `limit` bytes come out of it
+    pub fn decode<R>(self, reader: R, limit: Option<u64>) -> Reader
+    where
+        R: AsyncBufRead + Send + 'static,
+    {
+        let decoded: Reader = match self {
+            Self::Identity => Box::pin(reader),
+            Self::Gzip => {
+                let mut decoder = GzipDecoder::new(reader);
+                // `cat a.gz b.gz > c.gz` is still a valid gzip file
+                decoder.multiple_members(true);
+                Box::pin(decoder)
+            }
+            Self::Deflate => Box::pin(ZlibDecoder::new(reader)),
+            Self::Zstd => Box::pin(ZstdDecoder::new(reader)),
+        };
+
+        match limit {
+            Some(limit) => Box::pin(Limit::new(decoded, limit)),
+            None => decoded,
+        }
+    }
+}
+
+pin_project! {
+    pub struct Limit<I> {
+        #[pin]
+        inner: I,
+        remaining: u64,
+        limit: u64,
+    }
+}
+
+impl<I> Limit<I> {
+    pub fn new(inner: I, limit: u64) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            limit,
+        }
+    }
+}
+
+impl<I: AsyncRead> AsyncRead for Limit<I> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        let poll = this.inner.poll_read(cx, buf);
+
+        let read = (buf.filled().len() - before) as u64;
+        if read > *this.remaining {
+            buf.set_filled(before + *this.remaining as usize);
+            *this.remaining = 0;
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                CompressionError::TooLarge(*this.limit),
+            )));
+        }
+        *this.remaining -= read;
+
+        poll
+    }
+}
+
+/// Whether `e` came from going over a `Limit`
+pub fn is_too_large(e: &io::Error) -> bool {
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<CompressionError>())
+        .is_some_and(|inner| matches!(inner, CompressionError::TooLarge(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use async_compression::tokio::bufread::{GzipEncoder, ZlibEncoder, ZstdEncoder};
+    use tokio::io::{AsyncReadExt, BufReader};
+
+    use super::{is_too_large, Encoding};
+
+    const LINES: &str = "83 <40>1 2012-11-30T06:45:29+00:00 host app web.3 - State changed\n";
+
+    async fn compress(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        match encoding {
+            Encoding::Gzip => GzipEncoder::new(data).read_to_end(&mut compressed).await,
+            Encoding::Deflate => ZlibEncoder::new(data).read_to_end(&mut compressed).await,
+            Encoding::Zstd => ZstdEncoder::new(data).read_to_end(&mut compressed).await,
+            Encoding::Identity => {
+                compressed.extend_from_slice(data);
+                Ok(data.len())
+            }
+        }
+        .unwrap();
+        compressed
+    }
+
+    #[test]
+    fn test_it_reads_the_encoding() {
+        assert_eq!(
+            Encoding::from_content_encoding("GZIP").unwrap(),
+            Encoding::Gzip
+        );
+        assert_eq!(
+            Encoding::from_content_encoding("deflate").unwrap(),
+            Encoding::Deflate
+        );
+        assert!(Encoding::from_content_encoding("br").is_err());
+
+        assert_eq!(Encoding::from_path("/var/log/app.log.zst"), Encoding::Zstd);
+        assert_eq!(Encoding::from_path("app.log"), Encoding::Identity);
+    }
+
+    #[tokio::test]
+    async fn test_it_decompresses() {
+        for encoding in [
+            Encoding::Identity,
+            Encoding::Gzip,
+            Encoding::Deflate,
+            Encoding::Zstd,
+        ] {
+            let compressed = compress(encoding, LINES.as_bytes()).await;
+
+            let mut decoded = String::new();
+            encoding
+                .decode(BufReader::new(Cursor::new(compressed)), Some(1024))
+                .read_to_string(&mut decoded)
+                .await
+                .unwrap();
+            assert_eq!(decoded, LINES);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_it_stops_at_the_limit() {
+        let bomb = LINES.repeat(1000);
+        let compressed = compress(Encoding::Gzip, bomb.as_bytes()).await;
+        assert!(compressed.len() < 1024);
+
+        let mut decoded = Vec::new();
+        let err = Encoding::Gzip
+            .decode(BufReader::new(Cursor::new(compressed)), Some(1024))
+            .read_to_end(&mut decoded)
+            .await
+            .unwrap_err();
+        assert!(is_too_large(&err));
+        assert_eq!(decoded.len(), 1024);
+    }
+}
@@ -0,0 +1,243 @@
+use std::borrow::Cow;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    decoder::{owned, DecodeError},
+    metric::Metric,
+    parser::{self, KVPairs, LogData},
+};
+
+const HEROKU: &str = "heroku";
+const HEROKU_ROUTER: &str = "router";
+
+const ERRORS_METRIC: &str = "heroku_errors";
+const DYNO_STATE_METRIC: &str = "heroku_dyno_state";
+
+const STATE_CHANGED: &str = "State changed from ";
+
+/// Router errors and warnings, eg `at=error code=H12 desc="Request timeout" ... dyno=web.2`, and
+/// dyno or logplex errors, eg `Error R14 (Memory quota exceeded)`
+pub fn matches_error(ld: &LogData) -> bool {
+    ld.appname == HEROKU
+        && if ld.procid == HEROKU_ROUTER {
+            let pairs = parser::extract_msg(&ld.msg, &["at", "code"]);
+            matches!(
+                pairs.get("at").map(AsRef::as_ref),
+                Some("error" | "warning")
+            ) && pairs.contains_key("code")
+        } else {
+            ld.msg.starts_with("Error ")
+        }
+}
+
+pub fn decode_error(ld: &LogData, timestamp: DateTime<Utc>) -> Result<Vec<Metric>, DecodeError> {
+    let mut tags = KVPairs::new();
+    let mut fields = KVPairs::new();
+
+    if ld.procid == HEROKU_ROUTER {
+        let pairs = parser::extract_msg(&ld.msg, &["code", "at", "dyno", "desc"]);
+        // An empty tag is invalid line protocol, so `code=` counts as no code at all
+        for key in ["code", "at"] {
+            if let Some(val) = pairs.get(key).filter(|val| !val.is_empty()) {
+                tags.insert(key, val.clone());
+            }
+        }
+        if let Some(dyno) = pairs.get("dyno") {
+            insert_dyno(&mut tags, dyno.clone());
+        }
+        if let Some(desc) = pairs.get("desc") {
+            fields.insert("desc", desc.clone());
+        }
+    } else {
+        // Error R14 (Memory quota exceeded)
+        // Error R10 (Boot timeout) -> Web process failed to bind to $PORT within 60 seconds
+        let rest = ld.msg.strip_prefix("Error ").unwrap_or(&ld.msg);
+        let (code, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if !code.is_empty() {
+            tags.insert("code", code.into());
+        }
+        tags.insert("at", "error".into());
+        insert_dyno(&mut tags, ld.procid.as_ref().into());
+
+        if let Some(desc) = rest.strip_prefix('(').and_then(|r| r.split_once(')')) {
+            fields.insert("desc", desc.0.into());
+        }
+    }
+
+    if !tags.contains_key("code") {
+        return Err(DecodeError::MissingTagKey("code".into(), owned(&tags)));
+    }
+    fields.insert("count", "1".into());
+
+    Ok(vec![Metric::new(
+        timestamp,
+        ERRORS_METRIC.into(),
+        tags,
+        fields,
+    )])
+}
+
+/// Dyno lifecycle events, eg `State changed from up to crashed`
+pub fn matches_dyno_state(ld: &LogData) -> bool {
+    ld.appname == HEROKU && ld.msg.starts_with(STATE_CHANGED)
+}
+
+pub fn decode_dyno_state(
+    ld: &LogData,
+    timestamp: DateTime<Utc>,
+) -> Result<Vec<Metric>, DecodeError> {
+    let (from, to) = ld
+        .msg
+        .strip_prefix(STATE_CHANGED)
+        .and_then(|rest| rest.split_once(" to "))
+        .ok_or(parser::ParseError::MissingField("to"))?;
+
+    let mut tags = KVPairs::new();
+    tags.insert("from", from.trim().into());
+    tags.insert("to", to.trim().into());
+    insert_dyno(&mut tags, ld.procid.as_ref().into());
+
+    let mut fields = KVPairs::new();
+    fields.insert("count", "1".into());
+
+    Ok(vec![Metric::new(
+        timestamp,
+        DYNO_STATE_METRIC.into(),
+        tags,
+        fields,
+    )])
+}
+
+// `web.1` is dyno `web.1` of process type `web`. The router leaves `dyno=` blank when the request
+// never reached one, so those get neither tag.
+fn insert_dyno<'a>(tags: &mut KVPairs<'a>, dyno: Cow<'a, str>) {
+    if dyno.is_empty() {
+        return;
+    }
+    let process_type = match &dyno {
+        Cow::Borrowed(dyno) => Cow::Borrowed(dyno.split('.').next().unwrap_or(dyno)),
+        Cow::Owned(dyno) => Cow::Owned(dyno.split('.').next().unwrap_or(dyno).to_string()),
+    };
+    tags.insert("dyno", dyno);
+    tags.insert("process_type", process_type);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::FieldValue;
+
+    fn parse(line: &str) -> LogData<'_> {
+        parser::parse_line(line)
+            .expect("Should parse a line")
+            .expect("Should have data")
+    }
+
+    #[test]
+    fn test_it_decodes_router_errors() {
+        let ld = parse(
+            r#"331 <158>1 2019-11-25T18:31:12.734164+00:00 host heroku router - at=error code=H12 desc="Request timeout" method=GET path="/reports/export" host=myapp.example request_id=0e5a6c7e-6a49-4e49-9a6b-2c1b1f4ad0c3 fwd="52.90.232.237" dyno=web.2 connect=1ms service=30000ms status=503 bytes=0 protocol=https"#,
+        );
+        assert!(matches_error(&ld));
+
+        let metric = &decode_error(&ld, Utc::now()).unwrap()[0];
+        assert_eq!(metric.name, "heroku_errors");
+        assert_eq!(metric.tags["code"], "H12");
+        assert_eq!(metric.tags["at"], "error");
+        assert_eq!(metric.tags["dyno"], "web.2");
+        assert_eq!(metric.tags["process_type"], "web");
+        assert_eq!(metric.fields["count"], FieldValue::Integer(1, None));
+        assert_eq!(
+            metric.fields["desc"],
+            FieldValue::Text("Request timeout".into())
+        );
+    }
+
+    #[test]
+    fn test_it_skips_blank_router_dyno() {
+        let ld = parse(
+            r#"293 <158>1 2019-11-25T18:31:14.120332+00:00 host heroku router - at=error code=H10 desc="App crashed" method=GET path="/" host=myapp.example request_id=5d3c0dd1-0b8a-4e37-8f5c-d5b1f9f2b8f4 fwd="52.90.232.237" dyno= connect= service= status=503 bytes= protocol=https"#,
+        );
+
+        let metric = &decode_error(&ld, Utc::now()).unwrap()[0];
+        assert_eq!(metric.tags["code"], "H10");
+        assert!(!metric.tags.contains_key("dyno"));
+    }
+
+    #[test]
+    fn test_it_ignores_successful_requests() {
+        let ld = parse(
+            r#"302 <158>1 2019-11-25T18:28:00.089034+00:00 host heroku router - at=info method=GET path="/" host=myapp.example dyno=web.1 connect=0ms service=25ms status=200 bytes=1541 protocol=https"#,
+        );
+        assert!(!matches_error(&ld));
+
+        // `code=H` in a path or header isn't an error
+        let ld = parse(
+            r#"302 <158>1 2019-11-25T18:28:00.089034+00:00 host heroku router - at=info method=GET path="/search?code=H12" host=myapp.example dyno=web.1 connect=0ms service=25ms status=200 bytes=1541 protocol=https"#,
+        );
+        assert!(!matches_error(&ld));
+    }
+
+    #[test]
+    fn test_it_matches_router_warnings() {
+        let ld = parse(
+            r#"251 <158>1 2019-11-25T18:31:20.325114+00:00 host heroku router - sock=client at=warning code=H27 desc="Client Request Interrupted" method=POST path="/upload" host=myapp.example dyno=web.1 status=499 protocol=https"#,
+        );
+        assert!(matches_error(&ld));
+
+        let metric = &decode_error(&ld, Utc::now()).unwrap()[0];
+        assert_eq!(metric.tags["code"], "H27");
+        assert_eq!(metric.tags["at"], "warning");
+    }
+
+    #[test]
+    fn test_it_decodes_dyno_errors() {
+        let ld = parse(
+            "113 <45>1 2019-11-25T18:32:40.561920+00:00 host heroku worker.3 - Error R14 (Memory quota exceeded)",
+        );
+        assert!(matches_error(&ld));
+
+        let metric = &decode_error(&ld, Utc::now()).unwrap()[0];
+        assert_eq!(metric.tags["code"], "R14");
+        assert_eq!(metric.tags["dyno"], "worker.3");
+        assert_eq!(metric.tags["process_type"], "worker");
+        assert_eq!(
+            metric.fields["desc"],
+            FieldValue::Text("Memory quota exceeded".into())
+        );
+    }
+
+    #[test]
+    fn test_it_refuses_errors_without_a_code() {
+        let ld = parse(
+            r#"251 <158>1 2019-11-25T18:31:20.325114+00:00 host heroku router - at=error code= desc="Request timeout" method=GET path="/" host=myapp.example dyno=web.1 status=503 protocol=https"#,
+        );
+        assert!(matches!(
+            decode_error(&ld, Utc::now()),
+            Err(DecodeError::MissingTagKey(key, _)) if key == "code"
+        ));
+
+        let ld = parse("113 <45>1 2019-11-25T18:32:40.561920+00:00 host heroku worker.3 - Error ");
+        assert!(matches_error(&ld));
+        assert!(matches!(
+            decode_error(&ld, Utc::now()),
+            Err(DecodeError::MissingTagKey(key, _)) if key == "code"
+        ));
+    }
+
+    #[test]
+    fn test_it_decodes_dyno_state_changes() {
+        let ld = parse(
+            "106 <45>1 2019-11-25T18:33:02.102393+00:00 host heroku web.1 - State changed from up to crashed",
+        );
+        assert!(matches_dyno_state(&ld));
+
+        let metric = &decode_dyno_state(&ld, Utc::now()).unwrap()[0];
+        assert_eq!(metric.name, "heroku_dyno_state");
+        assert_eq!(metric.tags["from"], "up");
+        assert_eq!(metric.tags["to"], "crashed");
+        assert_eq!(metric.tags["process_type"], "web");
+        assert_eq!(metric.fields["count"], FieldValue::Integer(1, None));
+    }
+}
@@ -0,0 +1,152 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    decoder::DecodeError,
+    metric::Metric,
+    parser::{self, KVPairs, LogData, Token},
+};
+
+// Application processes log as `app web.1`, but so do the Heroku addons (`app heroku-postgres`),
+// which have their own `sample#` decoders.
+const APP: &str = "app";
+const ADDON_PREFIX: &str = "heroku-";
+
+const PREFIXES: &[&str] = &["count#", "measure#", "sample#"];
+
+/// Application log-metrics, using the l2met conventions:
+///
+/// ```text
+/// source=web.1 measure#db.query=12ms count#signup=1 sample#queue.depth=5
+/// ```
+pub fn matches(ld: &LogData) -> bool {
+    ld.appname == APP
+        && !ld.procid.starts_with(ADDON_PREFIX)
+        && PREFIXES.iter().any(|prefix| ld.msg.contains(prefix))
+}
+
+/// Each `count#`, `measure#` or `sample#` key becomes its own metric, named `metric_prefix` and the rest
+/// of the key, tagged with its `type`, and with a single `value` field. A bare `count#signup`
+/// counts 1. If a key appears more than once, the first one wins.
+pub fn decode(
+    ld: &LogData,
+    timestamp: DateTime<Utc>,
+    metric_prefix: &str,
+) -> Result<Vec<Metric>, DecodeError> {
+    let mut tags = KVPairs::new();
+    if let Some(source) = parser::extract_msg(&ld.msg, &["source"]).remove("source") {
+        tags.insert("source", source);
+    }
+
+    let mut seen = BTreeSet::new();
+    Ok(parser::scan_msg(&ld.msg)
+        .filter_map(|token| {
+            // A word is owned when it was quoted with escapes in it
+            let (key, val) = match token {
+                Token::Pair(key, val) => (Cow::Borrowed(key), val),
+                Token::Word(word) if word.starts_with("count#") => (word, Cow::Borrowed("")),
+                Token::Word(_) => return None,
+            };
+            let (kind, name) = PREFIXES.iter().find_map(|prefix| {
+                let name = key.strip_prefix(prefix)?;
+                Some((prefix.trim_end_matches('#'), name.to_string()))
+            })?;
+            if name.is_empty() || !seen.insert(key) {
+                return None;
+            }
+
+            let val = if kind == "count" && val.is_empty() {
+                Cow::Borrowed("1")
+            } else {
+                val
+            };
+            let mut tags = tags.clone();
+            tags.insert("type", kind.into());
+            let mut fields = KVPairs::new();
+            fields.insert("value", val);
+            Some(Metric::new(
+                timestamp,
+                format!("{}{}", metric_prefix, name),
+                tags,
+                fields,
+            ))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::FieldValue;
+
+    fn parse(line: &str) -> LogData<'_> {
+        parser::parse_line(line)
+            .expect("Should parse a line")
+            .expect("Should have data")
+    }
+
+    #[test]
+    fn test_it_decodes_each_key() {
+        let ld = parse(
+            "131 <190>1 2019-11-25T18:30:01.120451+00:00 host app web.1 - source=web.1 measure#db.query=12ms count#signup=1 sample#queue.depth=5",
+        );
+        assert!(matches(&ld));
+
+        let metrics = decode(&ld, Utc::now(), "").unwrap();
+        let names: Vec<&str> = metrics.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["db.query", "signup", "queue.depth"]);
+        let kinds: Vec<&str> = metrics.iter().map(|m| m.tags["type"].as_str()).collect();
+        assert_eq!(kinds, vec!["measure", "count", "sample"]);
+
+        assert_eq!(metrics[0].tags["source"], "web.1");
+        assert_eq!(
+            metrics[0].fields["value"],
+            FieldValue::Integer(12, Some("ms".into()))
+        );
+    }
+
+    #[test]
+    fn test_a_bare_count_is_one() {
+        let ld = parse(
+            "97 <190>1 2019-11-25T18:30:01.120451+00:00 host app web.1 - signed up count#signup count#signup=5",
+        );
+        assert!(matches(&ld));
+
+        let metrics = decode(&ld, Utc::now(), "").unwrap();
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].name, "signup");
+        assert_eq!(metrics[0].fields["value"], FieldValue::Integer(1, None));
+        assert!(!metrics[0].tags.contains_key("source"));
+    }
+
+    #[test]
+    fn test_it_counts_quoted_words_with_escapes() {
+        let ld = parse(
+            r#"97 <190>1 2019-11-25T18:30:01.120451+00:00 host app web.1 - "count#sign\\up" count#login"#,
+        );
+
+        let metrics = decode(&ld, Utc::now(), "").unwrap();
+        let names: Vec<&str> = metrics.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, vec!["sign\\up", "login"]);
+    }
+
+    #[test]
+    fn test_it_prefixes_names() {
+        let ld = parse(
+            "97 <190>1 2019-11-25T18:30:01.120451+00:00 host app web.1 - measure#db.query=12ms",
+        );
+
+        let metrics = decode(&ld, Utc::now(), "l2met_").unwrap();
+        assert_eq!(metrics[0].name, "l2met_db.query");
+    }
+
+    #[test]
+    fn test_it_ignores_addon_samples() {
+        let ld = parse(
+            "225 <134>1 2019-11-25T18:29:19+00:00 host app heroku-redis - source=CACHE_STORE addon=redis-regular-64666 sample#active-connections=18 sample#hit-rate=0.97585",
+        );
+        assert!(!matches(&ld));
+    }
+}
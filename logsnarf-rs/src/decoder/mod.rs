@@ -0,0 +1,584 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use config::ConfigError;
+use thiserror::Error;
+use tracing::instrument;
+
+use crate::metric::Metric;
+use crate::{
+    parser::{self, KVPairs, LogData},
+    settings::{Builtins, Matcher, MetricDecoder, MetricDecoders},
+};
+
+mod heroku;
+mod l2met;
+
+#[derive(Debug)]
+pub struct Decoder {
+    metric_decoder: MetricDecoder,
+    // Every key the decoder can use, so the rest of the message doesn't have to be parsed. `None`
+    // when it needs all of them, for globs or `strict`.
+    keys: Option<Vec<String>>,
+}
+
+/// Decoders implemented in code rather than configured with `[[metrics]]`. Unlike the configured
+/// decoders, where only the first match is used, every enabled builtin that matches a line is run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Builtin {
+    HerokuErrors,
+    HerokuDynoState,
+    /// With the prefix for metric names
+    L2met(String),
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error(transparent)]
+    ParseError(#[from] parser::ParseError),
+
+    #[error("Tag Key `{0}` was not found in {1:?}")]
+    MissingTagKey(String, BTreeMap<String, String>),
+
+    #[error("Field Key `{0}` was not found in {1:?}")]
+    MissingFieldKey(String, BTreeMap<String, String>),
+
+    #[error("Metric `{0}` has {1} fields, but needs at least {2}")]
+    TooFewFields(String, usize, usize),
+}
+
+impl DecodeError {
+    /// The line was decoded, but the resulting metric didn't meet the decoder's requirements
+    pub fn is_rejection(&self) -> bool {
+        matches!(
+            self,
+            DecodeError::MissingTagKey(..)
+                | DecodeError::MissingFieldKey(..)
+                | DecodeError::TooFewFields(..)
+        )
+    }
+
+    /// A short name for the variant, for counting errors by kind
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DecodeError::ParseError(_) => "parse",
+            DecodeError::MissingTagKey(..) => "missing_tag_key",
+            DecodeError::MissingFieldKey(..) => "missing_field_key",
+            DecodeError::TooFewFields(..) => "too_few_fields",
+        }
+    }
+}
+
+impl Decoder {
+    pub fn new(metric_decoder: MetricDecoder) -> Self {
+        let names = || {
+            metric_decoder
+                .tag_names
+                .iter()
+                .chain(metric_decoder.field_names.iter())
+        };
+        let keys = if metric_decoder.strict || names().any(|key| is_glob(key)) {
+            None
+        } else {
+            Some(names().cloned().collect())
+        };
+
+        Self {
+            metric_decoder,
+            keys,
+        }
+    }
+
+    pub fn name(&self) -> &String {
+        &self.metric_decoder.name
+    }
+
+    pub fn metric_decoder(&self) -> &MetricDecoder {
+        &self.metric_decoder
+    }
+
+    pub fn matches(&self, log_data: &LogData) -> bool {
+        self.mismatch(log_data).is_none()
+    }
+
+    /// The first matcher condition that `log_data` fails, if any
+    pub fn mismatch(&self, log_data: &LogData) -> Option<(&str, &Matcher)> {
+        self.metric_decoder
+            .matcher
+            .iter()
+            .find(|(attr, condition)| !match_attribute(log_data, attr, condition))
+            .map(|(attr, condition)| (attr.as_str(), condition))
+    }
+
+    /// The pairs from the message that `decode` looks at, which is all of them unless every tag
+    /// and field name is known up front
+    pub fn pairs<'a>(&self, log_data: &'a LogData) -> KVPairs<'a> {
+        match &self.keys {
+            Some(keys) => parser::extract_msg(&log_data.msg, keys),
+            None => parser::parse_msg(&log_data.msg),
+        }
+    }
+
+    pub fn decode(
+        &self,
+        log_data: &LogData,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Option<Metric>, DecodeError> {
+        let dec = &self.metric_decoder;
+        let pairs = self.pairs(log_data);
+
+        if dec.strict {
+            let unmatched = self.unmatched_keys(&pairs);
+            if !unmatched.is_empty() {
+                tracing::warn!(
+                    "{}: keys matched no tag or field: {:?}",
+                    dec.name,
+                    unmatched
+                );
+            }
+        }
+
+        let tags = extract_keys(&dec.tag_names, &dec.exclude_tag_names, &pairs)?;
+        let fields = extract_keys(&dec.field_names, &dec.exclude_field_names, &pairs)?;
+
+        if let Some(key) = dec
+            .required_tags
+            .iter()
+            .find(|k| !tags.contains_key(k.as_str()))
+        {
+            return Err(DecodeError::MissingTagKey(key.clone(), owned(&pairs)));
+        }
+        if let Some(key) = dec
+            .required_fields
+            .iter()
+            .find(|k| !fields.contains_key(k.as_str()))
+        {
+            return Err(DecodeError::MissingFieldKey(key.clone(), owned(&pairs)));
+        }
+        let min_fields = dec.min_fields.max(1);
+        if fields.len() < min_fields {
+            return Err(DecodeError::TooFewFields(
+                dec.name.clone(),
+                fields.len(),
+                min_fields,
+            ));
+        }
+
+        Ok(Some(Metric::new(
+            timestamp,
+            dec.name.to_string(),
+            tags,
+            fields,
+        )))
+    }
+
+    /// Keys in `pairs` that this decoder would not use as either a tag or a field
+    pub fn unmatched_keys<'a>(&self, pairs: &KVPairs<'a>) -> Vec<&'a str> {
+        let dec = &self.metric_decoder;
+        pairs
+            .keys()
+            .filter(|key| {
+                !(includes(&dec.tag_names, &dec.exclude_tag_names, key)
+                    || includes(&dec.field_names, &dec.exclude_field_names, key))
+            })
+            .copied()
+            .collect()
+    }
+}
+
+impl Builtin {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::HerokuErrors => "heroku_errors",
+            Builtin::HerokuDynoState => "heroku_dyno_state",
+            Builtin::L2met(_) => "l2met",
+        }
+    }
+
+    pub fn matches(&self, log_data: &LogData) -> bool {
+        match self {
+            Builtin::HerokuErrors => heroku::matches_error(log_data),
+            Builtin::HerokuDynoState => heroku::matches_dyno_state(log_data),
+            Builtin::L2met(_) => l2met::matches(log_data),
+        }
+    }
+
+    pub fn decode(
+        &self,
+        log_data: &LogData,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Vec<Metric>, DecodeError> {
+        match self {
+            Builtin::HerokuErrors => heroku::decode_error(log_data, timestamp),
+            Builtin::HerokuDynoState => heroku::decode_dyno_state(log_data, timestamp),
+            Builtin::L2met(prefix) => l2met::decode(log_data, timestamp, prefix),
+        }
+    }
+}
+
+#[instrument(name = "configure_builtins", level = "trace")]
+pub fn build_builtins(builtins: &Builtins) -> Vec<Builtin> {
+    let mut out = Vec::new();
+    if builtins.heroku_errors {
+        out.push(Builtin::HerokuErrors);
+    }
+    if builtins.heroku_dyno_state {
+        out.push(Builtin::HerokuDynoState);
+    }
+    if builtins.l2met {
+        out.push(Builtin::L2met(builtins.l2met_prefix.clone()));
+    }
+    out
+}
+
+/// Fails on matcher attributes or conditions that don't exist, so a typo in the config is caught
+/// at startup rather than never matching
+#[instrument(name = "configure_decoders", level = "trace")]
+pub fn build_decoders(metric_decoders: &MetricDecoders) -> Result<Vec<Decoder>, ConfigError> {
+    metric_decoders
+        .iter()
+        .map(|dec| {
+            for (attr, condition) in &dec.matcher {
+                check_matcher(attr, condition).map_err(|e| {
+                    ConfigError::Message(format!("metrics `{}` matcher: {}", dec.name, e))
+                })?;
+            }
+            Ok(Decoder::new(dec.clone()))
+        })
+        .collect()
+}
+
+// Everything `match_attribute` knows how to match
+fn check_matcher(attr: &str, condition: &Matcher) -> Result<(), String> {
+    let known = match attr {
+        "hostname" | "appname" | "procid" | "msgid" | "msg" | "severity" | "facility" => true,
+        _ => attr
+            .strip_prefix("sd.")
+            .and_then(|sd| sd.rsplit_once('.'))
+            .is_some(),
+    };
+    if !known {
+        return Err(format!("unknown field `{}`", attr));
+    }
+
+    let check_severity = |value: &str| match parser::severity_from_str(value) {
+        Some(_) => Ok(()),
+        None => Err(format!("unknown severity `{}`", value)),
+    };
+    let rule = if attr == "severity" {
+        "at_least"
+    } else {
+        "contains"
+    };
+    match condition {
+        Matcher::String(value) if attr == "severity" => check_severity(value),
+        Matcher::String(_) => Ok(()),
+        Matcher::Condition(conditions) => {
+            for (name, value) in conditions {
+                if name != rule {
+                    return Err(format!("unknown condition `{}` for `{}`", name, attr));
+                }
+                if attr == "severity" {
+                    check_severity(value)?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+fn match_attribute(log_data: &LogData, attr: &str, condition: &Matcher) -> bool {
+    match attr {
+        "hostname" => match_value(&log_data.hostname, condition),
+        "appname" => match_value(&log_data.appname, condition),
+        "procid" => match_value(&log_data.procid, condition),
+        "msgid" => log_data
+            .msgid
+            .is_some_and(|msgid| match_value(msgid, condition)),
+        "msg" => match_value(&log_data.msg, condition),
+        "severity" => match_severity(log_data.severity, condition),
+        "facility" => log_data
+            .facility_name()
+            .is_some_and(|facility| match_value(facility, condition)),
+        // sd.<SD-ID>.<PARAM-NAME>, eg `sd.origin@48577.ip`
+        _ => match attr.strip_prefix("sd.").and_then(|sd| sd.rsplit_once('.')) {
+            Some((id, name)) => log_data
+                .sd_param(id, name)
+                .is_some_and(|value| match_value(value, condition)),
+            // Ruled out by `build_decoders`
+            None => false,
+        },
+    }
+}
+
+// Severities match by keyword or number, and `at_least` matches that severity or anything more
+// urgent, eg `severity = { at_least = "warning" }`
+fn match_severity(severity: Option<u8>, matcher_value: &Matcher) -> bool {
+    let severity = match severity {
+        Some(severity) => severity,
+        None => return false,
+    };
+
+    match matcher_value {
+        Matcher::String(str) => parser::severity_from_str(str) == Some(severity),
+        Matcher::Condition(conditions) => {
+            conditions.iter().any(|(rule, value)| match rule.as_str() {
+                "at_least" => parser::severity_from_str(value).is_some_and(|v| severity <= v),
+                _ => false,
+            })
+        }
+    }
+}
+
+fn match_value(data_value: &str, matcher_value: &Matcher) -> bool {
+    match matcher_value {
+        Matcher::String(str) => str == data_value,
+        Matcher::Condition(conditions) => {
+            conditions.iter().any(|(rule, value)| match rule.as_str() {
+                "contains" => data_value.contains(value),
+                _ => false,
+            })
+        }
+    }
+}
+
+/// Checks the rules every metric has to follow, no matter which decoder produced it. InfluxDB
+/// rejects an entire batch if any point in it has no fields.
+pub fn validate(metric: &Metric) -> Result<(), DecodeError> {
+    if metric.fields.is_empty() {
+        return Err(DecodeError::TooFewFields(metric.name.clone(), 0, 1));
+    }
+    Ok(())
+}
+
+fn extract_keys<'a>(
+    keys: &[String],
+    excludes: &[String],
+    pairs: &KVPairs<'a>,
+) -> Result<KVPairs<'a>, DecodeError> {
+    let mut out = KVPairs::new();
+    if keys.iter().any(|key| is_glob(key)) {
+        for (key, val) in pairs {
+            if includes(keys, excludes, key) {
+                out.insert(*key, val.clone());
+            }
+        }
+    } else {
+        for key in keys {
+            if let Some((key, val)) = pairs.get_key_value(key.as_str()) {
+                if !excludes.iter().any(|pattern| glob_match(pattern, key)) {
+                    out.insert(*key, val.clone());
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+// Errors outlive the line they came from
+pub(crate) fn owned(pairs: &KVPairs) -> BTreeMap<String, String> {
+    pairs
+        .iter()
+        .map(|(key, val)| (key.to_string(), val.to_string()))
+        .collect()
+}
+
+fn includes(patterns: &[String], excludes: &[String], key: &str) -> bool {
+    patterns.iter().any(|pattern| glob_match(pattern, key))
+        && !excludes.iter().any(|pattern| glob_match(pattern, key))
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?'])
+}
+
+/// Shell-style matching, where `*` matches any run of characters and `?` any single one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the most recent `*`, if the rest doesn't match
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use chrono::Utc;
+
+    use super::{build_decoders, extract_keys, glob_match, DecodeError, Decoder};
+    use crate::{
+        parser::{parse_line, parse_msg, rfc5424},
+        settings::{Matcher, MetricDecoder},
+    };
+
+    fn dyno_memory() -> MetricDecoder {
+        MetricDecoder {
+            name: "heroku_dyno_memory".into(),
+            tag_names: vec!["source".into()],
+            field_names: vec!["sample#memory_total".into(), "sample#memory_rss".into()],
+            ..Default::default()
+        }
+    }
+
+    const LINE: &str = "176 <45>1 2019-11-25T18:28:15.490955+00:00 host heroku background_worker.1 - source=background_worker.1 sample#memory_total=324.41MB sample#memory_cache=6.48MB";
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("sample#*", "sample#wal-percentage-used"));
+        assert!(glob_match("sample#load-avg-?m", "sample#load-avg-5m"));
+        assert!(glob_match("*-iops", "sample#read-iops"));
+        assert!(glob_match("sample#memory", "sample#memory"));
+        assert!(!glob_match("sample#*", "source"));
+        assert!(!glob_match("sample#load-avg-?m", "sample#load-avg-15m"));
+    }
+
+    #[test]
+    fn test_it_extracts_globbed_keys() {
+        let pairs =
+            parse_msg("source=HEROKU_POSTGRESQL_GREEN sample#tables=57 sample#current_transaction=369961 sample#read-iops=0");
+
+        let keys = vec!["sample#*".to_string()];
+        let excludes = vec!["*-iops".to_string()];
+        let out = extract_keys(&keys, &excludes, &pairs).unwrap();
+
+        let keys: Vec<&str> = out.keys().copied().collect();
+        assert_eq!(keys, vec!["sample#current_transaction", "sample#tables"]);
+    }
+
+    #[test]
+    fn test_it_rejects_missing_required_keys() {
+        let ld = parse_line(LINE).unwrap().unwrap();
+
+        let mut dec = dyno_memory();
+        dec.required_fields = vec!["sample#memory_rss".into()];
+        let r = Decoder::new(dec).decode(&ld, Utc::now());
+        assert!(
+            matches!(r, Err(DecodeError::MissingFieldKey(key, _)) if key == "sample#memory_rss")
+        );
+
+        let mut dec = dyno_memory();
+        dec.required_tags = vec!["dyno".into()];
+        let r = Decoder::new(dec).decode(&ld, Utc::now());
+        assert!(matches!(r, Err(DecodeError::MissingTagKey(key, _)) if key == "dyno"));
+    }
+
+    #[test]
+    fn test_it_never_decodes_a_metric_without_fields() {
+        let ld = parse_line(LINE).unwrap().unwrap();
+
+        let mut dec = dyno_memory();
+        dec.field_names = vec!["sample#memory_swap".into()];
+        let r = Decoder::new(dec).decode(&ld, Utc::now());
+        assert!(matches!(r, Err(DecodeError::TooFewFields(_, 0, 1))));
+
+        let mut dec = dyno_memory();
+        dec.min_fields = 2;
+        let r = Decoder::new(dec).decode(&ld, Utc::now());
+        assert!(matches!(r, Err(DecodeError::TooFewFields(_, 1, 2))));
+    }
+
+    #[test]
+    fn test_it_matches_severity_and_structured_data() {
+        let ld = rfc5424::parse_line(
+            r#"<11>1 2019-11-25T18:28:00Z host app 123 - [origin@48577 ip="10.0.0.1"] sample#memory_total=1MB"#,
+        )
+        .unwrap()
+        .unwrap();
+
+        let mut dec = dyno_memory();
+        let mut at_least = BTreeMap::new();
+        at_least.insert("at_least".to_string(), "warning".to_string());
+        dec.matcher
+            .insert("severity".into(), Matcher::Condition(at_least));
+        dec.matcher.insert(
+            "sd.origin@48577.ip".into(),
+            Matcher::String("10.0.0.1".into()),
+        );
+        assert!(Decoder::new(dec.clone()).matches(&ld));
+
+        dec.matcher
+            .insert("severity".into(), Matcher::String("debug".into()));
+        assert!(!Decoder::new(dec).matches(&ld));
+    }
+
+    #[test]
+    fn test_it_reports_the_first_failed_condition() {
+        let ld = parse_line(LINE).unwrap().unwrap();
+
+        let mut dec = dyno_memory();
+        dec.matcher
+            .insert("appname".into(), Matcher::String("heroku".into()));
+        assert!(Decoder::new(dec.clone()).mismatch(&ld).is_none());
+
+        dec.matcher
+            .insert("procid".into(), Matcher::String("web.1".into()));
+        let decoder = Decoder::new(dec);
+        let (attr, condition) = decoder.mismatch(&ld).unwrap();
+        assert_eq!(attr, "procid");
+        assert!(matches!(condition, Matcher::String(s) if s == "web.1"));
+    }
+
+    #[test]
+    fn test_it_refuses_unknown_matchers() {
+        let condition = |rule: &str, value: &str| {
+            let mut conditions = BTreeMap::new();
+            conditions.insert(rule.to_string(), value.to_string());
+            Matcher::Condition(conditions)
+        };
+        let valid = [
+            ("msg", condition("contains", "sample#")),
+            ("severity", condition("at_least", "warning")),
+            ("severity", Matcher::String("3".into())),
+            ("sd.origin@48577.ip", Matcher::String("10.0.0.1".into())),
+        ];
+        let invalid = [
+            ("hostnmae", Matcher::String("host".into())),
+            ("msg", condition("starts_with", "sample#")),
+            ("severity", condition("contains", "warning")),
+            ("severity", Matcher::String("loud".into())),
+            ("sd.origin", Matcher::String("10.0.0.1".into())),
+        ];
+
+        for (attr, matcher) in valid {
+            let mut dec = dyno_memory();
+            dec.matcher.insert(attr.into(), matcher);
+            assert!(build_decoders(&vec![dec]).is_ok(), "{}", attr);
+        }
+        for (attr, matcher) in invalid {
+            let mut dec = dyno_memory();
+            dec.matcher.insert(attr.into(), matcher);
+            let e = build_decoders(&vec![dec]).unwrap_err().to_string();
+            assert!(
+                e.starts_with("metrics `heroku_dyno_memory` matcher"),
+                "{}",
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,521 @@
+//! Proposes `[[metrics]]` blocks from sample logs. Lines are grouped by app, proc and the set of
+//! keys in their message, and each key is classified by the values seen for it: numbers become
+//! fields, and text with few distinct values becomes tags. Blocks are listed in the order they
+//! have to go in, each matching on a key none of the blocks after it has, so every block gets
+//! its own lines.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Display, Formatter};
+
+use crate::{
+    metric::FieldValue,
+    parser::{self, LogData},
+    settings::Matcher,
+};
+
+// Distinct values counted per key before giving up, so a request id can't use up all the memory
+const MAX_DISTINCT: usize = 1000;
+// Text keys with more distinct values than this would make too many series to be tags
+const MAX_TAG_CARDINALITY: usize = 50;
+
+#[derive(Debug, Default)]
+pub struct Discovery {
+    groups: BTreeMap<GroupKey, Group>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct GroupKey {
+    appname: String,
+    // Without the dyno number, so `web.1` and `web.2` end up together
+    process_type: String,
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Group {
+    lines: u64,
+    procids: BTreeSet<String>,
+    example: String,
+    keys: BTreeMap<String, KeyStats>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Integer,
+    Float,
+    Boolean,
+    Text,
+}
+
+/// What was seen for one key
+#[derive(Debug, Clone, Default)]
+pub struct KeyStats {
+    pub kind: Option<Kind>,
+    pub units: BTreeSet<String>,
+    values: BTreeSet<String>,
+}
+
+/// A `[[metrics]]` block, which displays as TOML with a comment describing each key
+#[derive(Debug, Clone)]
+pub struct Suggestion {
+    pub name: String,
+    pub lines: u64,
+    pub example: String,
+    pub matcher: BTreeMap<String, Matcher>,
+    pub tag_names: Vec<String>,
+    pub field_names: Vec<String>,
+    pub keys: BTreeMap<String, KeyStats>,
+}
+
+impl Discovery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, log_data: &LogData) {
+        let pairs = parser::parse_msg(&log_data.msg);
+        if pairs.is_empty() {
+            return;
+        }
+
+        let key = GroupKey {
+            appname: log_data.appname.to_string(),
+            process_type: process_type(&log_data.procid).to_string(),
+            keys: pairs.keys().map(|key| key.to_string()).collect(),
+        };
+        let group = self.groups.entry(key).or_default();
+        group.lines += 1;
+        group.procids.insert(log_data.procid.to_string());
+        if group.example.is_empty() {
+            group.example = log_data.msg.to_string();
+        }
+        for (key, value) in &pairs {
+            group.keys.entry(key.to_string()).or_default().add(value);
+        }
+    }
+
+    /// A block for each group with at least one numeric key, busiest app first, and in the
+    /// order they have to be listed within each app
+    pub fn suggestions(&self) -> Vec<Suggestion> {
+        let mut apps: BTreeMap<&str, Vec<(&str, Group)>> = BTreeMap::new();
+        for (key, group) in &self.groups {
+            if group.keys.values().any(KeyStats::is_numeric) {
+                apps.entry(&key.appname)
+                    .or_default()
+                    .push((&key.process_type, group.clone()));
+            }
+        }
+        let mut apps: Vec<_> = apps
+            .into_iter()
+            .map(|(appname, groups)| (appname, order_blocks(groups)))
+            .collect();
+        apps.sort_by_key(|(_, groups)| {
+            std::cmp::Reverse(groups.iter().map(|(_, group, _)| group.lines).sum::<u64>())
+        });
+
+        let mut names = BTreeSet::new();
+        let mut suggestions = Vec::new();
+        for (appname, groups) in apps {
+            for (process_type, group, match_key) in groups {
+                suggestions.push(suggestion(
+                    &mut names,
+                    appname,
+                    process_type,
+                    group,
+                    match_key,
+                ));
+            }
+        }
+        suggestions
+    }
+}
+
+fn suggestion(
+    names: &mut BTreeSet<String>,
+    appname: &str,
+    process_type: &str,
+    group: Group,
+    match_key: String,
+) -> Suggestion {
+    let field_names: Vec<String> = group
+        .keys
+        .iter()
+        .filter(|(_, stats)| stats.is_numeric())
+        .map(|(key, _)| key.clone())
+        .collect();
+    let tag_names = group
+        .keys
+        .iter()
+        .filter(|(_, stats)| !stats.is_numeric() && stats.cardinality() <= MAX_TAG_CARDINALITY)
+        .map(|(key, _)| key.clone())
+        .collect();
+
+    let mut matcher = BTreeMap::new();
+    if !appname.is_empty() {
+        matcher.insert("appname".into(), Matcher::String(appname.to_string()));
+    }
+    // Only when there's no dyno number, which the matcher can't leave out
+    if group.procids.len() == 1 && group.procids.contains(process_type) && !process_type.is_empty()
+    {
+        matcher.insert("procid".into(), Matcher::String(process_type.to_string()));
+    }
+    let mut contains = BTreeMap::new();
+    contains.insert("contains".to_string(), format!("{}=", match_key));
+    matcher.insert("msg".into(), Matcher::Condition(contains));
+
+    let mut name = metric_name(appname, process_type);
+    let base = name.clone();
+    for n in 2.. {
+        if names.insert(name.clone()) {
+            break;
+        }
+        name = format!("{}_{}", base, n);
+    }
+
+    Suggestion {
+        name,
+        lines: group.lines,
+        example: group.example,
+        matcher,
+        tag_names,
+        field_names,
+        keys: group.keys,
+    }
+}
+
+impl Group {
+    fn merge(&mut self, other: Group) {
+        self.lines += other.lines;
+        self.procids.extend(other.procids);
+        for (key, stats) in other.keys {
+            self.keys.entry(key).or_default().merge(stats);
+        }
+    }
+}
+
+// Orders the groups of one app, busiest first where there's a choice, along with the key each
+// one's block matches on. A block only matches on a key none of the groups after it has, so it
+// can't take their lines, and since the same goes for the blocks before it, they can't take its
+// lines either. The procid isn't always in the matcher, so this goes across process types. A
+// group that can't go anywhere, eg `web` and `worker` lines with the same keys, is merged into
+// the group that shares the most keys with it, preferring one of the same process type.
+fn order_blocks(mut groups: Vec<(&str, Group)>) -> Vec<(&str, Group, String)> {
+    let mut ordered = Vec::new();
+    while !groups.is_empty() {
+        let next = (0..groups.len())
+            .filter_map(|i| Some((i, match_key(&groups, i)?)))
+            .max_by_key(|(i, _)| (groups[*i].1.lines, std::cmp::Reverse(*i)));
+        if let Some((i, key)) = next {
+            let (process_type, group) = groups.remove(i);
+            ordered.push((process_type, group, key));
+            continue;
+        }
+
+        let smallest = (0..groups.len()).min_by_key(|&i| groups[i].1.lines);
+        let (process_type, group) = match smallest {
+            Some(i) => groups.remove(i),
+            None => break,
+        };
+        let shared = |other: &Group| {
+            group
+                .keys
+                .keys()
+                .filter(|key| other.keys.contains_key(*key))
+                .count()
+        };
+        let target = (0..groups.len()).max_by_key(|&j| {
+            let (other_type, other) = &groups[j];
+            (*other_type == process_type, shared(other), other.lines)
+        });
+        if let Some(j) = target {
+            groups[j].1.merge(group);
+        }
+    }
+    ordered
+}
+
+// A key of group `i` that no other group has, a field if there is one, since those are in every
+// line the block decodes. `msg contains "count="` also matches `page_count=`, so a key only counts
+// if no key of another group ends with it.
+fn match_key(groups: &[(&str, Group)], i: usize) -> Option<String> {
+    let distinct = |key: &&String| {
+        groups.iter().enumerate().all(|(j, (_, other))| {
+            j == i || !other.keys.keys().any(|other| other.ends_with(key.as_str()))
+        })
+    };
+    let keys = &groups[i].1.keys;
+    keys.iter()
+        .filter(|(_, stats)| stats.is_numeric())
+        .map(|(key, _)| key)
+        .find(distinct)
+        .or_else(|| keys.keys().find(distinct))
+        .cloned()
+}
+
+impl KeyStats {
+    fn add(&mut self, value: &str) {
+        let (kind, unit) = match value.parse::<FieldValue>() {
+            Ok(FieldValue::Integer(_, unit)) => (Kind::Integer, unit),
+            Ok(FieldValue::Float(_, unit)) => (Kind::Float, unit),
+            Ok(FieldValue::Boolean(_)) => (Kind::Boolean, None),
+            _ => (Kind::Text, None),
+        };
+        self.kind = Some(loosest(self.kind, kind));
+        self.units.extend(unit);
+        if self.values.len() < MAX_DISTINCT {
+            self.values.insert(value.to_string());
+        }
+    }
+
+    fn merge(&mut self, other: KeyStats) {
+        if let Some(kind) = other.kind {
+            self.kind = Some(loosest(self.kind, kind));
+        }
+        self.units.extend(other.units);
+        for value in other.values {
+            if self.values.len() >= MAX_DISTINCT {
+                break;
+            }
+            self.values.insert(value);
+        }
+    }
+
+    pub fn is_numeric(&self) -> bool {
+        matches!(self.kind, Some(Kind::Integer | Kind::Float))
+    }
+
+    /// Distinct values seen, up to `MAX_DISTINCT`
+    pub fn cardinality(&self) -> usize {
+        self.values.len()
+    }
+}
+
+// One `1.5` makes a key a float, and one `n/a` makes it text
+fn loosest(seen: Option<Kind>, kind: Kind) -> Kind {
+    match (seen, kind) {
+        (None, kind) => kind,
+        (Some(seen), kind) if seen == kind => kind,
+        (Some(Kind::Integer), Kind::Float) | (Some(Kind::Float), Kind::Integer) => Kind::Float,
+        _ => Kind::Text,
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let name = match self {
+            Kind::Integer => "integer",
+            Kind::Float => "float",
+            Kind::Boolean => "boolean",
+            Kind::Text => "text",
+        };
+        f.pad(name)
+    }
+}
+
+impl Display for Suggestion {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "# {} lines, eg: {}", self.lines, self.example)?;
+        let width = self.keys.keys().map(|key| key.len()).max().unwrap_or(0);
+        for (key, stats) in &self.keys {
+            let cardinality = match stats.cardinality() {
+                n if n >= MAX_DISTINCT => format!("{}+", n),
+                n => n.to_string(),
+            };
+            let units: Vec<&str> = stats.units.iter().map(String::as_str).collect();
+            let line = format!(
+                "# {:width$}  {:7}  {:>5} distinct  {}",
+                key,
+                stats.kind.unwrap_or(Kind::Text),
+                cardinality,
+                units.join(","),
+                width = width
+            );
+            writeln!(f, "{}", line.trim_end())?;
+        }
+
+        writeln!(f, "[[metrics]]")?;
+        writeln!(f, "name = {}", quote(&self.name))?;
+        let matcher: Vec<String> = self
+            .matcher
+            .iter()
+            .map(|(attr, condition)| format!("{} = {}", attr, matcher_toml(condition)))
+            .collect();
+        writeln!(f, "matcher = {{ {} }}", matcher.join(", "))?;
+        writeln!(f, "tag_names = {}", array(&self.tag_names))?;
+        writeln!(f, "field_names = {}", array(&self.field_names))
+    }
+}
+
+// `web.1` is a `web` dyno
+fn process_type(procid: &str) -> &str {
+    match procid.rsplit_once('.') {
+        Some((process_type, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+            process_type
+        }
+        _ => procid,
+    }
+}
+
+fn metric_name(appname: &str, process_type: &str) -> String {
+    let name: String = [appname, process_type]
+        .iter()
+        .filter(|part| !part.is_empty())
+        .copied()
+        .collect::<Vec<_>>()
+        .join("_")
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.is_empty() {
+        "metric".into()
+    } else {
+        name.to_lowercase()
+    }
+}
+
+fn matcher_toml(matcher: &Matcher) -> String {
+    match matcher {
+        Matcher::String(s) => quote(s),
+        Matcher::Condition(conditions) => {
+            let rules: Vec<String> = conditions
+                .iter()
+                .map(|(rule, value)| format!("{} = {}", rule, quote(value)))
+                .collect();
+            format!("{{ {} }}", rules.join(", "))
+        }
+    }
+}
+
+fn array(values: &[String]) -> String {
+    let values: Vec<String> = values.iter().map(|value| quote(value)).collect();
+    format!("[{}]", values.join(", "))
+}
+
+// A TOML basic string
+fn quote(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{Discovery, Kind};
+    use crate::{decoder::build_decoders, parser::parse_line, settings::MetricDecoder};
+
+    #[test]
+    fn test_it_suggests_tags_and_fields() {
+        let mut discovery = Discovery::new();
+        for (dyno, connect, service) in [("web.1", "1ms", "5"), ("web.2", "2ms", "7.5")] {
+            let line = format!(
+                "100 <158>1 2019-11-25T18:28:00Z host heroku router - method=GET dyno={} connect={} service={}",
+                dyno, connect, service
+            );
+            discovery.add(&parse_line(&line).unwrap().unwrap());
+        }
+        let line = "80 <45>1 2019-11-25T18:28:00Z host app web.1 - Started GET /";
+        discovery.add(&parse_line(line).unwrap().unwrap());
+
+        let suggestions = discovery.suggestions();
+        assert_eq!(suggestions.len(), 1);
+        let suggestion = &suggestions[0];
+        assert_eq!(suggestion.name, "heroku_router");
+        assert_eq!(suggestion.lines, 2);
+        assert_eq!(suggestion.tag_names, vec!["dyno", "method"]);
+        assert_eq!(suggestion.field_names, vec!["connect", "service"]);
+        assert_eq!(suggestion.keys["service"].kind, Some(Kind::Float));
+        assert!(suggestion.keys["connect"].units.contains("ms"));
+        assert_eq!(suggestion.keys["dyno"].cardinality(), 2);
+
+        let toml = suggestion.to_string();
+        assert!(toml.contains(
+            r#"matcher = { appname = "heroku", msg = { contains = "connect=" }, procid = "router" }"#
+        ));
+        assert!(toml.ends_with("field_names = [\"connect\", \"service\"]\n"));
+    }
+
+    #[test]
+    fn test_it_groups_dynos_of_the_same_process_type() {
+        let mut discovery = Discovery::new();
+        for dyno in ["web.1", "web.2"] {
+            let line = format!(
+                "80 <45>1 2019-11-25T18:28:00Z host app {} - sample#load_avg_1m=0.01",
+                dyno
+            );
+            discovery.add(&parse_line(&line).unwrap().unwrap());
+        }
+
+        let suggestions = discovery.suggestions();
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].name, "app_web");
+        assert!(!suggestions[0].matcher.contains_key("procid"));
+    }
+
+    #[test]
+    fn test_groups_sharing_keys_get_distinct_reachable_blocks() {
+        let lines = [
+            "<45>1 2019-11-25T18:28:00Z host app worker - job=mail count=3 duration=12ms",
+            "<45>1 2019-11-25T18:28:00Z host app worker - job=mail count=4 duration=9ms",
+            "<45>1 2019-11-25T18:28:00Z host app worker - job=sync count=1 size=512",
+            // Nothing of its own, so it has to go after the others
+            "<45>1 2019-11-25T18:28:00Z host app worker - count=2",
+            // Would also match `msg contains "count="`
+            "<45>1 2019-11-25T18:28:00Z host app worker - page_count=7",
+        ];
+        let mut discovery = Discovery::new();
+        for line in lines {
+            discovery.add(&parse_line(line).unwrap().unwrap());
+        }
+
+        let suggestions = discovery.suggestions();
+        assert_eq!(suggestions.len(), 4);
+        assert_eq!(suggestions[0].lines, 2);
+
+        let decoders = suggestions
+            .iter()
+            .map(|suggestion| MetricDecoder {
+                name: suggestion.name.clone(),
+                tag_names: suggestion.tag_names.clone(),
+                field_names: suggestion.field_names.clone(),
+                matcher: suggestion.matcher.clone(),
+                ..Default::default()
+            })
+            .collect();
+        let decoders = build_decoders(&decoders).unwrap();
+        let first_match = |line: &str| {
+            let ld = parse_line(line).unwrap().unwrap();
+            decoders
+                .iter()
+                .position(|decoder| decoder.matches(&ld))
+                .unwrap()
+        };
+
+        let matched: BTreeSet<usize> = lines.iter().map(|line| first_match(line)).collect();
+        assert_eq!(matched.len(), 4);
+        assert_eq!(first_match(lines[0]), first_match(lines[1]));
+        assert_eq!(first_match(lines[3]), 3);
+    }
+
+    #[test]
+    fn test_it_merges_groups_it_cannot_tell_apart() {
+        let mut discovery = Discovery::new();
+        for procid in ["web.1", "worker.1"] {
+            let line = format!("<45>1 2019-11-25T18:28:00Z host app {} - count=2", procid);
+            discovery.add(&parse_line(&line).unwrap().unwrap());
+        }
+
+        let suggestions = discovery.suggestions();
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].lines, 2);
+    }
+}
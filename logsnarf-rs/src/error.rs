@@ -1,6 +1,6 @@
 use thiserror::Error;
 
-use crate::{decoder, metric_writer, parser};
+use crate::{decoder, metric_writer, parser, timestamp};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -18,10 +18,55 @@ pub enum Error {
     #[error(transparent)]
     AdapterError(#[from] metric_writer::WriterError),
 
+    #[error(transparent)]
+    Config(#[from] config::ConfigError),
+
     #[error("{0}")]
     Msg(String),
 }
 
+/// Why a line gave no metrics, or fewer than it should have, along with the line itself
+#[derive(Error, Debug)]
+#[error("line {number}: {kind}")]
+pub struct LineError {
+    /// Counting from 1
+    pub number: u64,
+    pub line: String,
+    pub kind: LineErrorKind,
+}
+
+#[derive(Error, Debug)]
+pub enum LineErrorKind {
+    /// The input couldn't be read, eg because it didn't decompress. Nothing after it is read.
+    #[error(transparent)]
+    Read(::std::io::Error),
+
+    #[error("line is longer than {0} bytes")]
+    TooLong(usize),
+
+    #[error(transparent)]
+    Parse(parser::ParseError),
+
+    #[error(transparent)]
+    Timestamp(timestamp::TimestampError),
+
+    #[error(transparent)]
+    Decode(decoder::DecodeError),
+}
+
+impl LineErrorKind {
+    /// Eg `parse.invalid` or `decode.missing_field_key`, for counters and dead letters
+    pub fn name(&self) -> String {
+        match self {
+            LineErrorKind::Read(_) => "read".into(),
+            LineErrorKind::TooLong(_) => "too_long".into(),
+            LineErrorKind::Parse(e) => format!("parse.{}", e.kind()),
+            LineErrorKind::Timestamp(e) => format!("timestamp.{}", e.kind()),
+            LineErrorKind::Decode(e) => format!("decode.{}", e.kind()),
+        }
+    }
+}
+
 impl From<&'static str> for Error {
     fn from(s: &'static str) -> Self {
         Error::Msg(s.to_owned())
@@ -0,0 +1,462 @@
+//! Where lines that couldn't be turned into metrics go, so they can be looked at and replayed
+//! instead of only showing up as a warning: a dead-letter file with the raw line, and a sample
+//! of them sent to Sentry.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc::{self, SyncSender, TrySendError},
+    Mutex,
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    error::LineError,
+    metric::{FieldValue, Fields, Metric, Tags},
+};
+
+/// The metric the error counters are written as, with a field per error kind
+pub const ERRORS_METRIC: &str = "logsnarf_line_errors";
+
+const SENTRY_WINDOW: Duration = Duration::from_secs(60);
+// Dead letters waiting to be written before more are dropped, so a flood can't use up the memory
+const QUEUE_SIZE: usize = 4096;
+// With a file per source, the least recently written is closed to make room for another
+const MAX_OPEN_FILES: usize = 64;
+const REPORT_DROPPED_EVERY: u64 = 1000;
+
+/// The `[errors]` section of the config
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Options {
+    /// A file to append every bad line to, or a directory for a file per source. It's a directory
+    /// if it already is one or ends in a separator, eg `/var/lib/logsnarf/dead-letter/`.
+    pub dead_letter: Option<PathBuf>,
+    /// Once a dead-letter file is this big it's moved aside to `<name>.1`, replacing the last one
+    pub dead_letter_max_bytes: u64,
+    /// The fraction of bad lines sent to Sentry, from 0 to 1
+    pub sentry_sample_rate: f64,
+    /// Sent to Sentry at most, whatever the sample rate, so a broken drain can't use up the quota
+    pub sentry_max_per_minute: u32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            dead_letter: None,
+            dead_letter_max_bytes: 100 * 1024 * 1024,
+            sentry_sample_rate: 0.0,
+            sentry_max_per_minute: 10,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Target {
+    File(PathBuf),
+    Dir(PathBuf),
+}
+
+impl Target {
+    fn new(path: PathBuf) -> Self {
+        let trailing_separator = path
+            .as_os_str()
+            .to_string_lossy()
+            .ends_with(std::path::is_separator);
+        if trailing_separator || path.is_dir() {
+            Target::Dir(path)
+        } else {
+            Target::File(path)
+        }
+    }
+}
+
+/// Dead letters are written by a thread of their own, so a source sending nothing but garbage
+/// can't hold up the tasks extracting everything else. When it falls behind, they're dropped.
+#[derive(Debug)]
+pub struct ErrorSink {
+    options: Options,
+    dead_letters: Option<(SyncSender<Record>, JoinHandle<()>)>,
+    dropped: AtomicU64,
+    // When the current window started, and how many were sent in it
+    sentry: Mutex<(Instant, u32)>,
+}
+
+/// One line of a dead-letter file
+#[derive(Debug, Serialize)]
+struct Record {
+    time: DateTime<Utc>,
+    source: String,
+    line_number: u64,
+    kind: String,
+    error: String,
+    line: String,
+}
+
+// The open dead-letter files, and how big each has got
+struct Writer {
+    target: Target,
+    max_bytes: u64,
+    files: BTreeMap<PathBuf, OpenFile>,
+    writes: u64,
+}
+
+struct OpenFile {
+    file: File,
+    bytes: u64,
+    last_write: u64,
+}
+
+impl ErrorSink {
+    pub fn new(options: Options) -> Self {
+        let dead_letters = options.dead_letter.clone().map(|path| {
+            let target = Target::new(path);
+            if let Target::Dir(dir) = &target {
+                if let Err(e) = fs::create_dir_all(dir) {
+                    warn!("Problem creating {}: {}", dir.display(), e);
+                }
+            }
+            let writer = Writer {
+                target,
+                max_bytes: options.dead_letter_max_bytes,
+                files: BTreeMap::new(),
+                writes: 0,
+            };
+            let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+            let thread = thread::Builder::new()
+                .name("dead-letters".into())
+                .spawn(move || writer.run(rx))
+                .expect("failed to start the dead-letter thread");
+            (tx, thread)
+        });
+
+        Self {
+            options,
+            dead_letters,
+            dropped: AtomicU64::new(0),
+            sentry: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Records a line from `source`, such as a drain or a file, that gave an error
+    pub fn report(&self, source: &str, error: &LineError) {
+        if let Some((tx, _)) = &self.dead_letters {
+            let record = Record {
+                time: Utc::now(),
+                source: source.to_string(),
+                line_number: error.number,
+                kind: error.kind.name(),
+                error: error.kind.to_string(),
+                line: error.line.clone(),
+            };
+            if let Err(TrySendError::Full(_)) = tx.try_send(record) {
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped % REPORT_DROPPED_EVERY == 1 {
+                    warn!("Dead-letter writer is behind, {} dropped so far", dropped);
+                }
+            }
+        }
+
+        if self.admit(rand::random(), Instant::now()) {
+            sentry::with_scope(
+                |scope| {
+                    scope.set_tag("source", source);
+                    scope.set_tag("kind", error.kind.name());
+                    scope.set_extra("line", error.line.clone().into());
+                },
+                || sentry::capture_error(error),
+            );
+        }
+    }
+
+    /// Dead letters dropped because the writer couldn't keep up
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Whether to send an error to Sentry, given a `roll` between 0 and 1
+    fn admit(&self, roll: f64, now: Instant) -> bool {
+        if roll >= self.options.sentry_sample_rate {
+            return false;
+        }
+        let mut window = self.sentry.lock().unwrap();
+        if now.duration_since(window.0) >= SENTRY_WINDOW {
+            *window = (now, 0);
+        }
+        if window.1 >= self.options.sentry_max_per_minute {
+            return false;
+        }
+        window.1 += 1;
+        true
+    }
+}
+
+/// Waits for the dead letters already reported to be written
+impl Drop for ErrorSink {
+    fn drop(&mut self) {
+        if let Some((tx, thread)) = self.dead_letters.take() {
+            drop(tx);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Writer {
+    fn run(mut self, records: mpsc::Receiver<Record>) {
+        for record in records {
+            if let Err(e) = self.write(&record) {
+                warn!("Problem writing dead letter: {}", e);
+            }
+        }
+    }
+
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let path = match &self.target {
+            Target::File(path) => path.clone(),
+            Target::Dir(dir) => dir.join(format!("{}.jsonl", file_name(&record.source))),
+        };
+        let mut json = serde_json::to_string(record)?;
+        json.push('\n');
+
+        if !self.files.contains_key(&path) {
+            if self.files.len() >= MAX_OPEN_FILES {
+                self.close_least_recent();
+            }
+            let file = open(&path)?;
+            let bytes = file.metadata()?.len();
+            self.files.insert(
+                path.clone(),
+                OpenFile {
+                    file,
+                    bytes,
+                    last_write: 0,
+                },
+            );
+        }
+        let open_file = self.files.get_mut(&path).expect("opened above");
+        if open_file.bytes > 0 && open_file.bytes + json.len() as u64 > self.max_bytes {
+            let mut rotated = path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&path, rotated)?;
+            open_file.file = open(&path)?;
+            open_file.bytes = 0;
+        }
+
+        open_file.file.write_all(json.as_bytes())?;
+        open_file.bytes += json.len() as u64;
+        self.writes += 1;
+        open_file.last_write = self.writes;
+        Ok(())
+    }
+
+    fn close_least_recent(&mut self) {
+        let oldest = self
+            .files
+            .iter()
+            .min_by_key(|(_, open_file)| open_file.last_write)
+            .map(|(path, _)| path.clone());
+        if let Some(path) = oldest {
+            self.files.remove(&path);
+        }
+    }
+}
+
+/// The error counters for one source, or `None` if it had no errors
+pub fn to_metric(
+    source: &str,
+    errors: &BTreeMap<String, u64>,
+    timestamp: DateTime<Utc>,
+) -> Option<Metric> {
+    if errors.is_empty() {
+        return None;
+    }
+    let mut tags = Tags::new();
+    tags.insert("source".into(), source.into());
+
+    let mut fields = Fields::new();
+    for (kind, count) in errors {
+        fields.insert(kind.clone(), FieldValue::Integer(*count as i64, None));
+    }
+
+    Some(Metric {
+        timestamp,
+        name: ERRORS_METRIC.into(),
+        tags,
+        fields,
+    })
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// A drain token or path, made safe to use as a file name
+fn file_name(source: &str) -> String {
+    source
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() || c == '-' || c == '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
+
+    use chrono::Utc;
+
+    use super::{to_metric, ErrorSink, Options, Record, Target, Writer, MAX_OPEN_FILES};
+    use crate::{
+        error::{LineError, LineErrorKind},
+        parser::ParseError,
+    };
+
+    #[test]
+    fn test_it_writes_dead_letters_per_source() {
+        let dir = std::env::temp_dir().join(format!("logsnarf-dead-letter-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sink = ErrorSink::new(Options {
+            dead_letter: Some(dir.clone()),
+            ..Default::default()
+        });
+
+        let error = LineError {
+            number: 3,
+            line: "not syslog".into(),
+            kind: LineErrorKind::Parse(ParseError::Invalid("priority")),
+        };
+        sink.report("d.1234/5", &error);
+        sink.report("d.1234/5", &error);
+        sink.report("other", &error);
+        // Waits for the writer
+        drop(sink);
+
+        let written = std::fs::read_to_string(dir.join("d.1234_5.jsonl")).unwrap();
+        let records: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["source"], "d.1234/5");
+        assert_eq!(records[0]["line_number"], 3);
+        assert_eq!(records[0]["kind"], "parse.invalid");
+        assert_eq!(records[0]["line"], "not syslog");
+        assert!(dir.join("other.jsonl").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_a_trailing_separator_makes_a_new_directory() {
+        let dir = std::env::temp_dir().join(format!("logsnarf-dead-new-{}", std::process::id()));
+        let mut path = dir.clone().into_os_string();
+        path.push(std::path::MAIN_SEPARATOR_STR);
+        let sink = ErrorSink::new(Options {
+            dead_letter: Some(path.into()),
+            ..Default::default()
+        });
+        assert!(dir.is_dir());
+
+        let error = LineError {
+            number: 1,
+            line: "not syslog".into(),
+            kind: LineErrorKind::Parse(ParseError::Invalid("priority")),
+        };
+        sink.report("d.1", &error);
+        drop(sink);
+        assert!(dir.join("d.1.jsonl").exists());
+
+        assert!(matches!(
+            Target::new(dir.join("dead.jsonl")),
+            Target::File(_)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_it_rotates_big_files_and_closes_idle_ones() {
+        let dir = std::env::temp_dir().join(format!("logsnarf-dead-rotate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut writer = Writer {
+            target: Target::Dir(dir.clone()),
+            max_bytes: 400,
+            files: BTreeMap::new(),
+            writes: 0,
+        };
+        let record = |source: String| Record {
+            time: Utc::now(),
+            source,
+            line_number: 1,
+            kind: "parse.invalid".into(),
+            error: "invalid priority".into(),
+            line: "not syslog".into(),
+        };
+
+        for _ in 0..5 {
+            writer.write(&record("busy".into())).unwrap();
+        }
+        // Two records fit in 400 bytes, so the fifth starts a new file, and the first two are gone
+        let lines = |name: &str| {
+            std::fs::read_to_string(dir.join(name))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        assert_eq!(lines("busy.jsonl"), 1);
+        assert_eq!(lines("busy.jsonl.1"), 2);
+
+        for n in 0..MAX_OPEN_FILES + 10 {
+            writer.write(&record(format!("drain-{}", n))).unwrap();
+        }
+        assert_eq!(writer.files.len(), MAX_OPEN_FILES);
+        assert!(!writer.files.contains_key(&dir.join("busy.jsonl")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_it_samples_and_limits_sentry_reports() {
+        let sink = ErrorSink::new(Options {
+            sentry_sample_rate: 0.5,
+            sentry_max_per_minute: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        assert!(!sink.admit(0.7, now));
+        assert!(sink.admit(0.1, now));
+        assert!(sink.admit(0.1, now));
+        assert!(!sink.admit(0.1, now));
+        assert!(sink.admit(0.1, now + Duration::from_secs(61)));
+
+        let sink = ErrorSink::new(Options::default());
+        assert!(!sink.admit(0.0, now));
+    }
+
+    #[test]
+    fn test_it_counts_errors_as_a_metric() {
+        assert!(to_metric("drain", &BTreeMap::new(), Utc::now()).is_none());
+
+        let errors = BTreeMap::from([("parse.invalid".to_string(), 2), ("too_long".into(), 1)]);
+        let metric = to_metric("drain", &errors, Utc::now()).unwrap();
+        assert_eq!(metric.name, "logsnarf_line_errors");
+        assert_eq!(metric.tags["source"], "drain");
+        assert_eq!(metric.fields.len(), 2);
+    }
+}
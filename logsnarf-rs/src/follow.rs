@@ -0,0 +1,308 @@
+//! Following a file as it grows, like `tail -F`. When the file is rotated, by being renamed or
+//! removed and created again, or truncated in place, reading starts over at the beginning of the
+//! new contents.
+//!
+//! Only complete lines are handed out, so the offset of the last one is a safe place to resume
+//! from: a [`Checkpoint`].
+
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde_derive::{Deserialize, Serialize};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::app::MAX_LINE_LENGTH;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const READ_SIZE: usize = 64 * 1024;
+
+/// Where to pick up again. `id` tells apart a file that was rotated while we weren't looking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub id: u64,
+    pub offset: u64,
+}
+
+pub struct Follower {
+    path: PathBuf,
+    file: Option<File>,
+    id: u64,
+    // How far into the file has been read, and the part of that after the last newline
+    read: u64,
+    partial: Vec<u8>,
+    // Dropping the rest of a line that was too long
+    skipping: bool,
+}
+
+impl Checkpoint {
+    pub async fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read(path).await {
+            Ok(data) => Ok(serde_json::from_slice(&data).ok()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes a temporary file and renames it over `path`, so a crash can't leave half a checkpoint
+    pub async fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?).await?;
+        fs::rename(&tmp, path).await
+    }
+}
+
+impl Follower {
+    /// Starts at `checkpoint` if it's for the file that's there now. A checkpoint that doesn't
+    /// apply, because the file was rotated or truncated since, means everything in the new file is
+    /// unread, so it starts at the beginning. Without a checkpoint, it starts at the beginning with
+    /// `from_start`, or the end without it.
+    pub async fn open(
+        path: impl Into<PathBuf>,
+        checkpoint: Option<Checkpoint>,
+        from_start: bool,
+    ) -> io::Result<Self> {
+        let mut follower = Self {
+            path: path.into(),
+            file: None,
+            id: 0,
+            read: 0,
+            partial: Vec::new(),
+            skipping: false,
+        };
+        if !follower.reopen().await? {
+            return Ok(follower);
+        }
+
+        let len = follower.file_len().await?;
+        let start = match checkpoint {
+            Some(checkpoint) if checkpoint.id == follower.id && checkpoint.offset <= len => {
+                checkpoint.offset
+            }
+            Some(_) => 0,
+            None if from_start => 0,
+            None => len,
+        };
+        follower.seek(start).await?;
+        Ok(follower)
+    }
+
+    /// The end of the last complete line handed out
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            id: self.id,
+            offset: self.read - self.partial.len() as u64,
+        }
+    }
+
+    /// Waits a moment for one or more complete lines. Returns nothing if none showed up, so the
+    /// caller gets a chance to do other things, like flush.
+    pub async fn next_chunk(&mut self) -> io::Result<Vec<u8>> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                if !self.reopen().await? {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+                return Ok(Vec::new());
+            }
+        };
+
+        let mut buf = vec![0; READ_SIZE];
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            if !self.rotated_or_truncated().await? {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            return Ok(Vec::new());
+        }
+        self.read += n as u64;
+        Ok(self.take_lines(&buf[..n]))
+    }
+
+    fn take_lines(&mut self, mut data: &[u8]) -> Vec<u8> {
+        if self.skipping {
+            match data.iter().position(|b| *b == b'\n') {
+                Some(end) => {
+                    self.skipping = false;
+                    data = &data[end + 1..];
+                }
+                None => return Vec::new(),
+            }
+        }
+        self.partial.extend_from_slice(data);
+
+        match self.partial.iter().rposition(|b| *b == b'\n') {
+            Some(end) => {
+                let rest = self.partial.split_off(end + 1);
+                std::mem::replace(&mut self.partial, rest)
+            }
+            None => {
+                if self.partial.len() > MAX_LINE_LENGTH {
+                    self.partial.clear();
+                    self.skipping = true;
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    // Whatever is at `path` now, if anything. A partial line left in the old file is dropped,
+    // since whatever was writing it never finished.
+    async fn reopen(&mut self) -> io::Result<bool> {
+        let file = match File::open(&self.path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        self.id = file_id(&file.metadata().await?);
+        self.file = Some(file);
+        self.read = 0;
+        self.partial.clear();
+        self.skipping = false;
+        Ok(true)
+    }
+
+    async fn rotated_or_truncated(&mut self) -> io::Result<bool> {
+        let metadata = match fs::metadata(&self.path).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+
+        if file_id(&metadata) != self.id {
+            self.reopen().await
+        } else if metadata.len() < self.read {
+            self.seek(0).await?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    async fn file_len(&self) -> io::Result<u64> {
+        match &self.file {
+            Some(file) => Ok(file.metadata().await?.len()),
+            None => Ok(0),
+        }
+    }
+
+    async fn seek(&mut self, offset: u64) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            file.seek(SeekFrom::Start(offset)).await?;
+        }
+        self.read = offset;
+        self.partial.clear();
+        self.skipping = false;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+// Without inodes, only truncation is noticed
+#[cfg(not(unix))]
+fn file_id(_metadata: &std::fs::Metadata) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::fs::{self, OpenOptions};
+    use tokio::io::AsyncWriteExt;
+
+    use super::{Checkpoint, Follower};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("logsnarf-follow-{}-{}", std::process::id(), name))
+    }
+
+    async fn append(path: &PathBuf, data: &str) {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .unwrap();
+        file.write_all(data.as_bytes()).await.unwrap();
+    }
+
+    // Everything available now, waiting out the idle polls
+    async fn lines(follower: &mut Follower) -> String {
+        let mut lines = Vec::new();
+        for _ in 0..3 {
+            lines.extend(follower.next_chunk().await.unwrap());
+        }
+        String::from_utf8(lines).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_it_follows_complete_lines() {
+        let path = temp_path("lines");
+        append(&path, "old\n").await;
+
+        let mut follower = Follower::open(&path, None, false).await.unwrap();
+        append(&path, "one\ntw").await;
+        assert_eq!(lines(&mut follower).await, "one\n");
+        assert_eq!(follower.checkpoint().offset, 8);
+
+        append(&path, "o\n").await;
+        assert_eq!(lines(&mut follower).await, "two\n");
+
+        let resumed = Follower::open(&path, Some(follower.checkpoint()), false).await;
+        append(&path, "three\n").await;
+        assert_eq!(lines(&mut resumed.unwrap()).await, "three\n");
+
+        fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_it_starts_over_after_rotation_and_truncation() {
+        let path = temp_path("rotation");
+        let rotated = temp_path("rotation.1");
+        append(&path, "one\n").await;
+
+        let mut follower = Follower::open(&path, None, true).await.unwrap();
+        assert_eq!(lines(&mut follower).await, "one\n");
+
+        fs::rename(&path, &rotated).await.unwrap();
+        append(&rotated, "two\n").await;
+        append(&path, "three\n").await;
+        assert_eq!(lines(&mut follower).await, "two\nthree\n");
+
+        OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .await
+            .unwrap();
+        append(&path, "4\n").await;
+        assert_eq!(lines(&mut follower).await, "4\n");
+
+        // A checkpoint for the rotated file doesn't apply to the new one
+        let stale = Checkpoint {
+            id: follower.checkpoint().id + 1,
+            offset: 1,
+        };
+        let mut resumed = Follower::open(&path, Some(stale), false).await.unwrap();
+        assert_eq!(lines(&mut resumed).await, "4\n");
+
+        // Nor does one past the end of a truncated file
+        let past = Checkpoint {
+            id: follower.checkpoint().id,
+            offset: 100,
+        };
+        let mut resumed = Follower::open(&path, Some(past), false).await.unwrap();
+        assert_eq!(lines(&mut resumed).await, "4\n");
+
+        fs::remove_file(&path).await.unwrap();
+        fs::remove_file(&rotated).await.unwrap();
+    }
+}
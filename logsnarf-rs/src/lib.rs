@@ -6,6 +6,15 @@ pub mod metric;
 pub mod settings;
 
 pub mod app;
+pub mod compression;
 pub mod decoder;
+pub mod discover;
+pub mod error_sink;
+pub mod follow;
+pub mod loadgen;
+pub mod logplex;
 pub mod metric_writer;
 pub mod parser;
+pub mod pipeline;
+pub mod replay;
+pub mod timestamp;
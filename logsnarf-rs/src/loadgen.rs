@@ -0,0 +1,190 @@
+//! Synthetic Heroku drain traffic, to see how much a server can take. Each tenant is one app with
+//! its own drain token, sending the router, dyno and Postgres lines a real app would, in logplex
+//! frames.
+
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::logplex::Frame;
+
+const METHODS: [&str; 4] = ["GET", "GET", "GET", "POST"];
+const PATHS: [&str; 4] = [
+    "/",
+    "/api/orders",
+    "/api/orders/42",
+    "/admin/sidekiq_queue_stats",
+];
+const STATUSES: [u16; 6] = [200, 200, 200, 200, 302, 404];
+const PROCESS_TYPES: [&str; 2] = ["web", "worker"];
+
+/// One app draining its logs to the server
+#[derive(Debug)]
+pub struct Tenant {
+    pub drain_token: String,
+    host: String,
+    dynos: u32,
+    frames: u64,
+    rng: StdRng,
+}
+
+/// Latencies of every request, for percentiles
+#[derive(Debug, Default)]
+pub struct Latencies(Vec<Duration>);
+
+impl Tenant {
+    /// Tenants with the same `id` and `seed` send the same lines
+    pub fn new(id: usize, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed ^ id as u64);
+        Self {
+            drain_token: format!("d.{:08x}-0000-4000-8000-{:012x}", rng.gen::<u32>(), id),
+            host: format!("app-{}.example", id),
+            dynos: rng.gen_range(1..=4),
+            frames: 0,
+            rng,
+        }
+    }
+
+    /// A frame of `lines` lines, as Heroku would POST it, with its headers
+    pub fn frame(&mut self, lines: usize, now: DateTime<Utc>) -> (Frame, String) {
+        self.frames += 1;
+        let mut body = String::new();
+        for _ in 0..lines {
+            let line = self.line(now);
+            // Octet counted, like RFC 6587
+            body.push_str(&format!("{} {}\n", line.len(), line));
+        }
+
+        let frame = Frame {
+            msg_count: Some(lines as u64),
+            frame_id: Some(format!(
+                "{:016X}{:016X}",
+                self.rng.gen::<u64>(),
+                self.frames
+            )),
+            drain_token: Some(self.drain_token.clone()),
+        };
+        (frame, body)
+    }
+
+    /// Mostly router lines, like a busy web app, with the odd sample from a dyno or database
+    pub fn line(&mut self, now: DateTime<Utc>) -> String {
+        let timestamp = now.to_rfc3339_opts(SecondsFormat::Micros, false);
+        let dyno = format!(
+            "{}.{}",
+            PROCESS_TYPES[self.rng.gen_range(0..PROCESS_TYPES.len())],
+            self.rng.gen_range(1..=self.dynos)
+        );
+
+        match self.rng.gen_range(0..10) {
+            0 => format!(
+                "<45>1 {} host heroku {} - source={} sample#load_avg_1m={:.2} sample#load_avg_5m={:.2} sample#load_avg_15m={:.2}",
+                timestamp,
+                dyno,
+                dyno,
+                self.rng.gen_range(0.0..2.0),
+                self.rng.gen_range(0.0..1.5),
+                self.rng.gen_range(0.0..1.0),
+            ),
+            1 => format!(
+                "<45>1 {} host heroku {} - source={} sample#memory_total={:.2}MB sample#memory_rss={:.2}MB sample#memory_cache={:.2}MB sample#memory_swap=0.00MB sample#memory_quota=512.00MB",
+                timestamp,
+                dyno,
+                dyno,
+                self.rng.gen_range(200.0..512.0),
+                self.rng.gen_range(150.0..500.0),
+                self.rng.gen_range(0.0..20.0),
+            ),
+            2 => format!(
+                "<134>1 {} host app heroku-postgres - source=DATABASE addon=postgresql-{} sample#db_size={}bytes sample#tables=57 sample#active-connections={} sample#index-cache-hit-rate={:.5} sample#load-avg-1m={:.2}",
+                timestamp,
+                self.host,
+                self.rng.gen_range(100_000_000..200_000_000u64),
+                self.rng.gen_range(1..40),
+                self.rng.gen_range(0.95..1.0),
+                self.rng.gen_range(0.0..1.0),
+            ),
+            _ => format!(
+                "<158>1 {} host heroku router - at=info method={} path=\"{}\" host={} request_id={:032x} fwd=\"10.0.0.1\" dyno={} connect={}ms service={}ms status={} bytes={} protocol=https",
+                timestamp,
+                METHODS[self.rng.gen_range(0..METHODS.len())],
+                PATHS[self.rng.gen_range(0..PATHS.len())],
+                self.host,
+                self.rng.gen::<u128>(),
+                dyno,
+                self.rng.gen_range(0..3),
+                self.rng.gen_range(1..500),
+                STATUSES[self.rng.gen_range(0..STATUSES.len())],
+                self.rng.gen_range(100..20_000),
+            ),
+        }
+    }
+}
+
+impl Latencies {
+    pub fn record(&mut self, latency: Duration) {
+        self.0.push(latency);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The latency `p` percent of requests were at least as fast as, eg 99.0 for p99
+    pub fn percentile(&mut self, p: f64) -> Option<Duration> {
+        if self.0.is_empty() {
+            return None;
+        }
+        self.0.sort_unstable();
+        let rank = (p / 100.0 * self.0.len() as f64).ceil() as usize;
+        Some(self.0[rank.clamp(1, self.0.len()) - 1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use super::{Latencies, Tenant};
+    use crate::parser::parse_line;
+
+    #[test]
+    fn test_it_generates_frames_that_parse() {
+        let mut tenant = Tenant::new(1, 42);
+        let (frame, body) = tenant.frame(50, Utc::now());
+        assert_eq!(frame.msg_count, Some(50));
+        assert_eq!(frame.drain_token.as_ref(), Some(&tenant.drain_token));
+        assert_eq!(body.lines().count(), 50);
+        for line in body.lines() {
+            let ld = parse_line(line).unwrap().unwrap();
+            assert!(["heroku", "app"].contains(&ld.appname.as_ref()));
+        }
+
+        let (next, _) = tenant.frame(1, Utc::now());
+        assert_ne!(frame.frame_id, next.frame_id);
+        assert_ne!(Tenant::new(2, 42).drain_token, tenant.drain_token);
+    }
+
+    #[test]
+    fn test_it_finds_percentiles() {
+        let mut latencies = Latencies::default();
+        assert_eq!(latencies.percentile(50.0), None);
+        for ms in (1..=100).rev() {
+            latencies.record(Duration::from_millis(ms));
+        }
+        assert_eq!(latencies.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(latencies.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(
+            latencies.percentile(100.0),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(latencies.percentile(0.0), Some(Duration::from_millis(1)));
+    }
+}
@@ -0,0 +1,262 @@
+//! Heroku's HTTPS drains POST batches of lines, called frames, with headers that describe them:
+//!
+//! ```text
+//! Logplex-Msg-Count: 2
+//! Logplex-Frame-Id: 09C557EAFCFB6CF2740EE62F62971098
+//! Logplex-Drain-Token: d.fc6b856b-3332-4546-93de-7d0ee272c3bd
+//! ```
+//!
+//! Heroku retries a frame if the response is too slow, and never retries one that's lost, so the
+//! frame id and message count are how we tell what was counted twice and what never arrived.
+
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use http::HeaderMap;
+
+use crate::metric::{FieldValue, Fields, Metric, Tags};
+
+pub const MSG_COUNT: &str = "logplex-msg-count";
+pub const FRAME_ID: &str = "logplex-frame-id";
+pub const DRAIN_TOKEN: &str = "logplex-drain-token";
+
+const LOSS_METRIC: &str = "logsnarf_drain";
+
+/// What the headers say about a frame. Anything other than logplex leaves them all out.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub msg_count: Option<u64>,
+    pub frame_id: Option<String>,
+    pub drain_token: Option<String>,
+}
+
+/// Remembers frame ids for `window`, so a retried frame is only extracted once
+#[derive(Debug)]
+pub struct Dedupe {
+    window: Duration,
+    seen: HashSet<String>,
+    order: VecDeque<(Instant, String)>,
+    // Claimed, but neither recorded nor released yet
+    in_flight: HashSet<String>,
+}
+
+/// Per-drain tallies of frames and messages, to spot the data Heroku never sent or we never got
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LossStats {
+    pub frames: u64,
+    pub duplicate_frames: u64,
+    pub mismatched_frames: u64,
+    /// Only frames with a `Logplex-Msg-Count` count towards the messages
+    pub messages_expected: u64,
+    pub messages_received: u64,
+}
+
+impl Frame {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+
+        Self {
+            msg_count: header(MSG_COUNT).and_then(|count| count.parse().ok()),
+            frame_id: header(FRAME_ID).map(String::from),
+            drain_token: header(DRAIN_TOKEN).map(String::from),
+        }
+    }
+}
+
+impl Dedupe {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            in_flight: HashSet::new(),
+        }
+    }
+
+    /// Whether `frame_id` was recorded within the window before `now`, or is still in flight
+    pub fn is_duplicate(&mut self, frame_id: &str, now: Instant) -> bool {
+        self.expire(now);
+        self.seen.contains(frame_id) || self.in_flight.contains(frame_id)
+    }
+
+    /// Claims `frame_id` for extracting, unless it's a duplicate. A claimed frame stays in flight,
+    /// and any other delivery of it a duplicate, until it's recorded or released.
+    pub fn claim(&mut self, frame_id: &str, now: Instant) -> bool {
+        !self.is_duplicate(frame_id, now) && self.in_flight.insert(frame_id.to_string())
+    }
+
+    /// Gives up a claim on `frame_id` without recording it, so that Heroku's retry of a frame
+    /// that failed still gets extracted
+    pub fn release(&mut self, frame_id: &str) {
+        self.in_flight.remove(frame_id);
+    }
+
+    /// Remembers `frame_id` from `now`. Only frames whose metrics were kept should be recorded.
+    pub fn record(&mut self, frame_id: &str, now: Instant) {
+        self.expire(now);
+        self.in_flight.remove(frame_id);
+        if self.seen.insert(frame_id.to_string()) {
+            self.order.push_back((now, frame_id.to_string()));
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((at, _)) = self.order.front() {
+            if now.saturating_duration_since(*at) < self.window {
+                break;
+            }
+            if let Some((_, id)) = self.order.pop_front() {
+                self.seen.remove(&id);
+            }
+        }
+    }
+}
+
+impl LossStats {
+    /// Counts a frame that had `lines` lines in it, and returns whether that's what the headers
+    /// said it would have
+    pub fn record(&mut self, frame: &Frame, lines: u64) -> bool {
+        self.frames += 1;
+
+        match frame.msg_count {
+            Some(expected) => {
+                self.messages_expected += expected;
+                self.messages_received += lines;
+                if expected != lines {
+                    self.mismatched_frames += 1;
+                    return false;
+                }
+                true
+            }
+            None => true,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == LossStats::default()
+    }
+
+    /// Messages the headers promised that never showed up
+    pub fn lost(&self) -> u64 {
+        self.messages_expected
+            .saturating_sub(self.messages_received)
+    }
+
+    pub fn to_metric(&self, drain: &str, timestamp: DateTime<Utc>) -> Metric {
+        let mut tags = Tags::new();
+        tags.insert("drain".into(), drain.into());
+
+        let mut fields = Fields::new();
+        for (name, value) in [
+            ("frames", self.frames),
+            ("duplicate_frames", self.duplicate_frames),
+            ("mismatched_frames", self.mismatched_frames),
+            ("messages_expected", self.messages_expected),
+            ("messages_received", self.messages_received),
+            ("messages_lost", self.lost()),
+        ] {
+            fields.insert(name.into(), FieldValue::Integer(value as i64, None));
+        }
+
+        Metric {
+            timestamp,
+            name: LOSS_METRIC.into(),
+            tags,
+            fields,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use chrono::Utc;
+    use http::HeaderMap;
+
+    use super::{Dedupe, Frame, LossStats};
+    use crate::metric::FieldValue;
+
+    #[test]
+    fn test_it_reads_the_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Logplex-Msg-Count", "2".parse().unwrap());
+        headers.insert(
+            "Logplex-Frame-Id",
+            "09C557EAFCFB6CF2740EE62F62971098".parse().unwrap(),
+        );
+        headers.insert(
+            "Logplex-Drain-Token",
+            "d.fc6b856b-3332-4546-93de-7d0ee272c3bd".parse().unwrap(),
+        );
+
+        let frame = Frame::from_headers(&headers);
+        assert_eq!(frame.msg_count, Some(2));
+        assert_eq!(
+            frame.frame_id.as_deref(),
+            Some("09C557EAFCFB6CF2740EE62F62971098")
+        );
+        assert_eq!(
+            frame.drain_token.as_deref(),
+            Some("d.fc6b856b-3332-4546-93de-7d0ee272c3bd")
+        );
+
+        assert_eq!(Frame::from_headers(&HeaderMap::new()), Frame::default());
+    }
+
+    #[test]
+    fn test_it_drops_retried_frames_within_the_window() {
+        let start = Instant::now();
+        let mut dedupe = Dedupe::new(Duration::from_secs(60));
+
+        dedupe.record("a", start);
+        dedupe.record("b", start + Duration::from_secs(10));
+        assert!(dedupe.is_duplicate("a", start + Duration::from_secs(30)));
+        assert!(!dedupe.is_duplicate("c", start + Duration::from_secs(30)));
+        assert!(!dedupe.is_duplicate("a", start + Duration::from_secs(61)));
+        assert!(dedupe.is_duplicate("b", start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_a_claimed_frame_is_a_duplicate_until_released() {
+        let now = Instant::now();
+        let mut dedupe = Dedupe::new(Duration::from_secs(60));
+
+        assert!(dedupe.claim("a", now));
+        assert!(!dedupe.claim("a", now));
+        dedupe.release("a");
+        assert!(dedupe.claim("a", now));
+        dedupe.record("a", now);
+        assert!(!dedupe.claim("a", now));
+        assert!(!dedupe.claim("a", now + Duration::from_secs(30)));
+        assert!(dedupe.claim("a", now + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn test_it_tracks_lost_messages() {
+        let frame = Frame {
+            msg_count: Some(3),
+            ..Default::default()
+        };
+        let mut stats = LossStats::default();
+        assert!(stats.record(&frame, 3));
+        assert!(!stats.record(&frame, 1));
+        assert!(stats.record(&Frame::default(), 5));
+
+        assert_eq!(stats.frames, 3);
+        assert_eq!(stats.mismatched_frames, 1);
+        assert_eq!(stats.messages_received, 4);
+        assert_eq!(stats.lost(), 2);
+
+        let metric = stats.to_metric("d.1", Utc::now());
+        assert_eq!(metric.tags["drain"], "d.1");
+        assert_eq!(metric.fields["messages_lost"], FieldValue::Integer(2, None));
+    }
+}
@@ -4,6 +4,8 @@ use std::fmt::{Display, Formatter};
 use std::str::FromStr;
 
 use chrono::{DateTime, Utc};
+use serde::Serializer;
+use serde_derive::Serialize;
 
 use crate::parser::KVPairs;
 
@@ -12,7 +14,7 @@ pub type TagKey = String;
 pub type TagValue = String;
 pub type FieldKey = String;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
 pub enum FieldValue {
     Boolean(bool),
     Float(f64, Option<String>),
@@ -23,7 +25,7 @@ pub enum FieldValue {
 pub type Tags = BTreeMap<TagKey, TagValue>;
 pub type Fields = BTreeMap<FieldKey, FieldValue>;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize)]
 pub struct Metric {
     pub timestamp: DateTime<Utc>,
     pub name: String,
@@ -59,13 +61,15 @@ impl Metric {
 }
 
 fn to_tags(t: KVPairs) -> Tags {
-    t.clone()
+    t.into_iter()
+        .map(|(k, v)| (k.to_string(), v.into_owned()))
+        .collect()
 }
 fn to_fields(f: KVPairs) -> Fields {
     let mut fields = Fields::new();
     for (k, v) in f {
         let key = k.replace("sample#", "");
-        let val = extract_unit(v);
+        let val = extract_unit(&v);
         fields.insert(key, val);
     }
     fields
@@ -84,6 +88,20 @@ impl FromStr for FieldValue {
     }
 }
 
+// Just the value, like in line protocol
+impl serde::Serialize for FieldValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use FieldValue::*;
+
+        match self {
+            Boolean(x) => serializer.serialize_bool(*x),
+            Float(v, _) => serializer.serialize_f64(*v),
+            Integer(v, _) => serializer.serialize_i64(*v),
+            Text(text) => serializer.serialize_str(text),
+        }
+    }
+}
+
 impl Display for FieldValue {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         use FieldValue::*;
@@ -97,7 +115,7 @@ impl Display for FieldValue {
     }
 }
 
-fn extract_unit(val: String) -> FieldValue {
+fn extract_unit(val: &str) -> FieldValue {
     val.parse::<FieldValue>().unwrap()
 }
 
@@ -0,0 +1,176 @@
+//! Lets many extractions share one writer. Each gets a `Sender`, and a single task owns the real
+//! writer, flushing it on an interval so a stream that never ends still gets written.
+//!
+//! The channel is bounded, so extractions that get ahead of the TSDB wait for it in `ready`
+//! instead of buffering without limit.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{
+    metric::Metric,
+    metric_writer::{MetricWriter, WriterError},
+};
+
+/// Metrics that can be waiting for the writer before senders have to wait too
+const CAPACITY: usize = 10_000;
+
+pub struct Sender {
+    tx: mpsc::Sender<Metric>,
+    // Written while the channel was full, and sent by `ready`
+    pending: VecDeque<Metric>,
+    closed: bool,
+}
+
+pub struct Receiver(mpsc::Receiver<Metric>);
+
+pub fn channel() -> (Sender, Receiver) {
+    with_capacity(CAPACITY)
+}
+
+fn with_capacity(capacity: usize) -> (Sender, Receiver) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let sender = Sender {
+        tx,
+        pending: VecDeque::new(),
+        closed: false,
+    };
+    (sender, Receiver(rx))
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            pending: VecDeque::new(),
+            closed: self.closed,
+        }
+    }
+}
+
+/// Flushing is left to the `Receiver`. Anything written while the channel is full is only sent by
+/// `ready` or `flush`, so one of them has to be awaited before the `Sender` is dropped.
+#[async_trait]
+impl MetricWriter for Sender {
+    fn write(&mut self, metric: Metric) {
+        if self.closed {
+            return;
+        }
+        if !self.pending.is_empty() {
+            return self.pending.push_back(metric);
+        }
+        match self.tx.try_send(metric) {
+            Ok(()) => {}
+            Err(TrySendError::Full(metric)) => self.pending.push_back(metric),
+            // The receiver is gone, so there's nowhere left to write
+            Err(TrySendError::Closed(_)) => self.closed = true,
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), WriterError> {
+        self.ready().await
+    }
+
+    async fn ready(&mut self) -> Result<(), WriterError> {
+        while let Some(metric) = self.pending.pop_front() {
+            if self.tx.send(metric).await.is_err() {
+                self.closed = true;
+                self.pending.clear();
+            }
+        }
+        if self.closed {
+            return Err(WriterError::Closed);
+        }
+        Ok(())
+    }
+}
+
+impl Receiver {
+    /// The next metric, or `None` once every `Sender` is dropped, or after `close` once everything
+    /// already sent has been received
+    pub async fn recv(&mut self) -> Option<Metric> {
+        self.0.recv().await
+    }
+
+    /// Stops taking more, eg at shutdown while senders are still around
+    pub fn close(&mut self) {
+        self.0.close()
+    }
+
+    /// Writes everything sent to `writer`, flushing every `interval`, until every `Sender` is
+    /// dropped. Then `writer` is closed.
+    pub async fn write_to(
+        mut self,
+        writer: &mut (impl MetricWriter + Send),
+        interval: Duration,
+    ) -> Result<(), WriterError> {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+
+        loop {
+            tokio::select! {
+                metric = self.recv() => match metric {
+                    Some(metric) => writer.write(metric),
+                    None => break,
+                },
+                _ = ticks.tick() => writer.flush().await?,
+            }
+        }
+
+        writer.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{channel, with_capacity};
+    use crate::metric::Metric;
+    use crate::metric_writer::{MetricWriter, WriterError};
+
+    #[tokio::test]
+    async fn test_it_writes_from_every_sender() {
+        let (tx, rx) = channel();
+        let mut senders = vec![tx.clone(), tx];
+        for sender in &mut senders {
+            sender.write(Metric::default());
+        }
+        drop(senders);
+
+        let mut written: Vec<Metric> = Vec::new();
+        rx.write_to(&mut written, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(written.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_it_waits_for_the_writer_and_stops_when_it_fails() {
+        let (mut tx, rx) = with_capacity(1);
+        for _ in 0..3 {
+            tx.write(Metric::default());
+        }
+        assert_eq!(tx.pending.len(), 2);
+
+        let writing = tokio::spawn(async move {
+            let mut written: Vec<Metric> = Vec::new();
+            rx.write_to(&mut written, Duration::from_secs(10))
+                .await
+                .unwrap();
+            written.len()
+        });
+        tx.ready().await.unwrap();
+        assert!(tx.pending.is_empty());
+        drop(tx);
+        assert_eq!(writing.await.unwrap(), 3);
+
+        let (mut tx, rx) = with_capacity(1);
+        drop(rx);
+        tx.write(Metric::default());
+        assert!(matches!(tx.ready().await, Err(WriterError::Closed)));
+    }
+}
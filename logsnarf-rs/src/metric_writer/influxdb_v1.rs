@@ -27,6 +27,7 @@ pub struct Credentials {
 }
 
 pub struct InfluxdbV1 {
+    #[allow(unused)]
     credentials: Credentials,
     metrics: Vec<Metric>,
     client: reqwest::Client,
@@ -82,7 +83,7 @@ impl MetricWriter for InfluxdbV1 {
             .body(body)
             .send()
             .await
-            .map_err(|e| InfluxdbV1Error::HttpError(e))?;
+            .map_err(InfluxdbV1Error::HttpError)?;
 
         tracing::Span::current().record("count", count);
         tracing::Span::current().record("response", response.status().as_str());
@@ -243,5 +244,5 @@ where
         last = idx + delim.len();
     }
 
-    w.write_all(value[last..].as_bytes())
+    w.write_all(&value.as_bytes()[last..])
 }
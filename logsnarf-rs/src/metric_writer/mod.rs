@@ -4,17 +4,24 @@ use thiserror::Error;
 
 use crate::{metric::Metric, settings::TsdbCredentials};
 
+pub mod channel;
 pub mod influxdb_v1;
+pub mod print;
+pub mod rollup;
 
 #[derive(Debug, Error)]
 pub enum WriterError {
     #[error(transparent)]
     InfluxdbV1Error(#[from] influxdb_v1::InfluxdbV1Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("the writer has stopped")]
+    Closed,
 }
 
 pub fn build(creds: &TsdbCredentials) -> impl MetricWriter {
     match creds {
-        TsdbCredentials::InfluxdbV1(creds) => influxdb_v1::InfluxdbV1::new(&creds),
+        TsdbCredentials::InfluxdbV1(creds) => influxdb_v1::InfluxdbV1::new(creds),
     }
 }
 
@@ -23,4 +30,27 @@ pub trait MetricWriter {
     fn write(&mut self, metric: Metric);
 
     async fn flush(&mut self) -> Result<(), WriterError>;
+
+    /// Waits until the writer can take more, for writers that hand metrics on to something that
+    /// can fall behind
+    async fn ready(&mut self) -> Result<(), WriterError> {
+        Ok(())
+    }
+
+    /// Flush everything, including any points being held back for aggregation
+    async fn close(&mut self) -> Result<(), WriterError> {
+        self.flush().await
+    }
+}
+
+/// Collects metrics in memory, eg to write them somewhere else all at once
+#[async_trait]
+impl MetricWriter for Vec<Metric> {
+    fn write(&mut self, metric: Metric) {
+        self.push(metric)
+    }
+
+    async fn flush(&mut self) -> Result<(), WriterError> {
+        Ok(())
+    }
 }
@@ -0,0 +1,185 @@
+//! Prints metrics instead of sending them anywhere, to see what a config would write
+
+use std::io;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+
+use crate::{
+    metric::{FieldValue, Metric},
+    metric_writer::{influxdb_v1::WriteDataPoint, MetricWriter, WriterError},
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// What would be sent to InfluxDB
+    #[default]
+    LineProtocol,
+    /// One JSON object per metric
+    Json,
+    /// Aligned columns, for reading
+    Table,
+}
+
+pub struct Print<O> {
+    format: Format,
+    out: O,
+    metrics: Vec<Metric>,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line-protocol" => Ok(Format::LineProtocol),
+            "json" => Ok(Format::Json),
+            "table" => Ok(Format::Table),
+            _ => Err(format!("unknown format {}", s)),
+        }
+    }
+}
+
+impl<O: io::Write> Print<O> {
+    pub fn new(format: Format, out: O) -> Self {
+        Self {
+            format,
+            out,
+            metrics: Vec::new(),
+        }
+    }
+
+    fn print(&mut self) -> io::Result<()> {
+        match self.format {
+            Format::LineProtocol => {
+                for metric in &self.metrics {
+                    metric.write_data_point_to(&mut self.out)?;
+                }
+            }
+            Format::Json => {
+                for metric in &self.metrics {
+                    serde_json::to_writer(&mut self.out, metric)?;
+                    self.out.write_all(b"\n")?;
+                }
+            }
+            Format::Table => self.print_table()?,
+        }
+        self.out.flush()
+    }
+
+    fn print_table(&mut self) -> io::Result<()> {
+        let rows: Vec<[String; 4]> = self
+            .metrics
+            .iter()
+            .map(|metric| {
+                let tags = metric.tags.iter().map(|(k, v)| format!("{}={}", k, v));
+                let fields = metric
+                    .fields
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, field_text(v)));
+                [
+                    metric.timestamp.to_rfc3339(),
+                    metric.name.clone(),
+                    tags.collect::<Vec<_>>().join(","),
+                    fields.collect::<Vec<_>>().join(","),
+                ]
+            })
+            .collect();
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let header = ["TIMESTAMP", "NAME", "TAGS", "FIELDS"].map(String::from);
+        let mut widths = [0; 4];
+        for row in std::iter::once(&header).chain(&rows) {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        for row in std::iter::once(&header).chain(&rows) {
+            let line = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+            writeln!(self.out, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+fn field_text(value: &FieldValue) -> String {
+    match value {
+        FieldValue::Float(v, Some(unit)) => format!("{}{}", v, unit),
+        FieldValue::Integer(v, Some(unit)) => format!("{}{}", v, unit),
+        FieldValue::Float(v, None) => v.to_string(),
+        FieldValue::Integer(v, None) => v.to_string(),
+        FieldValue::Boolean(v) => v.to_string(),
+        FieldValue::Text(text) => format!("{:?}", text),
+    }
+}
+
+/// Everything written is held until `flush`, so a table can be lined up
+#[async_trait]
+impl<O: io::Write + Send> MetricWriter for Print<O> {
+    fn write(&mut self, metric: Metric) {
+        self.metrics.push(metric);
+    }
+
+    async fn flush(&mut self) -> Result<(), WriterError> {
+        self.print()?;
+        self.metrics.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::{Format, Print};
+    use crate::metric::{FieldValue, Fields, Metric, Tags};
+    use crate::metric_writer::MetricWriter;
+
+    fn metric() -> Metric {
+        let mut tags = Tags::new();
+        tags.insert("dyno".into(), "web.1".into());
+        let mut fields = Fields::new();
+        fields.insert("connect".into(), FieldValue::Integer(1, Some("ms".into())));
+        fields.insert("service".into(), FieldValue::Float(2.5, None));
+
+        Metric {
+            timestamp: Utc.with_ymd_and_hms(2019, 11, 25, 18, 28, 0).unwrap(),
+            name: "heroku_router".into(),
+            tags,
+            fields,
+        }
+    }
+
+    async fn print(format: Format) -> String {
+        let mut out = Vec::new();
+        let mut writer = Print::new(format, &mut out);
+        writer.write(metric());
+        writer.flush().await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_it_prints_each_format() {
+        assert_eq!(
+            print(Format::LineProtocol).await,
+            "heroku_router,dyno=web.1 connect=1i,service=2.5 1574706480000000\n"
+        );
+        assert_eq!(
+            print(Format::Json).await,
+            r#"{"timestamp":"2019-11-25T18:28:00Z","name":"heroku_router","tags":{"dyno":"web.1"},"fields":{"connect":1,"service":2.5}}"#.to_string() + "\n"
+        );
+        assert_eq!(
+            print(Format::Table).await,
+            "TIMESTAMP                  NAME           TAGS        FIELDS\n\
+             2019-11-25T18:28:00+00:00  heroku_router  dyno=web.1  connect=1ms,service=2.5\n"
+        );
+    }
+}
@@ -0,0 +1,577 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use config::ConfigError;
+use tracing::{instrument, warn};
+
+use crate::{
+    metric::{FieldKey, FieldValue, Fields, Metric, Name, Tags},
+    metric_writer::{MetricWriter, WriterError},
+    settings::{self, Aggregation},
+};
+
+/// Downsamples the configured metrics into a single point per series (name + tags) per window,
+/// and passes every other metric straight through to the inner writer.
+///
+/// Each series has its own watermark, the newest timestamp seen for it, so a source whose clock
+/// runs ahead can't close the windows of the others. Once the watermark passes the end of a window
+/// plus that rule's `lateness`, the window is written to the inner writer. A series that stops
+/// sending has its open windows written on the first flush after `window + lateness` of wall-clock
+/// time without a point, and is forgotten after as long again. Points that arrive for a window that
+/// has already been written are dropped.
+pub struct Rollup<W> {
+    inner: W,
+    rules: BTreeMap<Name, settings::Rollup>,
+    series: BTreeMap<SeriesKey, Series>,
+    dropped: u64,
+    reported: u64,
+}
+
+type SeriesKey = (Name, Tags);
+
+struct Series {
+    // Every window closing at or before this has been written
+    watermark: DateTime<Utc>,
+    last_seen: Instant,
+    // By window start
+    windows: BTreeMap<DateTime<Utc>, Window>,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    fields: BTreeMap<FieldKey, State>,
+}
+
+#[derive(Debug)]
+enum State {
+    Last(DateTime<Utc>, FieldValue),
+    // The mean of integers stays an integer, so the field keeps its type in the TSDB
+    Avg {
+        sum: f64,
+        count: u64,
+        integer: bool,
+        unit: Option<String>,
+    },
+    Max(f64, FieldValue),
+}
+
+impl<W> Rollup<W> {
+    /// Fails if a rule's window is 0 seconds
+    pub fn new(rules: &settings::Rollups, inner: W) -> Result<Self, ConfigError> {
+        if let Some(rule) = rules.iter().find(|rule| rule.window == 0) {
+            return Err(ConfigError::Message(format!(
+                "rollups `{}`: window must be at least 1 second",
+                rule.name
+            )));
+        }
+        let rules = rules
+            .iter()
+            .map(|rule| (rule.name.clone(), rule.clone()))
+            .collect();
+
+        Ok(Self {
+            inner,
+            rules,
+            series: BTreeMap::new(),
+            dropped: 0,
+            reported: 0,
+        })
+    }
+
+    pub fn inner(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Number of points dropped because their window had already been written
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn open_windows(&self) -> usize {
+        self.series
+            .values()
+            .map(|series| series.windows.len())
+            .sum()
+    }
+}
+
+impl<W: MetricWriter> Rollup<W> {
+    fn aggregate(&mut self, metric: Metric, now: Instant) {
+        let rule = match self.rules.get(&metric.name) {
+            Some(rule) => rule,
+            None => return self.inner.write(metric),
+        };
+
+        let window = rule.window as i64;
+        let start_secs = metric.timestamp.timestamp().div_euclid(window) * window;
+        let start = DateTime::from_timestamp(start_secs, 0).expect("timestamp out of range");
+
+        let key = (metric.name, metric.tags);
+        if let Some(series) = self.series.get(&key) {
+            if closes_at(rule, start) <= series.watermark {
+                self.dropped += 1;
+                tracing::debug!(
+                    "Dropping late point for {} at {}, window already closed",
+                    key.0,
+                    metric.timestamp
+                );
+                return;
+            }
+        }
+
+        let series = self.series.entry(key.clone()).or_insert_with(|| Series {
+            watermark: DateTime::<Utc>::MIN_UTC,
+            last_seen: now,
+            windows: BTreeMap::new(),
+        });
+        series.last_seen = now;
+        series.watermark = series.watermark.max(metric.timestamp);
+
+        let entry = series.windows.entry(start).or_default();
+        for (field, value) in metric.fields {
+            let aggregation = rule.fields.get(&field).copied().unwrap_or(rule.default);
+            match entry.fields.get_mut(&field) {
+                Some(state) => state.update(metric.timestamp, value),
+                None => {
+                    let state = State::new(aggregation, metric.timestamp, value);
+                    entry.fields.insert(field, state);
+                }
+            }
+        }
+
+        while let Some(window) = series.windows.first_entry() {
+            if closes_at(rule, *window.key()) > series.watermark {
+                break;
+            }
+            let (start, window) = window.remove_entry();
+            self.inner.write(window.into_metric(&key, start));
+        }
+    }
+
+    /// Writes the windows of series that haven't had a point for `window + lateness`, and forgets
+    /// series that have been quiet for twice that
+    fn close_idle(&mut self, now: Instant) {
+        let rules = &self.rules;
+        let inner = &mut self.inner;
+
+        self.series.retain(|key, series| {
+            let rule = &rules[&key.0];
+            let timeout = std::time::Duration::from_secs(rule.window + rule.lateness);
+            let idle = now.saturating_duration_since(series.last_seen);
+            if idle < timeout {
+                return true;
+            }
+
+            for (start, window) in std::mem::take(&mut series.windows) {
+                series.watermark = series.watermark.max(closes_at(rule, start));
+                inner.write(window.into_metric(key, start));
+            }
+            idle < timeout * 2
+        });
+    }
+}
+
+fn closes_at(rule: &settings::Rollup, start: DateTime<Utc>) -> DateTime<Utc> {
+    start + Duration::seconds(rule.window as i64) + Duration::seconds(rule.lateness as i64)
+}
+
+#[async_trait]
+impl<W: MetricWriter + Send> MetricWriter for Rollup<W> {
+    #[instrument(skip(self))]
+    fn write(&mut self, metric: Metric) {
+        self.aggregate(metric, Instant::now())
+    }
+
+    #[instrument(skip(self), fields(windows, dropped))]
+    async fn flush(&mut self) -> Result<(), WriterError> {
+        self.close_idle(Instant::now());
+
+        tracing::Span::current().record("windows", self.open_windows());
+        tracing::Span::current().record("dropped", self.dropped);
+        if self.dropped > self.reported {
+            warn!(
+                "Dropped {} late points since the last flush",
+                self.dropped - self.reported
+            );
+            self.reported = self.dropped;
+        }
+
+        self.inner.flush().await
+    }
+
+    async fn ready(&mut self) -> Result<(), WriterError> {
+        self.inner.ready().await
+    }
+
+    #[instrument(skip(self), fields(windows, dropped))]
+    async fn close(&mut self) -> Result<(), WriterError> {
+        tracing::Span::current().record("windows", self.open_windows());
+        tracing::Span::current().record("dropped", self.dropped);
+
+        for (key, series) in std::mem::take(&mut self.series) {
+            for (start, window) in series.windows {
+                self.inner.write(window.into_metric(&key, start));
+            }
+        }
+        self.inner.close().await
+    }
+}
+
+impl Window {
+    fn into_metric(self, (name, tags): &SeriesKey, start: DateTime<Utc>) -> Metric {
+        let fields: Fields = self
+            .fields
+            .into_iter()
+            .map(|(field, state)| (field, state.into_value()))
+            .collect();
+
+        Metric {
+            timestamp: start,
+            name: name.clone(),
+            tags: tags.clone(),
+            fields,
+        }
+    }
+}
+
+impl State {
+    fn new(aggregation: Aggregation, at: DateTime<Utc>, value: FieldValue) -> Self {
+        match (aggregation, numeric(&value)) {
+            (Aggregation::Avg, Some(v)) => State::Avg {
+                sum: v,
+                count: 1,
+                integer: matches!(value, FieldValue::Integer(..)),
+                unit: unit(&value),
+            },
+            (Aggregation::Max, Some(v)) => State::Max(v, value),
+            // Text and boolean values can only ever be `last`
+            _ => State::Last(at, value),
+        }
+    }
+
+    fn update(&mut self, at: DateTime<Utc>, value: FieldValue) {
+        match self {
+            // Ties go to the greater value, so the result doesn't depend on arrival order
+            State::Last(last_at, last) => {
+                if (at, &value).partial_cmp(&(*last_at, &*last)) == Some(Ordering::Greater) {
+                    *last_at = at;
+                    *last = value;
+                }
+            }
+            State::Avg {
+                sum,
+                count,
+                integer,
+                ..
+            } => {
+                if let Some(v) = numeric(&value) {
+                    *sum += v;
+                    *count += 1;
+                    *integer &= matches!(value, FieldValue::Integer(..));
+                }
+            }
+            State::Max(max, max_value) => {
+                if let Some(v) = numeric(&value) {
+                    if v > *max
+                        || (v == *max && value.partial_cmp(max_value) == Some(Ordering::Greater))
+                    {
+                        *max = v;
+                        *max_value = value;
+                    }
+                }
+            }
+        }
+    }
+
+    fn into_value(self) -> FieldValue {
+        match self {
+            State::Last(_, value) => value,
+            State::Avg {
+                sum,
+                count,
+                integer: true,
+                unit,
+            } => FieldValue::Integer((sum / count as f64).round() as i64, unit),
+            State::Avg {
+                sum, count, unit, ..
+            } => FieldValue::Float(sum / count as f64, unit),
+            State::Max(_, value) => value,
+        }
+    }
+}
+
+fn numeric(value: &FieldValue) -> Option<f64> {
+    match value {
+        FieldValue::Float(v, _) => Some(*v),
+        FieldValue::Integer(v, _) => Some(*v as f64),
+        _ => None,
+    }
+}
+
+fn unit(value: &FieldValue) -> Option<String> {
+    match value {
+        FieldValue::Float(_, u) | FieldValue::Integer(_, u) => u.clone(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, Instant};
+
+    use async_trait::async_trait;
+    use chrono::{DateTime, TimeZone, Utc};
+
+    use super::Rollup;
+    use crate::{
+        metric::{FieldValue, Fields, Metric, Tags},
+        metric_writer::{MetricWriter, WriterError},
+        settings::{self, Aggregation},
+    };
+
+    #[derive(Default)]
+    struct Capture(Vec<Metric>);
+
+    #[async_trait]
+    impl MetricWriter for Capture {
+        fn write(&mut self, metric: Metric) {
+            self.0.push(metric)
+        }
+
+        async fn flush(&mut self) -> Result<(), WriterError> {
+            Ok(())
+        }
+    }
+
+    fn rules() -> settings::Rollups {
+        let mut fields = BTreeMap::new();
+        fields.insert("load_avg_1m".to_string(), Aggregation::Avg);
+        fields.insert("load_avg_5m".to_string(), Aggregation::Max);
+
+        vec![settings::Rollup {
+            name: "heroku_dyno_load".into(),
+            window: 300,
+            lateness: 0,
+            default: Aggregation::Last,
+            fields,
+        }]
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_574_706_000 + secs, 0).unwrap()
+    }
+
+    fn load(secs: i64, source: &str, avg: f64, max: f64, last: f64) -> Metric {
+        let mut tags = Tags::new();
+        tags.insert("source".into(), source.into());
+        let mut fields = Fields::new();
+        fields.insert("load_avg_1m".into(), FieldValue::Float(avg, None));
+        fields.insert("load_avg_5m".into(), FieldValue::Float(max, None));
+        fields.insert("load_avg_15m".into(), FieldValue::Float(last, None));
+
+        Metric {
+            timestamp: at(secs),
+            name: "heroku_dyno_load".into(),
+            tags,
+            fields,
+        }
+    }
+
+    async fn run(points: Vec<Metric>) -> Vec<Metric> {
+        let rules = rules();
+        let mut rollup = Rollup::new(&rules, Capture::default()).unwrap();
+        for point in points {
+            rollup.write(point);
+        }
+        rollup.close().await.unwrap();
+        rollup.inner.0
+    }
+
+    #[tokio::test]
+    async fn test_it_aggregates_a_window() {
+        let out = run(vec![
+            load(0, "web.1", 1.0, 1.0, 1.0),
+            load(20, "web.1", 2.0, 3.0, 2.0),
+            load(40, "web.1", 3.0, 2.0, 3.0),
+        ])
+        .await;
+
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].timestamp, at(0));
+        assert_eq!(out[0].fields["load_avg_1m"], FieldValue::Float(2.0, None));
+        assert_eq!(out[0].fields["load_avg_5m"], FieldValue::Float(3.0, None));
+        assert_eq!(out[0].fields["load_avg_15m"], FieldValue::Float(3.0, None));
+    }
+
+    #[tokio::test]
+    async fn test_it_keeps_series_separate() {
+        let out = run(vec![
+            load(0, "web.1", 1.0, 1.0, 1.0),
+            load(0, "web.2", 2.0, 2.0, 2.0),
+        ])
+        .await;
+
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].tags["source"], "web.1");
+        assert_eq!(out[1].tags["source"], "web.2");
+    }
+
+    #[tokio::test]
+    async fn test_out_of_order_points_are_deterministic() {
+        let ordered = run(vec![
+            load(0, "web.1", 1.0, 1.0, 1.0),
+            load(20, "web.1", 2.0, 3.0, 2.0),
+            load(40, "web.1", 3.0, 2.0, 3.0),
+        ])
+        .await;
+        let shuffled = run(vec![
+            load(40, "web.1", 3.0, 2.0, 3.0),
+            load(0, "web.1", 1.0, 1.0, 1.0),
+            load(20, "web.1", 2.0, 3.0, 2.0),
+        ])
+        .await;
+
+        assert_eq!(ordered, shuffled);
+    }
+
+    #[tokio::test]
+    async fn test_ties_go_to_the_greater_value_either_way() {
+        let tied = |max| {
+            let mut metric = load(0, "web.1", 1.0, 0.0, max);
+            metric.fields.insert(
+                "load_avg_5m".into(),
+                FieldValue::Integer(2, Some(max.to_string())),
+            );
+            metric
+        };
+        let first = run(vec![tied(1.0), tied(2.0)]).await;
+        let second = run(vec![tied(2.0), tied(1.0)]).await;
+
+        assert_eq!(first, second);
+        assert_eq!(
+            first[0].fields["load_avg_15m"],
+            FieldValue::Float(2.0, None)
+        );
+        assert_eq!(
+            first[0].fields["load_avg_5m"],
+            FieldValue::Integer(2, Some("2".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_it_emits_closed_windows_and_drops_late_points() {
+        let rules = rules();
+        let mut rollup = Rollup::new(&rules, Capture::default()).unwrap();
+
+        rollup.write(load(0, "web.1", 1.0, 1.0, 1.0));
+        rollup.write(load(310, "web.1", 2.0, 2.0, 2.0));
+        assert_eq!(rollup.inner.0.len(), 1);
+
+        rollup.write(load(290, "web.1", 9.0, 9.0, 9.0));
+        assert_eq!(rollup.dropped(), 1);
+
+        rollup.close().await.unwrap();
+        let out = rollup.inner.0;
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].fields["load_avg_1m"], FieldValue::Float(1.0, None));
+        assert_eq!(out[1].timestamp, at(300));
+    }
+
+    #[tokio::test]
+    async fn test_a_series_ahead_doesnt_close_the_others() {
+        let rules = rules();
+        let mut rollup = Rollup::new(&rules, Capture::default()).unwrap();
+
+        rollup.write(load(0, "web.1", 1.0, 1.0, 1.0));
+        rollup.write(load(3600, "web.2", 2.0, 2.0, 2.0));
+        rollup.write(load(20, "web.1", 3.0, 3.0, 3.0));
+        assert!(rollup.inner.0.is_empty());
+        assert_eq!(rollup.dropped(), 0);
+
+        rollup.close().await.unwrap();
+        let out = rollup.inner.0;
+        assert_eq!(out.len(), 2);
+        assert_eq!(out[0].tags["source"], "web.1");
+        assert_eq!(out[0].fields["load_avg_1m"], FieldValue::Float(2.0, None));
+    }
+
+    #[tokio::test]
+    async fn test_it_closes_windows_of_quiet_series() {
+        let rules = rules();
+        let mut rollup = Rollup::new(&rules, Capture::default()).unwrap();
+        let now = Instant::now();
+
+        rollup.aggregate(load(0, "web.1", 1.0, 1.0, 1.0), now);
+        rollup.close_idle(now + Duration::from_secs(299));
+        assert!(rollup.inner.0.is_empty());
+
+        rollup.close_idle(now + Duration::from_secs(300));
+        assert_eq!(rollup.inner.0.len(), 1);
+        assert_eq!(rollup.inner.0[0].timestamp, at(0));
+
+        // Still remembered, so the written window isn't reopened
+        rollup.aggregate(
+            load(10, "web.1", 9.0, 9.0, 9.0),
+            now + Duration::from_secs(301),
+        );
+        assert_eq!(rollup.dropped(), 1);
+
+        rollup.close_idle(now + Duration::from_secs(900));
+        assert!(rollup.series.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_the_average_of_integers_stays_an_integer() {
+        let point = |secs, value| {
+            let mut metric = load(secs, "web.1", 0.0, 0.0, 0.0);
+            metric.fields.insert(
+                "load_avg_1m".into(),
+                FieldValue::Integer(value, Some("ms".into())),
+            );
+            metric
+        };
+        let out = run(vec![point(0, 1), point(20, 2)]).await;
+
+        assert_eq!(
+            out[0].fields["load_avg_1m"],
+            FieldValue::Integer(2, Some("ms".into()))
+        );
+
+        // Unless a float shows up, whichever point it's in
+        let mut float = point(20, 0);
+        float.fields.insert(
+            "load_avg_1m".into(),
+            FieldValue::Float(2.5, Some("ms".into())),
+        );
+        let out = run(vec![point(0, 1), float]).await;
+
+        assert_eq!(
+            out[0].fields["load_avg_1m"],
+            FieldValue::Float(1.75, Some("ms".into()))
+        );
+    }
+
+    #[test]
+    fn test_it_refuses_empty_windows() {
+        let mut rules = rules();
+        rules[0].window = 0;
+
+        assert!(Rollup::new(&rules, Capture::default()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_other_metrics_pass_through() {
+        let metric = Metric::default();
+        let out = run(vec![metric.clone()]).await;
+
+        assert_eq!(out, vec![metric]);
+    }
+}
@@ -0,0 +1,210 @@
+//! JSON lines, one object per line, like Vector or a CloudWatch export sends:
+//!
+//! ```text
+//! {"timestamp":"2019-11-25T18:28:00Z","host":"ip-10-0-0-1","app":"api","message":"at=info status=200"}
+//! ```
+//!
+//! Which keys hold the timestamp, host, app, proc and message is configurable, and can be dotted
+//! paths into nested objects, like `kubernetes.pod_name`. A line without a message gets one made
+//! from its other top level fields, in logfmt, so the usual decoders can pick them apart.
+
+use std::borrow::Cow;
+
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::parser::{LogData, ParseError, ParseResult, StructuredData};
+
+// Epoch timestamps bigger than this are in milliseconds, which is what CloudWatch uses. In seconds
+// it's the year 5138.
+const MAX_EPOCH_SECONDS: f64 = 1e11;
+
+/// Which keys to read the parts of a `LogData` from
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Fields {
+    pub timestamp: String,
+    pub hostname: String,
+    pub appname: String,
+    pub procid: String,
+    pub msg: String,
+}
+
+impl Default for Fields {
+    fn default() -> Self {
+        Self {
+            timestamp: "timestamp".into(),
+            hostname: "host".into(),
+            appname: "app".into(),
+            procid: "proc".into(),
+            msg: "message".into(),
+        }
+    }
+}
+
+pub fn parse_line<'a>(m: &'a str, fields: &Fields) -> ParseResult<Option<LogData<'a>>> {
+    let m = m.trim();
+    if m.is_empty() {
+        return Ok(None);
+    }
+
+    let object = match serde_json::from_str(m)? {
+        Value::Object(object) => object,
+        _ => return Err(ParseError::Invalid("json object")),
+    };
+
+    let text = |key: &str| lookup(&object, key).and_then(to_text);
+    let msg = match text(&fields.msg) {
+        Some(msg) => msg,
+        None => to_logfmt(&object, fields),
+    };
+
+    Ok(Some(LogData {
+        facility: None,
+        severity: None,
+        version: None,
+        timestamp_str: lookup(&object, &fields.timestamp)
+            .and_then(to_timestamp)
+            .map(Cow::from),
+        hostname: text(&fields.hostname).unwrap_or_default().into(),
+        appname: text(&fields.appname).unwrap_or_default().into(),
+        procid: text(&fields.procid).unwrap_or_default().into(),
+        msgid: None,
+        structured_data: StructuredData::new(),
+        msg: msg.into(),
+    }))
+}
+
+fn lookup<'v>(object: &'v Map<String, Value>, path: &str) -> Option<&'v Value> {
+    if let Some(value) = object.get(path) {
+        return Some(value);
+    }
+    let (first, rest) = path.split_once('.')?;
+    match object.get(first)? {
+        Value::Object(nested) => lookup(nested, rest),
+        _ => None,
+    }
+}
+
+fn to_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+// RFC 3339 and the other formats `timestamp::parse` knows are passed through, epoch numbers are
+// converted
+fn to_timestamp(value: &Value) -> Option<String> {
+    let epoch = match value {
+        Value::String(s) => return Some(s.clone()),
+        Value::Number(n) => n.as_f64()?,
+        _ => return None,
+    };
+    let millis = if epoch.abs() < MAX_EPOCH_SECONDS {
+        epoch * 1000.0
+    } else {
+        epoch
+    };
+    let timestamp: DateTime<Utc> = Utc.timestamp_millis_opt(millis as i64).single()?;
+    Some(timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+}
+
+fn to_logfmt(object: &Map<String, Value>, fields: &Fields) -> String {
+    let mapped = [
+        &fields.timestamp,
+        &fields.hostname,
+        &fields.appname,
+        &fields.procid,
+    ];
+    let mut msg = String::new();
+
+    for (key, value) in object {
+        if mapped.contains(&key) || key.contains(char::is_whitespace) {
+            continue;
+        }
+        let value = match value {
+            Value::Null | Value::Object(_) | Value::Array(_) => continue,
+            Value::String(s) => Cow::from(s.as_str()),
+            other => Cow::from(other.to_string()),
+        };
+
+        if !msg.is_empty() {
+            msg.push(' ');
+        }
+        msg.push_str(key);
+        msg.push('=');
+        if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+            msg.push('"');
+            for c in value.chars() {
+                if c == '"' || c == '\\' {
+                    msg.push('\\');
+                }
+                msg.push(c);
+            }
+            msg.push('"');
+        } else {
+            msg.push_str(&value);
+        }
+    }
+
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_line, Fields};
+    use crate::parser::parse_msg;
+
+    #[test]
+    fn test_it_parses_json_lines() {
+        let line = r#"{"timestamp":"2019-11-25T18:28:00Z","host":"ip-10-0-0-1","app":"api","proc":"web.1","message":"at=info path=\"/\" status=200"}"#;
+
+        let r = parse_line(line, &Fields::default())
+            .expect("Should parse a line")
+            .expect("Should have data");
+        assert_eq!(r.timestamp_str.as_deref(), Some("2019-11-25T18:28:00Z"));
+        assert_eq!(r.hostname, "ip-10-0-0-1");
+        assert_eq!(r.appname, "api");
+        assert_eq!(r.procid, "web.1");
+        assert_eq!(r.msg, r#"at=info path="/" status=200"#);
+
+        assert!(parse_line("[1, 2]", &Fields::default()).is_err());
+        assert!(parse_line("at=info", &Fields::default()).is_err());
+        assert!(parse_line("", &Fields::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_it_maps_nested_fields_and_epoch_timestamps() {
+        let fields = Fields {
+            appname: "kubernetes.container_name".into(),
+            msg: "log".into(),
+            ..Default::default()
+        };
+        let line = r#"{"timestamp":1574706480000,"kubernetes":{"container_name":"api"},"log":"sample#load_avg_1m=0.01"}"#;
+
+        let r = parse_line(line, &fields).unwrap().unwrap();
+        assert_eq!(r.timestamp_str.as_deref(), Some("2019-11-25T18:28:00Z"));
+        assert_eq!(r.appname, "api");
+        assert_eq!(r.hostname, "");
+        assert_eq!(r.msg, "sample#load_avg_1m=0.01");
+
+        let line = r#"{"timestamp":1574706480.5,"log":"x"}"#;
+        let r = parse_line(line, &fields).unwrap().unwrap();
+        assert_eq!(r.timestamp_str.as_deref(), Some("2019-11-25T18:28:00.500Z"));
+    }
+
+    #[test]
+    fn test_it_makes_a_message_from_the_other_fields() {
+        let line = r#"{"timestamp":"2019-11-25T18:28:00Z","app":"api","level":"info","duration_ms":12,"path":"/a b","ok":true,"extra":{"x":1}}"#;
+
+        let r = parse_line(line, &Fields::default()).unwrap().unwrap();
+        assert_eq!(r.msg, r#"duration_ms=12 level=info ok=true path="/a b""#);
+
+        let pairs = parse_msg(&r.msg);
+        assert_eq!(pairs["path"], "/a b");
+        assert_eq!(pairs["duration_ms"], "12");
+    }
+}
@@ -0,0 +1,69 @@
+//! Bare logfmt, with no syslog header in front of it:
+//!
+//! ```text
+//! ts=2019-11-25T18:28:00Z app=api host=ip-10-0-0-1 at=info path="/" status=200
+//! ```
+//!
+//! The whole line is the message, so decoders see every pair. The timestamp, app and host come
+//! from the pairs that conventionally hold them.
+
+use crate::parser::{extract_msg, LogData, ParseResult, StructuredData};
+
+const TIMESTAMP_KEYS: [&str; 3] = ["ts", "time", "timestamp"];
+const APPNAME_KEY: &str = "app";
+const HOSTNAME_KEY: &str = "host";
+
+pub fn parse_line(m: &str) -> ParseResult<Option<LogData<'_>>> {
+    let m = m.trim_end();
+    if m.is_empty() {
+        return Ok(None);
+    }
+
+    let mut keys = TIMESTAMP_KEYS.to_vec();
+    keys.extend([APPNAME_KEY, HOSTNAME_KEY]);
+    let mut pairs = extract_msg(m, &keys);
+
+    Ok(Some(LogData {
+        facility: None,
+        severity: None,
+        version: None,
+        timestamp_str: TIMESTAMP_KEYS.iter().find_map(|key| pairs.remove(key)),
+        hostname: pairs.remove(HOSTNAME_KEY).unwrap_or_default(),
+        appname: pairs.remove(APPNAME_KEY).unwrap_or_default(),
+        procid: "".into(),
+        msgid: None,
+        structured_data: StructuredData::new(),
+        msg: m.into(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_line;
+
+    #[test]
+    fn test_it_parses_logfmt() {
+        let line =
+            r#"ts=2019-11-25T18:28:00Z app=api host=ip-10-0-0-1 at=info path="/" status=200"#;
+
+        let r = parse_line(line)
+            .expect("Should parse a line")
+            .expect("Should have data");
+        assert_eq!(r.timestamp_str.as_deref(), Some("2019-11-25T18:28:00Z"));
+        assert_eq!(r.appname, "api");
+        assert_eq!(r.hostname, "ip-10-0-0-1");
+        assert_eq!(r.procid, "");
+        assert_eq!(r.msg, line);
+
+        let r = parse_line("time=\"2019-11-25 18:28:00 +0000\" level=warn")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            r.timestamp_str.as_deref(),
+            Some("2019-11-25 18:28:00 +0000")
+        );
+        assert_eq!(r.appname, "");
+
+        assert!(parse_line("").unwrap().is_none());
+    }
+}
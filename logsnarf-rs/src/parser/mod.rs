@@ -0,0 +1,818 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::str;
+use std::string;
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone, Utc};
+use serde_derive::Deserialize;
+use thiserror::Error;
+
+pub mod json;
+pub mod logfmt;
+pub mod rfc3164;
+pub mod rfc5424;
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("unexpected eof")]
+    UnexpectedEndOfInput,
+    #[error("missing field {0}")]
+    MissingField(&'static str),
+    #[error("invalid {0}")]
+    Invalid(&'static str),
+    #[error("unicode error: {0}")]
+    BaseUnicodeError(#[from] str::Utf8Error),
+    #[error("unicode error: {0}")]
+    UnicodeError(#[from] string::FromUtf8Error),
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+}
+
+impl ParseError {
+    /// A short name for the variant, for counting errors by kind
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ParseError::UnexpectedEndOfInput => "unexpected_eof",
+            ParseError::MissingField(_) => "missing_field",
+            ParseError::Invalid(_) => "invalid",
+            ParseError::BaseUnicodeError(_) | ParseError::UnicodeError(_) => "unicode",
+            ParseError::JsonError(_) => "json",
+        }
+    }
+}
+
+type ParseResult<T> = Result<T, ParseError>;
+
+/// Which format, or flavor of syslog, to expect on each line
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Heroku's logplex drains, which look like RFC 5424 but leave out the structured data
+    #[default]
+    Logplex,
+    /// Conformant RFC 5424, including structured data
+    Rfc5424,
+    /// Classic BSD syslog, eg `<34>Oct 11 22:14:15 host app[123]: msg`
+    Rfc3164,
+    /// Decide between the syslog flavors line by line
+    Auto,
+    /// One JSON object per line, see [`json`]
+    Json,
+    /// Bare logfmt key-value pairs, see [`logfmt`]
+    Logfmt,
+}
+
+/// The timezone for timestamps that don't include one
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum Timezone {
+    /// The timezone of the machine logsnarf is running on
+    #[default]
+    Local,
+    Fixed(FixedOffset),
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Options {
+    pub mode: Mode,
+    pub timezone: Timezone,
+    pub json: json::Fields,
+}
+
+/// A parsed line, borrowing from the line itself. Only the parts that have to be rewritten, like
+/// an RFC 3164 timestamp, an escaped SD param or a JSON string, are owned.
+#[derive(Debug, Clone)]
+pub struct LogData<'a> {
+    pub facility: Option<u8>,
+    pub severity: Option<u8>,
+    pub version: Option<u8>,
+    /// `None` when the line has a NIL timestamp, `-`
+    pub timestamp_str: Option<Cow<'a, str>>,
+    pub hostname: Cow<'a, str>,
+    pub appname: Cow<'a, str>,
+    pub procid: Cow<'a, str>,
+    pub msgid: Option<&'a str>,
+    pub structured_data: StructuredData<'a>,
+    pub msg: Cow<'a, str>,
+}
+
+pub type KVPairs<'a> = BTreeMap<&'a str, Cow<'a, str>>;
+
+/// SD-ID => (PARAM-NAME => PARAM-VALUE)
+pub type StructuredData<'a> = BTreeMap<&'a str, KVPairs<'a>>;
+
+const BOM: char = '\u{feff}';
+
+const SEVERITIES: [&str; 8] = [
+    "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+];
+
+const FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+impl LogData<'_> {
+    /// Keyword for the severity, eg `err` or `info`
+    pub fn severity_name(&self) -> Option<&'static str> {
+        self.severity.map(|s| SEVERITIES[s as usize])
+    }
+
+    /// Keyword for the facility, eg `user` or `local0`
+    pub fn facility_name(&self) -> Option<&'static str> {
+        self.facility.map(|f| FACILITIES[f as usize])
+    }
+
+    /// Looks up a structured data param, eg `origin@48577`, `ip`
+    pub fn sd_param(&self, id: &str, name: &str) -> Option<&str> {
+        self.structured_data.get(id)?.get(name).map(Cow::as_ref)
+    }
+}
+
+/// Looks up a severity by keyword or number, eg `warning` or `4`
+pub fn severity_from_str(s: &str) -> Option<u8> {
+    s.parse::<u8>()
+        .ok()
+        .filter(|s| (*s as usize) < SEVERITIES.len())
+        .or_else(|| {
+            SEVERITIES
+                .iter()
+                .position(|name| *name == s)
+                .map(|s| s as u8)
+        })
+}
+
+impl str::FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "logplex" => Ok(Mode::Logplex),
+            "rfc5424" => Ok(Mode::Rfc5424),
+            "rfc3164" => Ok(Mode::Rfc3164),
+            "auto" => Ok(Mode::Auto),
+            "json" => Ok(Mode::Json),
+            "logfmt" => Ok(Mode::Logfmt),
+            _ => Err(format!("unknown mode {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Timezone {
+    type Error = String;
+
+    /// `local`, `UTC`, or an offset like `+02:00`
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "local" => Ok(Timezone::Local),
+            "UTC" | "utc" | "Z" => Ok(Timezone::Fixed(FixedOffset::east_opt(0).unwrap())),
+            offset => offset
+                .parse()
+                .map(Timezone::Fixed)
+                .map_err(|_| format!("unknown timezone {}", s)),
+        }
+    }
+}
+
+impl Timezone {
+    fn naive_from_utc(&self, dt: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Timezone::Local => dt.with_timezone(&Local).naive_local(),
+            Timezone::Fixed(offset) => dt.with_timezone(offset).naive_local(),
+        }
+    }
+
+    // Times that happen twice when the clocks go back use the earlier one
+    fn utc_from_naive(&self, naive: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            Timezone::Local => Local
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|dt| dt.with_timezone(&Utc)),
+            Timezone::Fixed(offset) => offset
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|dt| dt.with_timezone(&Utc)),
+        }
+    }
+}
+
+pub fn parse_line_with<'a>(options: &Options, m: &'a str) -> ParseResult<Option<LogData<'a>>> {
+    match options.mode {
+        Mode::Logplex => parse_line(m),
+        Mode::Rfc5424 => rfc5424::parse_line(m),
+        Mode::Rfc3164 => rfc3164::parse_line(m, &options.timezone),
+        Mode::Auto => parse_auto(m, options),
+        Mode::Json => json::parse_line(m, &options.json),
+        Mode::Logfmt => logfmt::parse_line(m),
+    }
+}
+
+// After the PRI, both RFC 5424 and logplex have a version number, where RFC 3164 goes straight to
+// the timestamp. Logplex lines are RFC 5424 without the structured data, so they're told apart by
+// whether what comes after the MSGID parses as structured data. Heroku app logs often start with
+// `[tag]`, which only looks like structured data without any params.
+fn parse_auto<'a>(m: &'a str, options: &Options) -> ParseResult<Option<LogData<'a>>> {
+    let after_pri = skip_octet_count(m).split_once('>').map(|(_, rest)| rest);
+    let has_version = after_pri.is_some_and(|rest| {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        digits > 0 && rest[digits..].starts_with(' ')
+    });
+
+    if !has_version {
+        return rfc3164::parse_line(m, &options.timezone);
+    }
+
+    match rfc5424::parse_line(m) {
+        Ok(Some(ld)) if ld.structured_data.values().all(|params| !params.is_empty()) => {
+            Ok(Some(ld))
+        }
+        Ok(None) => Ok(None),
+        _ => parse_line(m),
+    }
+}
+
+/// Quickly parses a syslog-ish line into LogData
+pub fn parse_line(m: &str) -> ParseResult<Option<LogData<'_>>> {
+    if m.is_empty() {
+        return Ok(None);
+    }
+
+    let mut rest = m;
+
+    rest = skip_to_after('<', rest)?;
+    let (pri, rest) = take_until('>', rest)?;
+    let (version, rest) = take_until(' ', rest)?;
+    let (facility, severity) = split_pri(pri.parse().ok());
+    let (timestamp_str, rest) = parse_term(rest)?;
+    let (hostname, rest) = parse_term(rest)?;
+    let (appname, rest) = parse_term(rest)?;
+    let (procid, rest) = parse_term(rest)?;
+    let (msgid, rest) = parse_term(rest)?;
+    let msg = rest.strip_prefix(BOM).unwrap_or(rest);
+
+    if let (Some(hostname), Some(appname), Some(procid)) = (hostname, appname, procid) {
+        Ok(Some(LogData {
+            facility,
+            severity,
+            version: version.parse().ok(),
+            timestamp_str: timestamp_str.map(Cow::from),
+            hostname: hostname.into(),
+            appname: appname.into(),
+            procid: procid.into(),
+            msgid,
+            structured_data: StructuredData::new(),
+            msg: msg.into(),
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+// PRI is the facility * 8 + the severity, and can't be any higher than local7.debug
+fn split_pri(pri: Option<u8>) -> (Option<u8>, Option<u8>) {
+    match pri {
+        Some(pri) if pri <= 191 => (Some(pri / 8), Some(pri % 8)),
+        _ => (None, None),
+    }
+}
+
+// The strict version, for the parsers of actual standards
+fn parse_pri(pri: &str) -> ParseResult<(u8, u8)> {
+    if pri.is_empty() || pri.len() > 3 || !pri.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ParseError::Invalid("priority"));
+    }
+    match split_pri(pri.parse().ok()) {
+        (Some(facility), Some(severity)) => Ok((facility, severity)),
+        _ => Err(ParseError::Invalid("priority")),
+    }
+}
+
+// Lines framed with octet counting (RFC 6587, and logplex) start with their length
+fn skip_octet_count(m: &str) -> &str {
+    let digits = m.bytes().take_while(u8::is_ascii_digit).count();
+    match m[digits..].strip_prefix(' ') {
+        Some(rest) if digits > 0 => rest,
+        _ => m,
+    }
+}
+
+/// Parses a syslog "message" into key-value pairs, honoring quotes. Bare words are skipped, see
+/// [`scan_msg`] to get them too. If a key appears more than once, the first one wins, like in
+/// [`extract_msg`].
+pub fn parse_msg(msg: &str) -> KVPairs<'_> {
+    let mut pairs = KVPairs::new();
+    for (key, val) in scan_msg(msg).filter_map(Token::into_pair) {
+        pairs.entry(key).or_insert(val);
+    }
+    pairs
+}
+
+/// Parses only the given keys out of a message, and stops scanning as soon as it has found them
+/// all. If a key appears more than once, the first one wins.
+pub fn extract_msg<'a, K: AsRef<str>>(msg: &'a str, keys: &[K]) -> KVPairs<'a> {
+    let mut wanted: Vec<&str> = keys.iter().map(AsRef::as_ref).collect();
+    wanted.sort_unstable();
+    wanted.dedup();
+
+    let mut pairs = KVPairs::new();
+    for (key, val) in scan_msg(msg).filter_map(Token::into_pair) {
+        if wanted.binary_search(&key).is_ok() {
+            pairs.entry(key).or_insert(val);
+            if pairs.len() == wanted.len() {
+                break;
+            }
+        }
+    }
+    pairs
+}
+
+/// Bare words in a message, in order, eg `Error`, `R14`, `(Memory`, ... in
+/// `Error R14 (Memory quota exceeded)`
+pub fn parse_words(msg: &str) -> Vec<Cow<'_, str>> {
+    scan_msg(msg)
+        .filter_map(|token| match token {
+            Token::Word(word) => Some(word),
+            Token::Pair(..) => None,
+        })
+        .collect()
+}
+
+/// Scans a message one token at a time, without building a map
+pub fn scan_msg(msg: &str) -> Tokens<'_> {
+    Tokens { rest: msg }
+}
+
+/// Something in a message between the spaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token<'a> {
+    /// `key=value` or `key="quoted value"`, where the value may be empty
+    Pair(&'a str, Cow<'a, str>),
+    /// Anything else, eg `crashed` or `"a quoted phrase"`
+    Word(Cow<'a, str>),
+}
+
+impl<'a> Token<'a> {
+    pub fn into_pair(self) -> Option<(&'a str, Cow<'a, str>)> {
+        match self {
+            Token::Pair(key, val) => Some((key, val)),
+            Token::Word(_) => None,
+        }
+    }
+}
+
+/// Iterator over the tokens in a message, see [`scan_msg`]. Messages come from anywhere, so it
+/// never fails: an unterminated quote runs to the end of the message, and a backslash escapes a
+/// quote or another backslash inside quotes.
+#[derive(Debug, Clone)]
+pub struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let m = self.rest.trim_start_matches(' ');
+        if m.is_empty() {
+            self.rest = m;
+            return None;
+        }
+
+        if let Some(quoted) = m.strip_prefix('"') {
+            let (word, rest) = parse_quoted(quoted);
+            self.rest = rest;
+            return Some(Token::Word(word));
+        }
+
+        let end = m.find([' ', '=']).unwrap_or(m.len());
+        match m[end..].strip_prefix('=') {
+            Some(value) if end > 0 => {
+                let key = &m[..end];
+                let (val, rest) = parse_value(value);
+                self.rest = rest;
+                Some(Token::Pair(key, val))
+            }
+            _ => {
+                let end = m.find(' ').unwrap_or(m.len());
+                self.rest = &m[end..];
+                Some(Token::Word(m[..end].into()))
+            }
+        }
+    }
+}
+
+// Messages are never rejected for what's in them, so the message parsers can't fail
+fn parse_value(input: &str) -> (Cow<'_, str>, &str) {
+    match input.strip_prefix('"') {
+        Some(quoted) => parse_quoted(quoted),
+        None => {
+            let end = input.find(' ').unwrap_or(input.len());
+            (input[..end].into(), &input[end..])
+        }
+    }
+}
+
+// Everything up to the closing quote, which has already been opened. Values without any escapes
+// are borrowed.
+fn parse_quoted(input: &str) -> (Cow<'_, str>, &str) {
+    match input.find(['"', '\\']) {
+        Some(idx) if input[idx..].starts_with('"') => {
+            return (input[..idx].into(), &input[(idx + 1)..]);
+        }
+        None => return (input.into(), ""),
+        Some(_) => (),
+    }
+
+    let mut value = String::new();
+    let mut chars = input.char_indices();
+    while let Some((idx, chr)) = chars.next() {
+        match chr {
+            '\\' => match chars.next() {
+                Some((_, esc @ ('"' | '\\'))) => value.push(esc),
+                Some((_, other)) => {
+                    value.push('\\');
+                    value.push(other);
+                }
+                None => value.push('\\'),
+            },
+            '"' => return (value.into(), &input[(idx + 1)..]),
+            _ => value.push(chr),
+        }
+    }
+    (value.into(), "")
+}
+
+fn skip_to_after(c: char, m: &str) -> ParseResult<&str> {
+    for (idx, chr) in m.char_indices() {
+        if chr == c {
+            return Ok(&m[(idx + 1)..]);
+        }
+    }
+    Err(ParseError::UnexpectedEndOfInput)
+}
+
+fn take_until(c: char, m: &str) -> ParseResult<(&str, &str)> {
+    m.split_once(c).ok_or(ParseError::UnexpectedEndOfInput)
+}
+
+fn parse_term(m: &str) -> ParseResult<(Option<&str>, &str)> {
+    // Blank field
+    if m == "-" {
+        return Ok((None, ""));
+    }
+    if let Some(rest) = m.strip_prefix("- ") {
+        return Ok((None, rest));
+    }
+
+    // Read until we get a Space or some unprintable ascii. Header fields are ascii only, so
+    // anything else means this isn't the header we think it is.
+    if let Some((idx, chr)) = m.char_indices().find(|(_, chr)| !chr.is_ascii_graphic()) {
+        if !chr.is_ascii() {
+            return Err(ParseError::Invalid("header"));
+        }
+        return Ok((Some(&m[..idx]), &m[(idx + 1)..]));
+    }
+    Err(ParseError::UnexpectedEndOfInput)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{
+        extract_msg, parse_line, parse_line_with, parse_msg, parse_words, scan_msg, Mode, Options,
+        ParseError, Token,
+    };
+
+    #[test]
+    fn test_it_parses() {
+        let line = r#"302 <158>1 2019-11-25T18:28:00.089034+00:00 host heroku router - at=info method=GET"#;
+
+        let r = parse_line(line)
+            .expect("Should parse a line")
+            .expect("Should have data");
+        assert_eq!(
+            r.timestamp_str.as_deref(),
+            Some("2019-11-25T18:28:00.089034+00:00")
+        );
+        assert_eq!(r.hostname, "host".to_string());
+        assert_eq!(r.appname, "heroku".to_string());
+        assert_eq!(r.procid, "router".to_string());
+        assert_eq!(r.msgid, None);
+        assert_eq!(r.msg, "at=info method=GET".to_string());
+    }
+
+    // #[test]
+    // fn test_it_parses_samples() {
+    //     let names = [
+    //         "dyno_load.log",
+    //         "dyno_mem.log",
+    //         "postgres.log",
+    //         "redis.log",
+    //         "router.log",
+    //     ];
+
+    //     for name in names {
+    //         let file = File::open(format!("samples/{}", name))
+    //             .expect(&format!("Cannot find file: {}", name));
+    //         let lines = io::BufReader::new(file).lines();
+    //         for line in lines {
+    //             if let Ok(l) = line {
+    //                 assert!(parse_line(&l).is_ok());
+    //             }
+    //         }
+    //     }
+    // }
+
+    // #[test]
+    // fn test_it_parses_a_huge_file() {
+    //     let file =
+    //         File::open("samples/staging.log").expect(&format!("Cannot find file: staging.log"));
+    //     let lines = io::BufReader::new(file).lines();
+
+    //     for line in lines {
+    //         if let Ok(l) = line {
+    //             assert!(parse_line(&l).is_ok());
+    //         }
+    //     }
+    // }
+
+    #[test]
+    fn test_it_parses_router_errors() {
+        let line = r#"293 <158>1 2019-11-25T18:31:14.120332+00:00 host heroku router - at=error code=H10 desc="App crashed" method=GET path="/" host=myapp.example request_id=5d3c0dd1-0b8a-4e37-8f5c-d5b1f9f2b8f4 fwd="52.90.232.237" dyno= connect= service= status=503 bytes= protocol=https"#;
+
+        let r = parse_line(line)
+            .expect("Should parse a line")
+            .expect("Should have data");
+        assert_eq!(r.procid, "router".to_string());
+
+        let pairs = parse_msg(&r.msg);
+        assert_eq!(pairs["code"], "H10");
+        assert_eq!(pairs["desc"], "App crashed");
+        assert_eq!(pairs["dyno"], "");
+        assert_eq!(pairs["protocol"], "https");
+    }
+
+    // Found by fuzzing, see fuzz/
+    #[test]
+    fn test_it_survives_truncated_and_non_ascii_headers() {
+        let lines = [
+            "<45>1 -",
+            "241 <45>1 2019-11-25T18:28:00.226738+00:00 -",
+            "241 <45>1 2019-11-25T18:28:00.226738+00:00 host heroku -",
+            "<45>1 2019-11-25T18:28:00Z h\u{e9}st heroku web.1 - msg",
+            "<45>1 2019-11-25T18:28:00Z host\u{e9}heroku web.1 - msg",
+        ];
+        for line in lines {
+            let options = Options {
+                mode: Mode::Auto,
+                ..Default::default()
+            };
+            let _ = parse_line(line);
+            let _ = parse_line_with(&options, line);
+        }
+
+        for line in &lines[3..] {
+            assert!(matches!(
+                parse_line(line),
+                Err(ParseError::Invalid("header"))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_it_parses_dyno_events() {
+        let lines = [
+            (
+                "113 <45>1 2019-11-25T18:32:40.561920+00:00 host heroku worker.3 - Error R14 (Memory quota exceeded)",
+                "worker.3",
+                "Error R14 (Memory quota exceeded)",
+            ),
+            (
+                "106 <45>1 2019-11-25T18:33:02.102393+00:00 host heroku web.1 - State changed from up to crashed",
+                "web.1",
+                "State changed from up to crashed",
+            ),
+        ];
+
+        for (line, procid, msg) in lines {
+            let r = parse_line(line)
+                .expect("Should parse a line")
+                .expect("Should have data");
+            assert_eq!(r.appname, "heroku".to_string());
+            assert_eq!(r.procid, procid.to_string());
+            assert_eq!(r.msg, msg.to_string());
+        }
+    }
+
+    #[test]
+    fn test_auto_mode_detects_each_format() {
+        let options = Options {
+            mode: Mode::Auto,
+            ..Default::default()
+        };
+        let lines = [
+            (
+                r#"302 <158>1 2019-11-25T18:28:00.089034+00:00 host heroku router - at=info method=GET"#,
+                Some(1),
+                "at=info method=GET",
+            ),
+            (
+                r#"120 <190>1 2019-11-25T18:28:00.089034+00:00 host app web.1 - [3f1c] Started GET "/""#,
+                Some(1),
+                r#"[3f1c] Started GET "/""#,
+            ),
+            (
+                r#"<165>1 2003-10-11T22:14:15.003Z host evntslog - ID47 [origin ip="10.0.0.1"] event"#,
+                Some(1),
+                "event",
+            ),
+            ("<34>Oct 11 22:14:15 host su[123]: failed", None, "failed"),
+        ];
+
+        for (line, version, msg) in lines {
+            let r = parse_line_with(&options, line)
+                .expect("Should parse a line")
+                .expect("Should have data");
+            assert_eq!(r.version, version, "{}", line);
+            assert_eq!(r.msg, msg.to_string());
+        }
+    }
+
+    #[test]
+    fn test_it_parses_missing_timestamp() {
+        let line = r#"302 <158>1 - host heroku router - at=info method=GET"#;
+
+        let r = parse_line(line)
+            .expect("Should parse the line")
+            .expect("Should have data");
+        assert_eq!(r.timestamp_str, None);
+        assert_eq!(r.msg, "at=info method=GET");
+    }
+
+    #[test]
+    fn test_malformed_input() {
+        let line = r#"8:28:00.089034+00:00 host heroku router - at=info method=GET"#;
+        let r = parse_line(line);
+        assert!(r.is_err())
+    }
+
+    #[test]
+    fn test_blank_link() {
+        let line = r#""#;
+        let r = parse_line(line).expect("Should parse a blank line");
+        assert!(r.is_none())
+    }
+
+    #[test]
+    fn test_it_parses_kv_pairs() {
+        let msg = r#"at=info method=GET"#;
+        let r = parse_msg(msg);
+
+        assert_eq!(r.get("at"), Some(&"info".into()));
+        assert_eq!(r.get("method"), Some(&"GET".into()));
+    }
+
+    #[test]
+    fn test_it_parses_quoted_pair_values() {
+        let msg = r#"path="/admin/sidekiq_queue_stats" fwd="52.90.232.237,70.132.60.79""#;
+        let r = parse_msg(msg);
+
+        assert_eq!(r.get("path"), Some(&"/admin/sidekiq_queue_stats".into()));
+        assert_eq!(r.get("fwd"), Some(&"52.90.232.237,70.132.60.79".into()));
+    }
+
+    #[test]
+    fn test_it_scans_tokens_in_order() {
+        let tokens: Vec<Token> = scan_msg(r#"at=info path="/a b" crashed status=200"#).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Pair("at", "info".into()),
+                Token::Pair("path", "/a b".into()),
+                Token::Word("crashed".into()),
+                Token::Pair("status", "200".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_it_extracts_only_the_requested_keys() {
+        let msg = r#"at=error code=H12 desc="Request timeout" method=GET code=H13"#;
+
+        let pairs = extract_msg(msg, &["desc", "code", "code"]);
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs["code"], "H12");
+        assert_eq!(pairs["desc"], "Request timeout");
+
+        assert!(extract_msg("at=info", &["dyno"]).is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_keys_keep_the_first_either_way() {
+        let msg = "at=info dyno=web.1 status=200 dyno=web.2";
+
+        assert_eq!(parse_msg(msg)["dyno"], "web.1");
+        assert_eq!(extract_msg(msg, &["dyno"])["dyno"], "web.1");
+        assert_eq!(extract_msg(msg, &["dyno", "status"])["dyno"], "web.1");
+    }
+
+    #[test]
+    fn test_it_tolerates_messy_messages() {
+        let pairs = parse_msg("at=info dyno= ");
+        assert_eq!(pairs["at"], "info");
+        assert_eq!(pairs["dyno"], "");
+
+        let pairs = parse_msg(r#"msg="say \"hi\" to C:\\temp" path="/unterminated x=1"#);
+        assert_eq!(pairs["msg"], r#"say "hi" to C:\temp"#);
+        assert_eq!(pairs["path"], "/unterminated x=1");
+        assert!(!pairs.contains_key("x"));
+
+        let pairs = parse_msg("=oops ==x a=b=c");
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs["a"], "b=c");
+    }
+
+    #[test]
+    fn test_it_keeps_bare_words() {
+        let msg = "Error R14 (Memory quota exceeded) source=web.1";
+        assert_eq!(
+            parse_words(msg),
+            vec!["Error", "R14", "(Memory", "quota", "exceeded)"]
+        );
+        assert_eq!(parse_msg(msg)["source"], "web.1");
+    }
+
+    // Formats pairs the way a well-behaved logger would
+    fn format_pairs(pairs: &[(String, String)]) -> String {
+        pairs
+            .iter()
+            .map(|(k, v)| {
+                if v.contains([' ', '"', '\\', '=']) || v.starts_with('"') {
+                    format!(
+                        r#"{}="{}""#,
+                        k,
+                        v.replace('\\', r"\\").replace('"', r#"\""#)
+                    )
+                } else {
+                    format!("{}={}", k, v)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    proptest! {
+        #[test]
+        fn prop_it_never_panics(msg in "\\PC*") {
+            let _ = parse_msg(&msg);
+            let _ = parse_words(&msg);
+        }
+
+        #[test]
+        fn prop_it_never_panics_on_delimiters(msg in r#"[ ="\\a\u{e9}]{0,32}"#) {
+            let tokens: Vec<Token> = scan_msg(&msg).collect();
+            prop_assert!(tokens.len() <= msg.len());
+        }
+
+        #[test]
+        fn prop_it_round_trips_pairs(
+            pairs in prop::collection::vec(("[a-z_#.]{1,12}", "\\PC{0,16}"), 0..8)
+        ) {
+            let msg = format_pairs(&pairs);
+            let mut expected = std::collections::BTreeMap::new();
+            for (k, v) in &pairs {
+                expected.entry(k.as_str()).or_insert(v.as_str());
+            }
+
+            let parsed = parse_msg(&msg);
+            let parsed: std::collections::BTreeMap<&str, &str> =
+                parsed.iter().map(|(k, v)| (*k, v.as_ref())).collect();
+            prop_assert_eq!(parsed, expected);
+        }
+    }
+}
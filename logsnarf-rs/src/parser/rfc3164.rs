@@ -0,0 +1,179 @@
+//! RFC 3164, the classic BSD syslog format that rsyslog forwards by default:
+//!
+//! ```text
+//! <34>Oct 11 22:14:15 mymachine su[123]: 'su root' failed for lonvick on /dev/pts/8
+//! ```
+//!
+//! The timestamp has neither a year nor a timezone, so the timezone comes from the config and the
+//! year is whichever one puts the timestamp closest to when the line was received.
+
+use chrono::{DateTime, Datelike, NaiveDateTime, SecondsFormat, Utc};
+
+use crate::parser::{
+    parse_pri, skip_octet_count, LogData, ParseError, ParseResult, StructuredData, Timezone,
+};
+
+// `Mmm dd hh:mm:ss`, with the day padded by a space
+const TIMESTAMP_LEN: usize = 15;
+
+pub fn parse_line<'a>(m: &'a str, tz: &Timezone) -> ParseResult<Option<LogData<'a>>> {
+    parse_line_at(m, tz, Utc::now())
+}
+
+/// Parses the line as if it was received at `now`
+pub fn parse_line_at<'a>(
+    m: &'a str,
+    tz: &Timezone,
+    now: DateTime<Utc>,
+) -> ParseResult<Option<LogData<'a>>> {
+    if m.is_empty() {
+        return Ok(None);
+    }
+
+    let rest = skip_octet_count(m);
+    let rest = rest
+        .strip_prefix('<')
+        .ok_or(ParseError::Invalid("priority"))?;
+    let (pri, rest) = rest
+        .split_once('>')
+        .ok_or(ParseError::Invalid("priority"))?;
+    let (facility, severity) = parse_pri(pri)?;
+    let (timestamp, rest) = parse_timestamp(rest, tz, now)?;
+    let (hostname, rest) = rest
+        .split_once(' ')
+        .ok_or(ParseError::UnexpectedEndOfInput)?;
+    let (appname, procid, msg) = parse_tag(rest);
+
+    Ok(Some(LogData {
+        facility: Some(facility),
+        severity: Some(severity),
+        version: None,
+        timestamp_str: Some(
+            timestamp
+                .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+                .into(),
+        ),
+        hostname: hostname.into(),
+        appname: appname.into(),
+        procid: procid.into(),
+        msgid: None,
+        structured_data: StructuredData::new(),
+        msg: msg.into(),
+    }))
+}
+
+fn parse_timestamp<'a>(
+    m: &'a str,
+    tz: &Timezone,
+    now: DateTime<Utc>,
+) -> ParseResult<(DateTime<Utc>, &'a str)> {
+    // rsyslog's "high precision" templates use RFC 3339 timestamps instead
+    if let Some((stamp, rest)) = m.split_once(' ') {
+        if let Ok(timestamp) = DateTime::parse_from_rfc3339(stamp) {
+            return Ok((timestamp.with_timezone(&Utc), rest));
+        }
+    }
+
+    let stamp = m
+        .get(..TIMESTAMP_LEN)
+        .ok_or(ParseError::UnexpectedEndOfInput)?;
+    let rest = m[TIMESTAMP_LEN..]
+        .strip_prefix(' ')
+        .ok_or(ParseError::Invalid("timestamp"))?;
+
+    let local_now = tz.naive_from_utc(now);
+    let year = local_now.year();
+    let timestamp = [year - 1, year, year + 1]
+        .iter()
+        .filter_map(|year| {
+            NaiveDateTime::parse_from_str(&format!("{} {}", year, stamp), "%Y %b %e %H:%M:%S").ok()
+        })
+        .min_by_key(|candidate| (*candidate - local_now).num_seconds().abs())
+        .ok_or(ParseError::Invalid("timestamp"))?;
+
+    let timestamp = tz
+        .utc_from_naive(timestamp)
+        .ok_or(ParseError::Invalid("timestamp"))?;
+    Ok((timestamp, rest))
+}
+
+// `TAG[PID]: MSG`, where the tag is the app name. Anything without a tag is all message.
+fn parse_tag(m: &str) -> (&str, &str, &str) {
+    match m.find([':', ' ']) {
+        Some(idx) if m[idx..].starts_with(':') => {
+            let (tag, msg) = (&m[..idx], &m[(idx + 1)..]);
+            let msg = msg.strip_prefix(' ').unwrap_or(msg);
+            match tag.split_once('[') {
+                Some((appname, pid)) => (appname, pid.trim_end_matches(']'), msg),
+                None => (tag, "", msg),
+            }
+        }
+        _ => ("", "", m),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{FixedOffset, TimeZone, Utc};
+
+    use super::parse_line_at;
+    use crate::parser::Timezone;
+
+    #[test]
+    fn test_it_parses_bsd_syslog() {
+        let now = Utc.with_ymd_and_hms(2019, 10, 12, 0, 0, 0).unwrap();
+        let line = "<34>Oct 11 22:14:15 mymachine su[123]: 'su root' failed for lonvick";
+
+        let r = parse_line_at(
+            line,
+            &Timezone::Fixed(FixedOffset::east_opt(0).unwrap()),
+            now,
+        )
+        .expect("Should parse a line")
+        .expect("Should have data");
+        assert_eq!(r.facility, Some(4));
+        assert_eq!(r.severity, Some(2));
+        assert_eq!(r.timestamp_str.as_deref(), Some("2019-10-11T22:14:15Z"));
+        assert_eq!(r.hostname, "mymachine".to_string());
+        assert_eq!(r.appname, "su".to_string());
+        assert_eq!(r.procid, "123".to_string());
+        assert_eq!(r.msg, "'su root' failed for lonvick".to_string());
+    }
+
+    #[test]
+    fn test_it_infers_the_year_around_new_years() {
+        let now = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 30).unwrap();
+        let line = "<13>Dec 31 23:59:58 host app: late";
+
+        let r = parse_line_at(
+            line,
+            &Timezone::Fixed(FixedOffset::east_opt(0).unwrap()),
+            now,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(r.timestamp_str.as_deref(), Some("2019-12-31T23:59:58Z"));
+    }
+
+    #[test]
+    fn test_it_applies_the_timezone() {
+        let now = Utc.with_ymd_and_hms(2019, 10, 12, 0, 0, 0).unwrap();
+        let line = "<13>Oct  1 02:00:00 host cron[9]: tick";
+
+        let tz = Timezone::Fixed(FixedOffset::west_opt(5 * 3600).unwrap());
+        let r = parse_line_at(line, &tz, now).unwrap().unwrap();
+        assert_eq!(r.timestamp_str.as_deref(), Some("2019-10-01T07:00:00Z"));
+        assert_eq!(r.msg, "tick".to_string());
+    }
+
+    #[test]
+    fn test_it_parses_untagged_messages() {
+        let now = Utc.with_ymd_and_hms(2019, 10, 12, 0, 0, 0).unwrap();
+        let line = "<13>2019-10-11T22:14:15.003+00:00 host just a message";
+
+        let r = parse_line_at(line, &Timezone::Local, now).unwrap().unwrap();
+        assert_eq!(r.timestamp_str.as_deref(), Some("2019-10-11T22:14:15.003Z"));
+        assert_eq!(r.appname, "".to_string());
+        assert_eq!(r.msg, "just a message".to_string());
+    }
+}
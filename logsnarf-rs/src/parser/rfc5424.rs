@@ -0,0 +1,216 @@
+//! A conformant RFC 5424 parser, for syslog sources other than Heroku.
+//!
+//! ```text
+//! SYSLOG-MSG = HEADER SP STRUCTURED-DATA [SP MSG]
+//! HEADER     = PRI VERSION SP TIMESTAMP SP HOSTNAME SP APP-NAME SP PROCID SP MSGID
+//! ```
+//!
+//! Lines may be prefixed with an octet count, as they are when framed over TCP (RFC 6587) or in a
+//! logplex drain.
+
+use std::borrow::Cow;
+
+use crate::parser::{
+    parse_pri, skip_octet_count, LogData, ParseError, ParseResult, StructuredData, BOM,
+};
+
+const NIL: &str = "-";
+
+pub fn parse_line(m: &str) -> ParseResult<Option<LogData<'_>>> {
+    if m.is_empty() {
+        return Ok(None);
+    }
+
+    let rest = skip_octet_count(m);
+    let rest = rest
+        .strip_prefix('<')
+        .ok_or(ParseError::Invalid("priority"))?;
+    let (pri, rest) = rest
+        .split_once('>')
+        .ok_or(ParseError::Invalid("priority"))?;
+    let (facility, severity) = parse_pri(pri)?;
+    let (version, rest) = parse_header_field(rest)?;
+    let version = version
+        .and_then(|v| v.parse::<u8>().ok())
+        .filter(|v| *v > 0)
+        .ok_or(ParseError::Invalid("version"))?;
+    let (timestamp_str, rest) = parse_header_field(rest)?;
+    let (hostname, rest) = parse_header_field(rest)?;
+    let (appname, rest) = parse_header_field(rest)?;
+    let (procid, rest) = parse_header_field(rest)?;
+    let (msgid, rest) = parse_header_field(rest)?;
+    let (structured_data, rest) = parse_structured_data(rest)?;
+
+    let msg = match rest.strip_prefix(' ') {
+        Some(msg) => msg.strip_prefix(BOM).unwrap_or(msg),
+        None if rest.is_empty() => rest,
+        None => return Err(ParseError::Invalid("structured data")),
+    };
+
+    Ok(Some(LogData {
+        facility: Some(facility),
+        severity: Some(severity),
+        version: Some(version),
+        timestamp_str: timestamp_str.map(Cow::from),
+        hostname: hostname.unwrap_or_default().into(),
+        appname: appname.unwrap_or_default().into(),
+        procid: procid.unwrap_or_default().into(),
+        msgid,
+        structured_data,
+        msg: msg.into(),
+    }))
+}
+
+// Every header field is followed by a space, since the structured data always comes after them
+fn parse_header_field(m: &str) -> ParseResult<(Option<&str>, &str)> {
+    let (field, rest) = m.split_once(' ').ok_or(ParseError::UnexpectedEndOfInput)?;
+    if field.is_empty() {
+        return Err(ParseError::Invalid("header"));
+    }
+    Ok((if field == NIL { None } else { Some(field) }, rest))
+}
+
+/// `-` or one or more `[SD-ID PARAM-NAME="PARAM-VALUE" ...]` elements
+fn parse_structured_data(m: &str) -> ParseResult<(StructuredData<'_>, &str)> {
+    let mut sd = StructuredData::new();
+
+    if let Some(rest) = m.strip_prefix(NIL) {
+        return Ok((sd, rest));
+    }
+
+    let mut rest = m;
+    while let Some(element) = rest.strip_prefix('[') {
+        let id_len = element
+            .find([' ', ']', '=', '"'])
+            .ok_or(ParseError::UnexpectedEndOfInput)?;
+        let id = &element[..id_len];
+        if id.is_empty() {
+            return Err(ParseError::Invalid("structured data"));
+        }
+        rest = &element[id_len..];
+
+        let params = sd.entry(id).or_default();
+        loop {
+            if let Some(r) = rest.strip_prefix(']') {
+                rest = r;
+                break;
+            }
+            let param = rest
+                .strip_prefix(' ')
+                .ok_or(ParseError::Invalid("structured data"))?;
+            let (name, value) = param
+                .split_once("=\"")
+                .ok_or(ParseError::Invalid("structured data"))?;
+            if name.is_empty() || name.contains([' ', ']', '"']) {
+                return Err(ParseError::Invalid("structured data"));
+            }
+            let (value, r) = parse_param_value(value)?;
+            params.insert(name, value);
+            rest = r;
+        }
+    }
+
+    if sd.is_empty() {
+        return Err(ParseError::Invalid("structured data"));
+    }
+    Ok((sd, rest))
+}
+
+// Inside a PARAM-VALUE, `"`, `\` and `]` must be escaped with a backslash. A backslash before
+// anything else is kept as-is. Values without any escapes are borrowed.
+fn parse_param_value(m: &str) -> ParseResult<(Cow<'_, str>, &str)> {
+    if let Some(idx) = m.find(['"', '\\']) {
+        if m[idx..].starts_with('"') {
+            return Ok((m[..idx].into(), &m[(idx + 1)..]));
+        }
+    }
+
+    let mut value = String::new();
+    let mut chars = m.char_indices();
+
+    while let Some((idx, chr)) = chars.next() {
+        match chr {
+            '\\' => match chars.next() {
+                Some((_, esc @ ('"' | '\\' | ']'))) => value.push(esc),
+                Some((_, other)) => {
+                    value.push('\\');
+                    value.push(other);
+                }
+                None => break,
+            },
+            '"' => return Ok((value.into(), &m[(idx + 1)..])),
+            _ => value.push(chr),
+        }
+    }
+    Err(ParseError::UnexpectedEndOfInput)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_line;
+
+    #[test]
+    fn test_it_parses_the_header() {
+        let line = "<34>1 2003-10-11T22:14:15.003Z mymachine.example.com su - ID47 - \u{feff}'su root' failed for lonvick on /dev/pts/8";
+
+        let r = parse_line(line)
+            .expect("Should parse a line")
+            .expect("Should have data");
+        assert_eq!(r.facility, Some(4));
+        assert_eq!(r.severity, Some(2));
+        assert_eq!(r.severity_name(), Some("crit"));
+        assert_eq!(r.facility_name(), Some("auth"));
+        assert_eq!(r.version, Some(1));
+        assert_eq!(r.timestamp_str.as_deref(), Some("2003-10-11T22:14:15.003Z"));
+        assert_eq!(r.hostname, "mymachine.example.com".to_string());
+        assert_eq!(r.appname, "su".to_string());
+        assert_eq!(r.procid, "".to_string());
+        assert_eq!(r.msgid, Some("ID47"));
+        assert!(r.structured_data.is_empty());
+        assert_eq!(
+            r.msg,
+            "'su root' failed for lonvick on /dev/pts/8".to_string()
+        );
+    }
+
+    #[test]
+    fn test_it_parses_structured_data() {
+        let line = r#"<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut="3" eventSource="Application" eventID="1011"][examplePriority@32473 class="high"] An application event"#;
+
+        let r = parse_line(line)
+            .expect("Should parse a line")
+            .expect("Should have data");
+        assert_eq!(r.sd_param("exampleSDID@32473", "iut"), Some("3"));
+        assert_eq!(
+            r.sd_param("exampleSDID@32473", "eventSource"),
+            Some("Application")
+        );
+        assert_eq!(r.sd_param("examplePriority@32473", "class"), Some("high"));
+        assert_eq!(r.msg, "An application event".to_string());
+    }
+
+    #[test]
+    fn test_it_parses_escaped_param_values() {
+        let line = r#"101 <13>1 2019-11-25T18:28:00Z host app 123 - [meta path="C:\\logs\]" quote="say \"hi\""]"#;
+
+        let r = parse_line(line)
+            .expect("Should parse a line")
+            .expect("Should have data");
+        assert_eq!(r.sd_param("meta", "path"), Some(r#"C:\logs]"#));
+        assert_eq!(r.sd_param("meta", "quote"), Some(r#"say "hi""#));
+        assert_eq!(r.msg, "".to_string());
+    }
+
+    #[test]
+    fn test_it_rejects_malformed_lines() {
+        let lines = [
+            "<999>1 2003-10-11T22:14:15.003Z host app - - - msg",
+            "<34>x 2003-10-11T22:14:15.003Z host app - - - msg",
+            "<34>1 2003-10-11T22:14:15.003Z host app - - [unterminated a=\"b\" msg",
+            "<34>1 2003-10-11T22:14:15.003Z host app - - msg",
+        ];
+        for line in lines {
+            assert!(parse_line(line).is_err(), "{}", line);
+        }
+    }
+}
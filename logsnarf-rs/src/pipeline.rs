@@ -0,0 +1,578 @@
+//! The parse, match and decode stages every line goes through, and [`Pipeline`], which runs them
+//! on lines already in memory. Unlike [`App`](crate::app::App), a pipeline doesn't need a config
+//! file or a TSDB, so it can be embedded in other services:
+//!
+//! ```
+//! use logsnarf::pipeline::Pipeline;
+//! use logsnarf::settings::{Matcher, MetricDecoder};
+//!
+//! let decoder = MetricDecoder {
+//!     name: "heroku_router".into(),
+//!     tag_names: vec!["dyno".into()],
+//!     field_names: vec!["service".into()],
+//!     matcher: [("procid".to_string(), Matcher::String("router".into()))].into(),
+//!     ..Default::default()
+//! };
+//! let mut pipeline = Pipeline::builder().decoders([decoder]).build()?;
+//!
+//! let line = "<158>1 2019-11-25T18:28:00Z host heroku router - dyno=web.1 service=25ms";
+//! let counts = pipeline.push("example", [line]);
+//! assert_eq!(counts.metrics, 1);
+//! assert_eq!(pipeline.writer()[0].name, "heroku_router");
+//! # Ok::<(), logsnarf::error::Error>(())
+//! ```
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream, StreamExt};
+use tracing::instrument;
+
+use crate::{
+    app::Counts,
+    decoder::{self, Builtin, DecodeError, Decoder},
+    error::{LineErrorKind, Result},
+    metric::Metric,
+    metric_writer::{rollup::Rollup, MetricWriter, WriterError},
+    parser::{self, LogData, ParseError},
+    settings::{Builtins, Matcher, MetricDecoder, MetricDecoders, Rollups},
+    timestamp::{self, Policies, TimestampError},
+};
+
+/// The decoders and builtins, shared by `App` and `Pipeline`
+#[derive(Debug)]
+pub(crate) struct Stages {
+    pub(crate) decoders: Vec<Decoder>,
+    pub(crate) builtins: Vec<Builtin>,
+}
+
+/// What each stage made of one line, to explain why it gave the metrics it did, or none
+#[derive(Debug, Default)]
+pub struct Trace<'a> {
+    /// Nothing if the line was empty or didn't parse
+    pub log_data: Option<LogData<'a>>,
+    /// Every configured decoder in order, with the first of its conditions the line failed
+    pub decoders: Vec<(String, Option<(String, Matcher)>)>,
+    /// Only the first decoder that matches is used
+    pub decoder: Option<String>,
+    /// The pairs that decoder read from the message
+    pub pairs: BTreeMap<String, String>,
+    /// Every enabled builtin, and whether it matched
+    pub builtins: Vec<(&'static str, bool)>,
+    /// Only resolved once something matched
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Which of the `results` came from each decoder or builtin
+    pub decoded: Vec<(String, Range<usize>)>,
+    /// What `App` would have written or reported for the line
+    pub results: Vec<std::result::Result<Metric, LineErrorKind>>,
+}
+
+/// Extracts metrics from lines in memory, writing them to `W`, which by default keeps them in a
+/// `Vec`. Metrics named in `rollups` are held back until their window closes, or `finish`.
+pub struct Pipeline<W = Vec<Metric>> {
+    stages: Stages,
+    parser: parser::Options,
+    timestamps: Policies,
+    writer: Rollup<W>,
+}
+
+/// Configures a [`Pipeline`]. Everything is optional: without decoders or builtins, nothing
+/// matches.
+pub struct Builder<W = Vec<Metric>> {
+    parser: parser::Options,
+    timestamps: Policies,
+    decoders: MetricDecoders,
+    builtins: Builtins,
+    rollups: Rollups,
+    writer: W,
+}
+
+impl Stages {
+    pub(crate) fn new(decoders: &MetricDecoders, builtins: &Builtins) -> Result<Self> {
+        Ok(Self {
+            decoders: decoder::build_decoders(decoders)?,
+            builtins: decoder::build_builtins(builtins),
+        })
+    }
+
+    /// Extracts the metrics from one line and writes them, keeping count of what happened
+    pub(crate) fn extract_line(
+        &self,
+        line: &str,
+        options: &parser::Options,
+        policy: &timestamp::Policy,
+        received: DateTime<Utc>,
+        counts: &mut Counts,
+        writer: &mut impl MetricWriter,
+    ) {
+        let results = self.results_from_line(line, options, policy, received, counts);
+        for metric in results.into_iter().flatten() {
+            writer.write(metric);
+        }
+    }
+
+    /// Every metric from one line, along with whatever kept the line from giving more. A line
+    /// without a timestamp is only skipped, as `missing = "skip"` asks.
+    pub(crate) fn results_from_line(
+        &self,
+        line: &str,
+        options: &parser::Options,
+        policy: &timestamp::Policy,
+        received: DateTime<Utc>,
+        counts: &mut Counts,
+    ) -> Vec<std::result::Result<Metric, LineErrorKind>> {
+        self.results(line, options, policy, received, counts, None)
+    }
+
+    /// Like `results_from_line`, but also keeps what each stage made of the line
+    pub(crate) fn trace_line<'a>(
+        &self,
+        line: &'a str,
+        options: &parser::Options,
+        policy: &timestamp::Policy,
+        received: DateTime<Utc>,
+    ) -> Trace<'a> {
+        let mut trace = Trace::default();
+        let mut counts = Counts::default();
+        trace.results = self.results(
+            line,
+            options,
+            policy,
+            received,
+            &mut counts,
+            Some(&mut trace),
+        );
+        trace
+    }
+
+    fn results<'a>(
+        &self,
+        line: &'a str,
+        options: &parser::Options,
+        policy: &timestamp::Policy,
+        received: DateTime<Utc>,
+        counts: &mut Counts,
+        trace: Option<&mut Trace<'a>>,
+    ) -> Vec<std::result::Result<Metric, LineErrorKind>> {
+        counts.lines += 1;
+        let results = match self.metrics_from_line(line, options, policy, received, counts, trace) {
+            Ok(results) => results,
+            Err(e) => {
+                counts.unparsed += 1;
+                vec![Err(LineErrorKind::Parse(e))]
+            }
+        };
+        for result in &results {
+            match result {
+                Ok(_) => counts.metrics += 1,
+                Err(kind) => counts.count_error(kind),
+            }
+        }
+        results
+    }
+
+    #[instrument(skip(self, options, policy, counts, trace))]
+    fn metrics_from_line<'a>(
+        &self,
+        line: &'a str,
+        options: &parser::Options,
+        policy: &timestamp::Policy,
+        received: DateTime<Utc>,
+        counts: &mut Counts,
+        mut trace: Option<&mut Trace<'a>>,
+    ) -> std::result::Result<Vec<std::result::Result<Metric, LineErrorKind>>, ParseError> {
+        let ld = match Self::parse_line(options, line)? {
+            Some(ld) => ld,
+            None => {
+                counts.unmatched += 1;
+                return Ok(Vec::new());
+            }
+        };
+
+        let decoder = Self::find_decoder(&self.decoders, &ld);
+        let builtins: Vec<&Builtin> = self.builtins.iter().filter(|b| b.matches(&ld)).collect();
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.log_data = Some(ld.clone());
+            trace.decoders = self
+                .decoders
+                .iter()
+                .map(|decoder| {
+                    let mismatch = decoder
+                        .mismatch(&ld)
+                        .map(|(attr, condition)| (attr.to_string(), condition.clone()));
+                    (decoder.name().clone(), mismatch)
+                })
+                .collect();
+            trace.builtins = self
+                .builtins
+                .iter()
+                .map(|builtin| (builtin.name(), builtins.contains(&builtin)))
+                .collect();
+            if let Some(decoder) = decoder {
+                trace.decoder = Some(decoder.name().clone());
+                trace.pairs = decoder::owned(&decoder.pairs(&ld));
+            }
+        }
+        if decoder.is_none() && builtins.is_empty() {
+            counts.unmatched += 1;
+            return Ok(Vec::new());
+        }
+        counts.matched += 1;
+
+        let timestamp =
+            match policy.resolve(ld.timestamp_str.as_deref(), received, &mut counts.skew) {
+                Ok(timestamp) => timestamp,
+                Err(TimestampError::Missing) => return Ok(Vec::new()),
+                Err(e @ TimestampError::Skewed(..)) => {
+                    counts.rejected += 1;
+                    tracing::warn!("Rejected line: {}\n{}", e, line);
+                    return Ok(vec![Err(LineErrorKind::Timestamp(e))]);
+                }
+                Err(e) => {
+                    tracing::warn!("Problem with timestamp: {}", e);
+                    return Ok(vec![Err(LineErrorKind::Timestamp(e))]);
+                }
+            };
+
+        let mut metrics = Vec::new();
+        let mut decoded = Vec::new();
+
+        if let Some(decoder) = decoder {
+            let start = metrics.len();
+            let metric = decoder
+                .decode(&ld, timestamp)
+                .map(|m| m.into_iter().collect());
+            Self::accept(decoder.name(), metric, line, counts, &mut metrics);
+            if trace.is_some() {
+                decoded.push((decoder.name().clone(), start..metrics.len()));
+            }
+        }
+
+        for builtin in builtins {
+            let start = metrics.len();
+            Self::accept(
+                builtin.name(),
+                builtin.decode(&ld, timestamp),
+                line,
+                counts,
+                &mut metrics,
+            );
+            if trace.is_some() {
+                decoded.push((builtin.name().to_string(), start..metrics.len()));
+            }
+        }
+
+        if let Some(trace) = trace {
+            trace.timestamp = Some(timestamp);
+            trace.decoded = decoded;
+        }
+        Ok(metrics)
+    }
+
+    #[instrument]
+    fn parse_line<'a>(
+        options: &parser::Options,
+        line: &'a str,
+    ) -> std::result::Result<Option<LogData<'a>>, ParseError> {
+        parser::parse_line_with(options, line).map_err(|e| {
+            tracing::warn!("Problem parsing line: {}", e);
+            e
+        })
+    }
+
+    #[instrument]
+    fn find_decoder<'a>(decoders: &'a Vec<Decoder>, ld: &'a LogData) -> Option<&'a Decoder> {
+        decoders.iter().find(|decoder| decoder.matches(ld))
+    }
+
+    // Keeps the metrics that are fit to be written. Anything that breaks the decoder's rules is
+    // counted and logged along with the line it came from, and handed on as an error.
+    fn accept(
+        name: &str,
+        decoded: std::result::Result<Vec<Metric>, DecodeError>,
+        line: &str,
+        counts: &mut Counts,
+        out: &mut Vec<std::result::Result<Metric, LineErrorKind>>,
+    ) {
+        // Each metric stands on its own, so one that breaks the rules doesn't take the rest of
+        // the line's metrics with it
+        let results: Vec<std::result::Result<Metric, DecodeError>> = match decoded {
+            Ok(metrics) => metrics
+                .into_iter()
+                .map(|metric| decoder::validate(&metric).map(|_| metric))
+                .collect(),
+            Err(e) => vec![Err(e)],
+        };
+
+        let tally = counts.decoders.entry(name.to_string()).or_default();
+        tally.lines += 1;
+        for result in results {
+            match result {
+                Ok(metric) => {
+                    tally.metrics += 1;
+                    out.push(Ok(metric))
+                }
+                Err(e) if e.is_rejection() => {
+                    tally.rejected += 1;
+                    counts.rejected += 1;
+                    tracing::warn!("Rejected {} metric: {}\n{}", name, e, line);
+                    out.push(Err(LineErrorKind::Decode(e)));
+                }
+                Err(e) => {
+                    tally.errors += 1;
+                    tracing::warn!("Problem decoding {} message: {}", name, e);
+                    out.push(Err(LineErrorKind::Decode(e)));
+                }
+            }
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn builder() -> Builder {
+        Builder {
+            parser: parser::Options::default(),
+            timestamps: Policies::default(),
+            decoders: Vec::new(),
+            builtins: Builtins::default(),
+            rollups: Rollups::new(),
+            writer: Vec::new(),
+        }
+    }
+}
+
+impl<W> Builder<W> {
+    /// How to read lines, including the mode. Logplex by default.
+    pub fn parser(mut self, options: parser::Options) -> Self {
+        self.parser = options;
+        self
+    }
+
+    pub fn mode(mut self, mode: parser::Mode) -> Self {
+        self.parser.mode = mode;
+        self
+    }
+
+    pub fn timestamps(mut self, policies: Policies) -> Self {
+        self.timestamps = policies;
+        self
+    }
+
+    /// Tried in order, and only the first that matches a line is used, like `[[metrics]]`
+    pub fn decoders(mut self, decoders: impl IntoIterator<Item = MetricDecoder>) -> Self {
+        self.decoders = decoders.into_iter().collect();
+        self
+    }
+
+    pub fn builtins(mut self, builtins: Builtins) -> Self {
+        self.builtins = builtins;
+        self
+    }
+
+    pub fn rollups(mut self, rollups: Rollups) -> Self {
+        self.rollups = rollups;
+        self
+    }
+
+    /// Where to write metrics, instead of a `Vec`
+    pub fn writer<V: MetricWriter>(self, writer: V) -> Builder<V> {
+        Builder {
+            parser: self.parser,
+            timestamps: self.timestamps,
+            decoders: self.decoders,
+            builtins: self.builtins,
+            rollups: self.rollups,
+            writer,
+        }
+    }
+
+    /// Fails if a decoder's matcher uses an attribute or condition that doesn't exist, or a rollup
+    /// has an empty window
+    pub fn build(self) -> Result<Pipeline<W>> {
+        Ok(Pipeline {
+            stages: Stages::new(&self.decoders, &self.builtins)?,
+            parser: self.parser,
+            timestamps: self.timestamps,
+            writer: Rollup::new(&self.rollups, self.writer)?,
+        })
+    }
+}
+
+impl<W: MetricWriter + Send> Pipeline<W> {
+    /// Extracts the metrics from `lines` into the writer. `source` picks the timestamp policy.
+    pub fn push<'a>(&mut self, source: &str, lines: impl IntoIterator<Item = &'a str>) -> Counts {
+        let policy = self.timestamps.for_source(source);
+        let mut counts = Counts::default();
+        for line in lines {
+            self.stages.extract_line(
+                line,
+                &self.parser,
+                policy,
+                Utc::now(),
+                &mut counts,
+                &mut self.writer,
+            );
+        }
+        counts
+    }
+
+    /// The metrics from each line as it's pulled from `lines`, without going through the writer
+    /// or any rollups
+    pub fn metrics<'a>(
+        &'a self,
+        source: &'a str,
+        lines: impl IntoIterator<Item = &'a str> + 'a,
+    ) -> impl Iterator<Item = Metric> + 'a {
+        lines
+            .into_iter()
+            .flat_map(move |line| self.metrics_from(source, line))
+    }
+
+    /// Like `metrics`, for lines that arrive asynchronously
+    pub fn stream<'a>(
+        &'a self,
+        source: &'a str,
+        lines: impl Stream<Item = String> + 'a,
+    ) -> impl Stream<Item = Metric> + 'a {
+        lines.flat_map(move |line| stream::iter(self.metrics_from(source, &line)))
+    }
+
+    fn metrics_from(&self, source: &str, line: &str) -> Vec<Metric> {
+        let policy = self.timestamps.for_source(source);
+        let mut metrics = Vec::new();
+        self.stages.extract_line(
+            line,
+            &self.parser,
+            policy,
+            Utc::now(),
+            &mut Counts::default(),
+            &mut metrics,
+        );
+        metrics
+    }
+
+    pub fn writer(&self) -> &W {
+        self.writer.inner()
+    }
+
+    /// Writes out any rollup windows still open, and hands back the writer
+    pub async fn finish(mut self) -> std::result::Result<W, WriterError> {
+        self.writer.close().await?;
+        Ok(self.writer.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{stream, StreamExt};
+
+    use super::{Pipeline, Stages};
+    use crate::{
+        app::Counts,
+        metric::{FieldValue, Fields, Metric, Tags},
+        settings::{Aggregation, Matcher, MetricDecoder, Rollup},
+    };
+
+    fn load() -> MetricDecoder {
+        MetricDecoder {
+            name: "heroku_dyno_load".into(),
+            tag_names: vec!["source".into()],
+            field_names: vec!["sample#load_avg_1m".into()],
+            matcher: [("appname".to_string(), Matcher::String("heroku".into()))].into(),
+            ..Default::default()
+        }
+    }
+
+    fn line(second: u32, load: f64) -> String {
+        format!(
+            "<45>1 2019-11-25T18:28:{:02}Z host heroku web.1 - source=web.1 sample#load_avg_1m={}",
+            second, load
+        )
+    }
+
+    #[tokio::test]
+    async fn test_it_extracts_and_rolls_up_lines_in_memory() {
+        let lines = [line(0, 1.0), line(30, 3.0), "not a line".into()];
+        let mut pipeline = Pipeline::builder()
+            .decoders([load()])
+            .rollups(vec![Rollup {
+                name: "heroku_dyno_load".into(),
+                window: 60,
+                lateness: 0,
+                default: Aggregation::Avg,
+                fields: Default::default(),
+            }])
+            .build()
+            .unwrap();
+
+        let counts = pipeline.push("test", lines.iter().map(String::as_str));
+        assert_eq!(counts.lines, 3);
+        assert_eq!(counts.unparsed, 1);
+        assert_eq!(counts.decoders["heroku_dyno_load"].metrics, 2);
+        assert!(pipeline
+            .writer()
+            .iter()
+            .all(|m| m.name != "heroku_dyno_load"));
+
+        let metrics = pipeline.finish().await.unwrap();
+        let rolled: Vec<_> = metrics
+            .iter()
+            .filter(|m| m.name == "heroku_dyno_load")
+            .collect();
+        assert_eq!(rolled.len(), 1);
+        assert_eq!(rolled[0].fields["load_avg_1m"].to_string(), "2 None");
+    }
+
+    #[tokio::test]
+    async fn test_it_streams_metrics() {
+        let pipeline = Pipeline::builder().decoders([load()]).build().unwrap();
+        let lines = vec![line(0, 1.0), line(1, 2.0)];
+
+        let metrics: Vec<_> = pipeline
+            .metrics("test", lines.iter().map(String::as_str))
+            .collect();
+        assert_eq!(metrics.len(), 2);
+
+        let streamed: Vec<_> = pipeline
+            .stream("test", stream::iter(lines.clone()))
+            .collect()
+            .await;
+        assert_eq!(streamed, metrics);
+        assert!(pipeline.writer().is_empty());
+    }
+
+    #[test]
+    fn test_it_rejects_only_the_invalid_metrics_of_a_line() {
+        let metric = |name: &str, value| {
+            let mut fields = Fields::new();
+            if let Some(value) = value {
+                fields.insert("value".into(), FieldValue::Integer(value, None));
+            }
+            Metric {
+                timestamp: chrono::Utc::now(),
+                name: name.into(),
+                tags: Tags::new(),
+                fields,
+            }
+        };
+        let decoded = vec![
+            metric("a", Some(1)),
+            metric("b", None),
+            metric("c", Some(3)),
+        ];
+
+        let mut counts = Counts::default();
+        let mut out = Vec::new();
+        Stages::accept("l2met", Ok(decoded), "line", &mut counts, &mut out);
+
+        let names: Vec<_> = out
+            .iter()
+            .map(|result| result.as_ref().map(|metric| metric.name.as_str()).ok())
+            .collect();
+        assert_eq!(names, vec![Some("a"), None, Some("c")]);
+        assert_eq!(counts.rejected, 1);
+        assert_eq!(counts.decoders["l2met"].lines, 1);
+        assert_eq!(counts.decoders["l2met"].metrics, 2);
+    }
+}
@@ -0,0 +1,312 @@
+//! The pieces of replaying archived logs into the TSDB, eg to backfill after an outage: merging
+//! files by time, holding writes to a steady rate, moving timestamps, and estimating how long is
+//! left.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+
+use crate::metric::Metric;
+
+/// Interleaves the lines of several files, oldest first, so files that overlap in time don't
+/// close each other's rollup windows early. Lines come from `app::Lines`, so `None` stands for a
+/// line that was too long. Lines without a timestamp keep their place after the line before them
+/// in the same file.
+pub struct Merge<S, F> {
+    heads: Vec<Head<S>>,
+    timestamp: F,
+}
+
+// The next line of one file, and when it happened
+struct Head<S> {
+    source: usize,
+    lines: S,
+    line: Option<String>,
+    at: DateTime<Utc>,
+}
+
+/// Spaces out writes to `per_second` points on average, since the replay started
+#[derive(Debug)]
+pub struct RateLimit {
+    per_second: u64,
+    started: Instant,
+    sent: u64,
+}
+
+/// What happens to each metric's timestamp before it's written
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retime {
+    /// Added to every timestamp, eg to move old logs up to now for a load test
+    pub shift: Option<chrono::Duration>,
+    /// Points from before this, after shifting, are dropped, like the TSDB's retention would
+    pub cutoff: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug)]
+pub struct Progress {
+    total: u64,
+    started: Instant,
+}
+
+impl<S, F> Merge<S, F>
+where
+    S: Stream<Item = io::Result<Option<String>>> + Unpin,
+    F: Fn(&str) -> Option<DateTime<Utc>>,
+{
+    /// `timestamp` finds the time of a line, if it has one
+    pub fn new(timestamp: F) -> Self {
+        Self {
+            heads: Vec::new(),
+            timestamp,
+        }
+    }
+
+    /// Adds the lines of another file, which the caller knows as `source`
+    pub async fn push(&mut self, source: usize, lines: S) -> io::Result<()> {
+        let head = Head {
+            source,
+            lines,
+            line: None,
+            at: DateTime::<Utc>::MIN_UTC,
+        };
+        self.advance(head).await
+    }
+
+    /// When the next line happened, or nothing once every file has run out
+    pub fn oldest(&self) -> Option<DateTime<Utc>> {
+        self.heads.iter().map(|head| head.at).min()
+    }
+
+    /// The oldest line left and the `source` it came from. Ties go to the file added first.
+    pub async fn next(&mut self) -> io::Result<Option<(usize, Option<String>)>> {
+        let oldest = self
+            .heads
+            .iter()
+            .enumerate()
+            .min_by_key(|(i, head)| (head.at, *i))
+            .map(|(i, _)| i);
+        let mut head = match oldest {
+            Some(i) => self.heads.remove(i),
+            None => return Ok(None),
+        };
+
+        let next = (head.source, head.line.take());
+        self.advance(head).await?;
+        Ok(Some(next))
+    }
+
+    // Reads the next line of a file and puts it back in line, unless it's run out
+    async fn advance(&mut self, mut head: Head<S>) -> io::Result<()> {
+        let line = match head.lines.next().await {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        if let Some(at) = line.as_deref().and_then(&self.timestamp) {
+            head.at = at;
+        }
+        head.line = line;
+        // Kept in the order the files were added, for ties
+        let i = self
+            .heads
+            .partition_point(|other| other.source < head.source);
+        self.heads.insert(i, head);
+        Ok(())
+    }
+}
+
+impl RateLimit {
+    pub fn new(per_second: u64) -> Self {
+        Self {
+            per_second: per_second.max(1),
+            started: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Waits until `points` more can be sent
+    pub async fn wait(&mut self, points: u64) {
+        self.sent += points;
+        tokio::time::sleep_until((self.started + self.due()).into()).await;
+    }
+
+    // How long after starting everything sent so far is allowed to have gone out
+    fn due(&self) -> Duration {
+        Duration::from_secs_f64(self.sent as f64 / self.per_second as f64)
+    }
+}
+
+impl Retime {
+    /// `None` when the point is too old to keep
+    pub fn apply(&self, mut metric: Metric) -> Option<Metric> {
+        if let Some(shift) = self.shift {
+            metric.timestamp += shift;
+        }
+        match self.cutoff {
+            Some(cutoff) if metric.timestamp < cutoff => None,
+            _ => Some(metric),
+        }
+    }
+}
+
+impl Progress {
+    /// `total` is in whatever units `done` will be counted in, eg bytes
+    pub fn new(total: u64) -> Self {
+        Self {
+            total,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn percent(&self, done: u64) -> f64 {
+        if self.total == 0 {
+            return 100.0;
+        }
+        (done as f64 / self.total as f64 * 100.0).min(100.0)
+    }
+
+    /// Assuming the rest goes as fast as what's been done so far
+    pub fn eta(&self, done: u64) -> Option<Duration> {
+        eta(self.started.elapsed(), done, self.total)
+    }
+}
+
+fn eta(elapsed: Duration, done: u64, total: u64) -> Option<Duration> {
+    if done == 0 {
+        return None;
+    }
+    let remaining = total.saturating_sub(done) as f64;
+    Some(elapsed.mul_f64(remaining / done as f64))
+}
+
+/// A duration like `90s`, `15m`, `12h` or `30d`
+pub fn parse_duration(s: &str) -> Result<chrono::Duration, String> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (n, unit) = s.split_at(split);
+    let n: i64 = n.parse().map_err(|_| format!("invalid duration {}", s))?;
+    match unit {
+        "s" => Ok(chrono::Duration::seconds(n)),
+        "m" => Ok(chrono::Duration::minutes(n)),
+        "h" => Ok(chrono::Duration::hours(n)),
+        "d" => Ok(chrono::Duration::days(n)),
+        _ => Err(format!("invalid duration {}, use s, m, h or d", s)),
+    }
+}
+
+/// Eg `1h02m03s`, for reporting
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::time::Duration;
+
+    use chrono::{DateTime, TimeZone, Utc};
+    use futures::stream::{self, Stream};
+
+    use super::{eta, format_duration, parse_duration, Merge, RateLimit, Retime};
+    use crate::metric::Metric;
+
+    fn lines(lines: &[&str]) -> impl Stream<Item = io::Result<Option<String>>> + Unpin {
+        let lines: Vec<_> = lines
+            .iter()
+            .map(|line| Ok((!line.is_empty()).then(|| line.to_string())))
+            .collect();
+        stream::iter(lines)
+    }
+
+    // Lines are `<seconds> <text>`, or just text without a timestamp
+    fn timestamp(line: &str) -> Option<DateTime<Utc>> {
+        let secs = line.split_once(' ')?.0.parse().ok()?;
+        Utc.timestamp_opt(secs, 0).single()
+    }
+
+    #[tokio::test]
+    async fn test_it_merges_overlapping_files_by_time() {
+        let mut merge = Merge::new(timestamp);
+        merge
+            .push(0, lines(&["10 a", "30 b", "no time", "50 c"]))
+            .await
+            .unwrap();
+        merge
+            .push(1, lines(&["20 x", "", "30 y", "40 z"]))
+            .await
+            .unwrap();
+        assert_eq!(merge.oldest(), timestamp("10 a"));
+
+        let mut merged = Vec::new();
+        while let Some((source, line)) = merge.next().await.unwrap() {
+            merged.push((source, line.unwrap_or_default()));
+        }
+        let expected = [
+            (0, "10 a"),
+            (1, "20 x"),
+            // Too long, so it keeps the time of the line before
+            (1, ""),
+            (0, "30 b"),
+            (0, "no time"),
+            (1, "30 y"),
+            (1, "40 z"),
+            (0, "50 c"),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(source, line)| (*source, line.to_string()))
+            .collect();
+        assert_eq!(merged, expected);
+        assert_eq!(merge.oldest(), None);
+    }
+
+    #[test]
+    fn test_it_spaces_out_points() {
+        let mut limit = RateLimit::new(100);
+        limit.sent = 50;
+        assert_eq!(limit.due(), Duration::from_millis(500));
+        limit.sent = 250;
+        assert_eq!(limit.due(), Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn test_it_shifts_and_drops_old_points() {
+        let old = Utc.with_ymd_and_hms(2019, 11, 25, 18, 28, 0).unwrap();
+        let metric = Metric {
+            timestamp: old,
+            ..Default::default()
+        };
+
+        let retime = Retime {
+            shift: None,
+            cutoff: Some(old + chrono::Duration::days(1)),
+        };
+        assert!(retime.apply(metric.clone()).is_none());
+
+        let retime = Retime {
+            shift: Some(chrono::Duration::days(2)),
+            ..retime
+        };
+        let shifted = retime.apply(metric).unwrap();
+        assert_eq!(shifted.timestamp, old + chrono::Duration::days(2));
+    }
+
+    #[test]
+    fn test_it_estimates_and_formats_durations() {
+        let elapsed = Duration::from_secs(60);
+        assert_eq!(eta(elapsed, 0, 100), None);
+        assert_eq!(eta(elapsed, 25, 100), Some(Duration::from_secs(180)));
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h02m03s");
+        assert_eq!(format_duration(Duration::from_secs(75)), "1m15s");
+
+        assert_eq!(parse_duration("30d"), Ok(chrono::Duration::days(30)));
+        assert_eq!(parse_duration("90s"), Ok(chrono::Duration::seconds(90)));
+        assert!(parse_duration("30").is_err());
+        assert!(parse_duration("d").is_err());
+    }
+}
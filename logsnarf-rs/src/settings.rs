@@ -1,16 +1,34 @@
 use std::collections::BTreeMap;
+use std::time::Duration;
 
-use config::{Config, ConfigError, Environment, File};
+use config::{
+    builder::DefaultState, Config, ConfigBuilder, ConfigError, Environment, File, FileFormat,
+};
 use serde_derive::Deserialize;
 use xdg;
 
-use crate::{metric, metric_writer};
+use crate::{error_sink, metric, metric_writer, parser, timestamp};
 
 #[derive(Debug, Deserialize)]
-#[allow(unused)]
 pub struct Daemon {
-    http_port: Option<u16>,
-    syslog_port: Option<u16>,
+    pub http_port: Option<u16>,
+    pub syslog_port: Option<u16>,
+    /// Seconds between writes to the TSDB
+    pub flush_interval: Option<u64>,
+    /// Seconds to remember each `Logplex-Frame-Id`, so frames Heroku retries are only counted once
+    pub dedupe_window: Option<u64>,
+    /// Largest request body to accept, in bytes after decompression
+    pub max_body_size: Option<u64>,
+    /// Tag every metric from a drain with its `Logplex-Drain-Token`, under this name
+    pub drain_token_tag: Option<String>,
+}
+
+impl Daemon {
+    pub const DEFAULT_FLUSH_INTERVAL: u64 = 10;
+
+    pub fn flush_every(&self) -> Duration {
+        Duration::from_secs(self.flush_interval.unwrap_or(Self::DEFAULT_FLUSH_INTERVAL))
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -42,24 +60,96 @@ pub enum Matcher {
     Condition(BTreeMap<String, String>),
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, Default)]
 #[allow(unused)]
 pub struct MetricDecoder {
     pub name: metric::Name,
+    /// Keys to use as tags. May be globs, eg `sample#*`
     pub tag_names: Vec<metric::TagKey>,
+    /// Keys to use as fields. May be globs, eg `sample#*`
     pub field_names: Vec<metric::FieldKey>,
+    /// Keys to leave out, even when they match `tag_names`
+    #[serde(default)]
+    pub exclude_tag_names: Vec<metric::TagKey>,
+    /// Keys to leave out, even when they match `field_names`
+    #[serde(default)]
+    pub exclude_field_names: Vec<metric::FieldKey>,
+    /// Reject the line if any of these tags are missing
+    #[serde(default)]
+    pub required_tags: Vec<metric::TagKey>,
+    /// Reject the line if any of these fields are missing
+    #[serde(default)]
+    pub required_fields: Vec<metric::FieldKey>,
+    /// Reject the line if it has fewer fields than this. A metric always needs at least one.
+    #[serde(default)]
+    pub min_fields: usize,
+    /// Report any keys in a matched message that weren't used as a tag or field
+    #[serde(default)]
+    pub strict: bool,
     pub matcher: BTreeMap<String, Matcher>,
 }
 
 pub type MetricDecoders = Vec<MetricDecoder>;
 
+/// Decoders that ship with logsnarf, for lines that can't be described by a `[[metrics]]` block
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Builtins {
+    /// Router H-codes and dyno R-codes, eg `at=error code=H12` or `Error R14 (Memory quota exceeded)`
+    pub heroku_errors: bool,
+    /// Dyno state changes, eg `State changed from up to crashed`
+    pub heroku_dyno_state: bool,
+    /// Application metrics logged as `count#`, `measure#` or `sample#` keys
+    pub l2met: bool,
+    /// Put in front of the name of each l2met metric, eg `l2met_`, to keep them apart from the
+    /// others. Without it, `measure#db.query` is the metric `db.query`.
+    pub l2met_prefix: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    #[default]
+    Last,
+    Avg,
+    Max,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Rollup {
+    /// Name of the metric to roll up
+    pub name: metric::Name,
+    /// Window size, in seconds
+    pub window: u64,
+    /// How long to wait for late points before closing a window, in seconds
+    #[serde(default)]
+    pub lateness: u64,
+    /// Aggregation used for fields not listed in `fields`
+    #[serde(default)]
+    pub default: Aggregation,
+    #[serde(default)]
+    pub fields: BTreeMap<metric::FieldKey, Aggregation>,
+}
+
+pub type Rollups = Vec<Rollup>;
+
 #[derive(Debug, Deserialize)]
 #[allow(unused)]
 pub struct Settings {
     pub daemon: Daemon,
     pub logging: Logging,
+    #[serde(default)]
+    pub parser: parser::Options,
+    #[serde(default)]
+    pub timestamps: timestamp::Policies,
     pub tsdb: TsdbCredentials,
     pub metrics: MetricDecoders,
+    #[serde(default)]
+    pub builtins: Builtins,
+    #[serde(default)]
+    pub rollups: Rollups,
+    #[serde(default)]
+    pub errors: error_sink::Options,
 }
 
 impl Settings {
@@ -67,12 +157,7 @@ impl Settings {
         // config files ~/.config/logsnarf/logsnarf.toml, /etc/logsnarf/logsnarf.toml, etc...
         let xdg_dirs = xdg::BaseDirectories::with_prefix("logsnarf").unwrap();
 
-        let mut builder = Config::builder()
-            // defaults
-            .set_default("logging.level", "info")?
-            .set_default("logging.output", "STDOUT")?;
-
-        builder = builder.add_source(File::with_name("logsnarf"));
+        let mut builder = defaults()?.add_source(File::with_name("logsnarf"));
 
         while let Some(config_file_path) = xdg_dirs.find_config_files("logsnarf.toml").next() {
             builder = builder
@@ -84,4 +169,19 @@ impl Settings {
 
         s.try_deserialize()
     }
+
+    /// Settings from the contents of one config file, without looking for others or reading the
+    /// environment, eg for tests
+    pub fn from_toml(toml: &str) -> Result<Self, ConfigError> {
+        defaults()?
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()?
+            .try_deserialize()
+    }
+}
+
+fn defaults() -> Result<ConfigBuilder<DefaultState>, ConfigError> {
+    Config::builder()
+        .set_default("logging.level", "info")?
+        .set_default("logging.output", "STDOUT")
 }
@@ -0,0 +1,319 @@
+//! Deciding when a line happened. Timestamps come from the sender's clock, so they can be missing,
+//! in some format other than RFC 3339, or far enough off from when the line arrived that writing
+//! them as-is would put points in the wrong place.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use serde_derive::Deserialize;
+use thiserror::Error;
+
+use crate::metric::{FieldValue, Fields, Metric, Tags};
+
+const SKEW_METRIC: &str = "logsnarf_timestamp_skew";
+
+#[derive(Debug, Error)]
+pub enum TimestampError {
+    #[error("line has no timestamp")]
+    Missing,
+    #[error("unrecognized timestamp `{0}`")]
+    Unparseable(String),
+    #[error("timestamp {0} is {1}s off from when it was received")]
+    Skewed(DateTime<Utc>, i64),
+}
+
+impl TimestampError {
+    /// A short name for the variant, for counting errors by kind
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TimestampError::Missing => "missing",
+            TimestampError::Unparseable(_) => "unparseable",
+            TimestampError::Skewed(..) => "skewed",
+        }
+    }
+}
+
+/// What to do with a line that has no timestamp, or one that can't be parsed
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Missing {
+    /// Skip the line
+    #[default]
+    Skip,
+    /// Use the time the line was received
+    ReceiveTime,
+}
+
+/// What to do with a timestamp outside of `max_past`/`max_future`
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Skew {
+    /// Use it anyway
+    #[default]
+    Accept,
+    /// Skip the line
+    Reject,
+    /// Move it to the nearest time that is within the limits
+    Clamp,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct Policy {
+    pub missing: Missing,
+    pub skew: Skew,
+    /// How far before the receive time a timestamp can be, in minutes
+    pub max_past: u64,
+    /// How far after the receive time a timestamp can be, in minutes
+    pub max_future: u64,
+}
+
+/// The policy for every source, along with overrides for particular ones, by drain or file name:
+///
+/// ```toml
+/// [timestamps]
+/// missing = "receive_time"
+///
+/// [timestamps.sources."d.8c5f7e2a-3b1c-4d6e-9f0a-1b2c3d4e5f60"]
+/// skew = "clamp"
+/// ```
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Policies {
+    #[serde(flatten)]
+    pub default: Policy,
+    #[serde(default)]
+    pub sources: BTreeMap<String, Policy>,
+}
+
+/// How far off the timestamps from one source have been, in seconds after the receive time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SkewStats {
+    pub timestamps: u64,
+    pub missing: u64,
+    pub rejected: u64,
+    pub clamped: u64,
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    pub total: i64,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            missing: Missing::default(),
+            skew: Skew::default(),
+            max_past: 60,
+            max_future: 5,
+        }
+    }
+}
+
+impl Policies {
+    pub fn for_source(&self, source: &str) -> &Policy {
+        self.sources.get(source).unwrap_or(&self.default)
+    }
+}
+
+impl Policy {
+    /// Decides the timestamp for a line received at `received`, and records how far off it was
+    pub fn resolve(
+        &self,
+        timestamp_str: Option<&str>,
+        received: DateTime<Utc>,
+        stats: &mut SkewStats,
+    ) -> Result<DateTime<Utc>, TimestampError> {
+        let parsed = match timestamp_str {
+            Some(s) => parse(s).ok_or_else(|| TimestampError::Unparseable(s.into())),
+            None => Err(TimestampError::Missing),
+        };
+        let timestamp = match (parsed, self.missing) {
+            (Ok(timestamp), _) => timestamp,
+            (Err(_), Missing::ReceiveTime) => {
+                stats.missing += 1;
+                return Ok(received);
+            }
+            (Err(e), Missing::Skip) => {
+                stats.missing += 1;
+                return Err(e);
+            }
+        };
+
+        let skew = (timestamp - received).num_seconds();
+        stats.record(skew);
+
+        let earliest = received - Duration::minutes(self.max_past as i64);
+        let latest = received + Duration::minutes(self.max_future as i64);
+        if earliest <= timestamp && timestamp <= latest {
+            return Ok(timestamp);
+        }
+
+        match self.skew {
+            Skew::Accept => Ok(timestamp),
+            Skew::Reject => {
+                stats.rejected += 1;
+                Err(TimestampError::Skewed(timestamp, skew))
+            }
+            Skew::Clamp => {
+                stats.clamped += 1;
+                Ok(timestamp.clamp(earliest, latest))
+            }
+        }
+    }
+}
+
+impl SkewStats {
+    fn record(&mut self, skew: i64) {
+        self.timestamps += 1;
+        self.total += skew;
+        self.min = Some(self.min.map_or(skew, |min| min.min(skew)));
+        self.max = Some(self.max.map_or(skew, |max| max.max(skew)));
+    }
+
+    pub fn merge(&mut self, other: &SkewStats) {
+        self.timestamps += other.timestamps;
+        self.missing += other.missing;
+        self.rejected += other.rejected;
+        self.clamped += other.clamped;
+        self.total += other.total;
+        self.min = self.min.into_iter().chain(other.min).min();
+        self.max = self.max.into_iter().chain(other.max).max();
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.timestamps > 0).then(|| self.total as f64 / self.timestamps as f64)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == SkewStats::default()
+    }
+
+    pub fn to_metric(&self, source: &str, timestamp: DateTime<Utc>) -> Metric {
+        let mut tags = Tags::new();
+        tags.insert("source".into(), source.into());
+
+        let mut fields = Fields::new();
+        for (name, value) in [
+            ("timestamps", self.timestamps),
+            ("missing", self.missing),
+            ("rejected", self.rejected),
+            ("clamped", self.clamped),
+        ] {
+            fields.insert(name.into(), FieldValue::Integer(value as i64, None));
+        }
+        for (name, value) in [("min", self.min), ("max", self.max)] {
+            if let Some(value) = value {
+                fields.insert(name.into(), FieldValue::Integer(value, Some("s".into())));
+            }
+        }
+        if let Some(mean) = self.mean() {
+            fields.insert("mean".into(), FieldValue::Float(mean, Some("s".into())));
+        }
+
+        Metric {
+            timestamp,
+            name: SKEW_METRIC.into(),
+            tags,
+            fields,
+        }
+    }
+}
+
+/// RFC 3339, which is what syslog uses, or a couple of other formats that loggers get wrong in
+/// common ways
+pub fn parse(s: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(s)
+        .or_else(|_| DateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f %z"))
+        .or_else(|_| DateTime::parse_from_rfc2822(s))
+        .ok()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, TimeZone, Utc};
+
+    use super::{parse, Missing, Policy, Skew, SkewStats, TimestampError};
+    use crate::metric::FieldValue;
+
+    fn received() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2019, 11, 25, 18, 30, 0).unwrap()
+    }
+
+    #[test]
+    fn test_it_parses_other_formats() {
+        let expected = Utc.with_ymd_and_hms(2019, 11, 25, 18, 28, 0).unwrap();
+        assert_eq!(parse("2019-11-25T18:28:00+00:00"), Some(expected));
+        assert_eq!(parse("2019-11-25 18:28:00 +0000"), Some(expected));
+        assert_eq!(parse("Mon, 25 Nov 2019 18:28:00 +0000"), Some(expected));
+        assert_eq!(parse("yesterday"), None);
+    }
+
+    #[test]
+    fn test_it_falls_back_to_receive_time() {
+        let mut stats = SkewStats::default();
+        let skip = Policy::default();
+        assert!(matches!(
+            skip.resolve(None, received(), &mut stats),
+            Err(TimestampError::Missing)
+        ));
+
+        let fallback = Policy {
+            missing: Missing::ReceiveTime,
+            ..Default::default()
+        };
+        assert_eq!(
+            fallback.resolve(None, received(), &mut stats).unwrap(),
+            received()
+        );
+        assert_eq!(
+            fallback
+                .resolve(Some("yesterday"), received(), &mut stats)
+                .unwrap(),
+            received()
+        );
+        assert_eq!(stats.missing, 3);
+        assert_eq!(stats.timestamps, 0);
+    }
+
+    #[test]
+    fn test_it_rejects_or_clamps_skewed_timestamps() {
+        let future = Some("2019-11-25T18:40:00Z");
+        let past = Some("2019-11-25T16:00:00Z");
+
+        let mut stats = SkewStats::default();
+        let accept = Policy::default();
+        assert!(accept.resolve(future, received(), &mut stats).is_ok());
+
+        let reject = Policy {
+            skew: Skew::Reject,
+            ..Default::default()
+        };
+        assert!(matches!(
+            reject.resolve(future, received(), &mut stats),
+            Err(TimestampError::Skewed(_, 600))
+        ));
+
+        let clamp = Policy {
+            skew: Skew::Clamp,
+            ..Default::default()
+        };
+        assert_eq!(
+            clamp.resolve(past, received(), &mut stats).unwrap(),
+            Utc.with_ymd_and_hms(2019, 11, 25, 17, 30, 0).unwrap()
+        );
+
+        assert_eq!(stats.timestamps, 3);
+        assert_eq!(stats.rejected, 1);
+        assert_eq!(stats.clamped, 1);
+        assert_eq!(stats.min, Some(-9000));
+        assert_eq!(stats.max, Some(600));
+
+        let metric = stats.to_metric("d.1", received());
+        assert_eq!(metric.tags["source"], "d.1");
+        assert_eq!(metric.fields["rejected"], FieldValue::Integer(1, None));
+        assert_eq!(
+            metric.fields["min"],
+            FieldValue::Integer(-9000, Some("s".into()))
+        );
+    }
+}